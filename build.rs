@@ -6,10 +6,16 @@ fn main() {
     //
     // include/redismodule.h is just vendored in from the Redis project and
     // src/redismodule.c is just a stub that includes it and plays a few other
-    // tricks that we need to complete the build.
+    // tricks that we need to complete the build. include/redismodule_mock.c
+    // is the C half of the mock Redis the unit tests run against; it only ends
+    // up in the module if something references it, which only the tests do.
     cc::Build::new()
         .file("include/redismodule.c")
+        .file("include/redismodule_mock.c")
         .include("include/")
         .compile("libredismodule.a");
-    // The CC module emits `rustc-link-lib=static=redismodule` for us.
+    // The CC module emits `rustc-link-lib=static=redismodule` for us. It also
+    // asks to rerun on changes to CC and friends, which stops Cargo from
+    // rerunning on changes to the C files unless they're listed too.
+    println!("cargo:rerun-if-changed=include/");
 }
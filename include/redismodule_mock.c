@@ -0,0 +1,161 @@
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// The variadic half of the mock Redis used by the unit tests (see
+// src/redis/mock.rs). Rust can't define C variadic functions, so these unpack
+// their arguments and hand them to hooks the Rust side installs. Nothing here
+// runs unless the hooks are set.
+//
+// redismodule.h isn't included: it defines the API function pointers, which
+// redismodule.c already does.
+
+typedef struct RedisModuleCtx RedisModuleCtx;
+typedef struct RedisModuleIO RedisModuleIO;
+typedef struct RedisModuleString RedisModuleString;
+typedef struct RedisModuleCallReply RedisModuleCallReply;
+typedef struct RedisModuleKey RedisModuleKey;
+
+// Set by RedisModule_Init, in redismodule.c.
+extern const char *(*RedisModule_StringPtrLen)(const RedisModuleString *str, size_t *len);
+
+RedisModuleCallReply *(*MockRedis_CallHook)(RedisModuleCtx *ctx, const char *cmdname, RedisModuleString **argv, size_t argc);
+void (*MockRedis_EmitAOFHook)(RedisModuleIO *io, const char *cmdname, const char **bufs, size_t *lens, size_t argc);
+int (*MockRedis_ReplicateHook)(RedisModuleCtx *ctx, const char *cmdname, RedisModuleString **argv, size_t argc);
+int (*MockRedis_HashSetHook)(RedisModuleKey *key, int flags, RedisModuleString *field, RedisModuleString *value);
+int (*MockRedis_HashGetHook)(RedisModuleKey *key, int flags, RedisModuleString *field, void *out);
+void (*MockRedis_LogHook)(RedisModuleCtx *ctx, RedisModuleIO *io, const char *level, const char *msg);
+
+// Collects the arguments described by `fmt` into a malloc'ed array. Only the
+// formats the module uses are supported: "s" for a RedisModuleString and "v"
+// for an array of them followed by its length.
+static RedisModuleString **collect(const char *fmt, va_list ap, size_t *argc) {
+    RedisModuleString **argv = NULL;
+    *argc = 0;
+    for (const char *p = fmt; *p; p++) {
+        if (*p == 's') {
+            argv = realloc(argv, sizeof(*argv) * (*argc + 1));
+            argv[(*argc)++] = va_arg(ap, RedisModuleString *);
+        } else if (*p == 'v') {
+            RedisModuleString **v = va_arg(ap, RedisModuleString **);
+            size_t n = va_arg(ap, size_t);
+            argv = realloc(argv, sizeof(*argv) * (*argc + n));
+            for (size_t i = 0; i < n; i++) argv[(*argc)++] = v[i];
+        } else {
+            fprintf(stderr, "mock redis: unsupported format '%c'\n", *p);
+            abort();
+        }
+    }
+    return argv;
+}
+
+RedisModuleCallReply *MockRedis_Call(RedisModuleCtx *ctx, const char *cmdname, const char *fmt, ...) {
+    va_list ap;
+    size_t argc;
+    va_start(ap, fmt);
+    RedisModuleString **argv = collect(fmt, ap, &argc);
+    va_end(ap);
+    RedisModuleCallReply *reply = MockRedis_CallHook(ctx, cmdname, argv, argc);
+    free(argv);
+    return reply;
+}
+
+int MockRedis_Replicate(RedisModuleCtx *ctx, const char *cmdname, const char *fmt, ...) {
+    va_list ap;
+    size_t argc;
+    va_start(ap, fmt);
+    RedisModuleString **argv = collect(fmt, ap, &argc);
+    va_end(ap);
+    int status = MockRedis_ReplicateHook(ctx, cmdname, argv, argc);
+    free(argv);
+    return status;
+}
+
+// EmitAOF is called during AOF rewrites. Besides "s" and "v", as in
+// `collect`, it takes "b" for a buffer followed by its length and "c" for a C
+// string. All arguments are handed to the hook as buffers. Arguments beyond
+// those in `fmt` are ignored, as by Redis.
+void MockRedis_EmitAOF(RedisModuleIO *io, const char *cmdname, const char *fmt, ...) {
+    va_list ap;
+    size_t argc = 0;
+    const char **bufs = NULL;
+    size_t *lens = NULL;
+    va_start(ap, fmt);
+    for (const char *p = fmt; *p; p++) {
+        size_t n = 1;
+        RedisModuleString **v = NULL;
+        if (*p == 'v') {
+            v = va_arg(ap, RedisModuleString **);
+            n = va_arg(ap, size_t);
+        }
+        bufs = realloc(bufs, sizeof(*bufs) * (argc + n));
+        lens = realloc(lens, sizeof(*lens) * (argc + n));
+        if (*p == 's') {
+            bufs[argc] = RedisModule_StringPtrLen(va_arg(ap, RedisModuleString *), &lens[argc]);
+        } else if (*p == 'v') {
+            for (size_t i = 0; i < n; i++) bufs[argc + i] = RedisModule_StringPtrLen(v[i], &lens[argc + i]);
+        } else if (*p == 'b') {
+            bufs[argc] = va_arg(ap, const char *);
+            lens[argc] = va_arg(ap, size_t);
+        } else if (*p == 'c') {
+            bufs[argc] = va_arg(ap, const char *);
+            lens[argc] = strlen(bufs[argc]);
+        } else {
+            fprintf(stderr, "mock redis: unsupported EmitAOF format '%c'\n", *p);
+            abort();
+        }
+        argc += n;
+    }
+    va_end(ap);
+    MockRedis_EmitAOFHook(io, cmdname, bufs, lens, argc);
+    free(bufs);
+    free(lens);
+}
+
+// HashSet and HashGet take field/value pairs up to a NULL field, and hand
+// them to the hooks one at a time.
+int MockRedis_HashSet(RedisModuleKey *key, int flags, ...) {
+    va_list ap;
+    int updated = 0;
+    va_start(ap, flags);
+    RedisModuleString *field;
+    while ((field = va_arg(ap, RedisModuleString *)) != NULL) {
+        updated += MockRedis_HashSetHook(key, flags, field, va_arg(ap, RedisModuleString *));
+    }
+    va_end(ap);
+    return updated;
+}
+
+int MockRedis_HashGet(RedisModuleKey *key, int flags, ...) {
+    va_list ap;
+    int status = 0;
+    va_start(ap, flags);
+    RedisModuleString *field;
+    while ((field = va_arg(ap, RedisModuleString *)) != NULL) {
+        status |= MockRedis_HashGetHook(key, flags, field, va_arg(ap, void *));
+    }
+    va_end(ap);
+    return status;
+}
+
+static void log_message(RedisModuleCtx *ctx, RedisModuleIO *io, const char *level, const char *fmt, va_list ap) {
+    char msg[4096];
+    vsnprintf(msg, sizeof(msg), fmt, ap);
+    MockRedis_LogHook(ctx, io, level, msg);
+}
+
+void MockRedis_Log(RedisModuleCtx *ctx, const char *level, const char *fmt, ...) {
+    va_list ap;
+    va_start(ap, fmt);
+    log_message(ctx, NULL, level, fmt, ap);
+    va_end(ap);
+}
+
+void MockRedis_LogIOError(RedisModuleIO *io, const char *level, const char *fmt, ...) {
+    va_list ap;
+    va_start(ap, fmt);
+    log_message(NULL, io, level, fmt, ap);
+    va_end(ap);
+}
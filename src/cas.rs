@@ -0,0 +1,115 @@
+// Compare-and-set, for clients that edit a color concurrently: each writes
+// only if the color is still the one it last read, without WATCH and MULTI.
+
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use {color_changed, replicate_color_value, Color, ColorValue};
+
+// Implements COLOR.CAS key expected new [TOLERANCE deltaE]
+//
+// Sets the color at `key` to `new` if it's currently `expected`, or with
+// TOLERANCE differs from `expected` (see `Color::delta_e`) by at most
+// `deltaE`. Alpha has to match either way. A running transition is compared
+// as of now, and stopped by the swap.
+//
+// Replies with whether the swap happened, 1 or 0, and the color the key holds
+// afterwards, nil if there's no such key. Replicated as the COLOR.SET of the
+// new color, and not at all without a swap, since what the key is compared
+// with depends on when the command runs.
+pub struct CasColorCommand {}
+impl Command for CasColorCommand {
+    fn name(&self) -> &'static str { "color.cas" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM | CommandFlags::FAST }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let expected: Color = args[2].parse("a hex color")?;
+        let new: Color = args[3].parse("a hex color")?;
+        let tolerance = match args.len() {
+            4 => 0.0,
+            6 if args[4].eq_ignore_case("tolerance") => {
+                let tolerance = args[5].to_f64()?;
+                if tolerance.is_nan() || tolerance < 0.0 {
+                    return Err(ColorError::OutOfRange("TOLERANCE can't be negative".to_string()))
+                }
+                tolerance
+            }
+            6 => return Err(ColorError::Syntax(format!("unknown option {}", args[4].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+
+        let key = r.open_key_writable(args[1]);
+        let current = match key.value::<ColorValue>()? {
+            Some(value) => value.at(redis::milliseconds()),
+            None => {
+                let mut reply = r.reply_array(2)?;
+                reply.integer(0)?;
+                reply.null()?;
+                return reply.finish()
+            }
+        };
+        let matches = current == expected
+            || (tolerance > 0.0 && current.a == expected.a && current.delta_e(&expected) <= tolerance);
+
+        let now = if matches {
+            let value = key.write(ColorValue::new(new))?;
+            replicate_color_value(&r, args[1].as_bytes(), &value)?;
+            color_changed(&r, self.name(), args[1].as_bytes(), Some(&current), &new);
+            new
+        } else {
+            current
+        };
+        let mut reply = r.reply_array(2)?;
+        reply.integer(matches as i64)?;
+        reply.string(now.format(config::get().default_format))?;
+        reply.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server, START_MS};
+
+    fn cas(swapped: i64, color: Option<&str>) -> Reply {
+        Reply::Array(vec![Reply::Integer(swapped), match color {
+            Some(c) => Reply::Bulk(c.as_bytes().to_vec()),
+            None => Reply::Null,
+        }])
+    }
+
+    #[test]
+    fn cas_swaps_only_expected_colors() {
+        let mut server = Server::with_colors(&[("brand", "#3366ff")]);
+        server.replicated.clear();
+        assert_eq!(server.run(&["COLOR.CAS", "brand", "#3366fe", "#ff0000"]), cas(0, Some("#3366ff")));
+        assert!(server.replicated.is_empty());
+        assert_eq!(server.run(&["COLOR.CAS", "brand", "#3366ff", "#ff0000"]), cas(1, Some("#ff0000")));
+        assert_eq!(server.run(&["COLOR.GET", "brand"]), Reply::simple("#ff0000"));
+        assert_eq!(server.replicated, vec![vec!["color.set", "brand", "#ff0000ff"]]);
+
+        assert_eq!(server.run(&["COLOR.CAS", "brand", "#fe0101", "#00ff00", "TOLERANCE", "2"]), cas(1, Some("#00ff00")));
+        assert_eq!(server.run(&["COLOR.CAS", "brand", "#00ff0080", "#000000", "TOLERANCE", "2"]), cas(0, Some("#00ff00")));
+        assert_eq!(server.run(&["COLOR.CAS", "brand", "#00dd00", "#000000", "TOLERANCE", "2"]), cas(0, Some("#00ff00")));
+        assert_eq!(server.run(&["COLOR.CAS", "nope", "#000000", "#ffffff"]), cas(0, None));
+        assert!(!server.exists("nope"));
+
+        assert!(server.run(&["COLOR.CAS", "brand", "#000000", "#fff", "TOLERANCE", "-1"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.CAS", "brand", "#000000", "blue"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.CAS", "brand", "#000000", "#fff", "TOLERANCE"]).is_error("ERR wrong number"));
+        server.hset("palette", "a", "#000");
+        assert!(server.run(&["COLOR.CAS", "palette", "#000000", "#fff"]).is_error("WRONGTYPE"));
+    }
+
+    #[test]
+    fn cas_compares_transitions_as_of_now() {
+        let mut server = Server::with_colors(&[("fade", "#000000")]);
+        server.run(&["COLOR.TRANSITION", "fade", "#ffffff", "DURATION", "1000"]);
+        server.set_time(START_MS + 500);
+        assert_eq!(server.run(&["COLOR.CAS", "fade", "#ffffff", "#ff0000"]), cas(0, Some("#808080")));
+        assert_eq!(server.run(&["COLOR.CAS", "fade", "#808080", "#ff0000"]), cas(1, Some("#ff0000")));
+        server.set_time(START_MS + 1000);
+        assert_eq!(server.run(&["COLOR.GET", "fade"]), Reply::simple("#ff0000"));
+    }
+}
@@ -0,0 +1,212 @@
+// Reading and changing one channel of a color at a time, so that a client
+// can, say, dim a light without reading its color first. Channels are those
+// of `Channel`: r, g, b and a, HSL's h, s and l, and OKLCH's L and C.
+//
+// Writes apply to the color as of now, stopping any transition, and are
+// replicated as the COLOR.SET of the color they led to.
+
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use {color_changed, replicate_color_value, Channel, Color, ColorValue};
+
+// What to do with a value outside the range of its channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    // Fail.
+    Strict,
+    // Use the nearest end of the range.
+    Clamp,
+    // Go around, for hues only.
+    Wrap,
+}
+
+// Reads the optional CLAMP or WRAP at `args[i]`, the last argument.
+fn parse_mode(args: &[redis::RedisStr], i: usize, channel: Channel) -> Result<Mode, ColorError> {
+    if i == args.len() {
+        return Ok(Mode::Strict)
+    }
+    if i + 1 < args.len() {
+        return Err(ColorError::Syntax(format!("unexpected {}", args[i + 1].to_str()?)))
+    }
+    match args[i].to_str()?.to_uppercase().as_str() {
+        "CLAMP" => Ok(Mode::Clamp),
+        "WRAP" if channel == Channel::Hue => Ok(Mode::Wrap),
+        "WRAP" => Err(ColorError::Syntax(format!("only h wraps around, not {}", channel))),
+        other => Err(ColorError::Syntax(format!("unknown option {}", other))),
+    }
+}
+
+// `value` fitted into the range of `channel` as `mode` says.
+fn fit(channel: Channel, value: f64, mode: Mode) -> Result<f64, ColorError> {
+    let (min, max) = channel.range();
+    if !value.is_finite() {
+        return Err(ColorError::OutOfRange(format!("{} isn't a number", value)))
+    }
+    match mode {
+        Mode::Clamp => Ok(value.clamp(min, max)),
+        Mode::Wrap => Ok(value.rem_euclid(max)),
+        Mode::Strict if value < min || value > max => Err(ColorError::OutOfRange(format!(
+            "{} goes from {} to {}, not {}", channel, min, max, value,
+        ))),
+        Mode::Strict => Ok(value),
+    }
+}
+
+fn reply_channel(r: &redis::Redis, color: &Color, channel: Channel) -> Result<(), ColorError> {
+    let value = color.channel(channel);
+    if channel.is_integer() {
+        r.reply_integer(value as i64)
+    } else {
+        r.reply_double(value)
+    }
+}
+
+// Sets `channel` of the color at `key` to what `f` makes of its current value,
+// and replies with the value it ends up with.
+fn update_channel<F>(
+    r: &redis::Redis,
+    command: &str,
+    key: redis::RedisStr,
+    channel: Channel,
+    f: F,
+) -> Result<(), ColorError>
+where F: FnOnce(f64) -> Result<f64, ColorError> {
+    let k = r.open_key_writable(key);
+    let old = match k.value::<ColorValue>()? {
+        Some(value) => value.at(redis::milliseconds()),
+        None => return Err(ColorError::NotFound(key.to_str()?.to_string())),
+    };
+    let new = old.with_channel(channel, f(old.channel(channel))?);
+
+    let value = k.write(ColorValue::new(new))?;
+    replicate_color_value(r, key.as_bytes(), &value)?;
+    color_changed(r, command, key.as_bytes(), Some(&old), &new);
+    reply_channel(r, &new, channel)
+}
+
+// Implements COLOR.GETCHANNEL key channel
+//
+// Replies with a channel of the color at `key`: an integer for r, g, b and a,
+// a double for the others. Nil if there's no such key.
+pub struct GetChannelCommand {}
+impl Command for GetChannelCommand {
+    fn name(&self) -> &'static str { "color.getchannel" }
+    fn arity(&self) -> i32 { 3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::FAST }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let channel: Channel = args[2].to_str()?.parse()?;
+        let key = r.open_key(args[1]);
+        let color = match key.value::<ColorValue>()? {
+            Some(value) => value.at(redis::milliseconds()),
+            None => return r.reply_null(),
+        };
+        reply_channel(&r, &color, channel)
+    }
+}
+
+// Implements COLOR.SETCHANNEL key channel value [CLAMP|WRAP]
+//
+// Sets a channel of the color at `key`, keeping the other channels of the
+// same model. A value outside the channel's range fails, unless CLAMP brings
+// it to the nearest end of the range or, for h, WRAP goes around the hue
+// circle. Replies with the channel as it reads back, which can differ a
+// little from `value` for the channels that aren't stored as such.
+pub struct SetChannelCommand {}
+impl Command for SetChannelCommand {
+    fn name(&self) -> &'static str { "color.setchannel" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM | CommandFlags::FAST }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let channel: Channel = args[2].to_str()?.parse()?;
+        let value = fit(channel, args[3].to_f64()?, parse_mode(args, 4, channel)?)?;
+        update_channel(&r, self.name(), args[1], channel, |_| Ok(value))
+    }
+}
+
+// Implements COLOR.INCRBY key channel increment [CLAMP|WRAP]
+//
+// Adds `increment`, which can be negative or fractional, to a channel of the
+// color at `key`, like HINCRBYFLOAT. Results outside the channel's range are
+// handled as for COLOR.SETCHANNEL, and r, g, b and a are rounded. Replies with
+// the channel's new value.
+pub struct IncrByCommand {}
+impl Command for IncrByCommand {
+    fn name(&self) -> &'static str { "color.incrby" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM | CommandFlags::FAST }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let channel: Channel = args[2].to_str()?.parse()?;
+        let increment = args[3].to_f64()?;
+        let mode = parse_mode(args, 4, channel)?;
+        update_channel(&r, self.name(), args[1], channel, |current| fit(channel, current + increment, mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+    use Color;
+
+    fn double(reply: Reply) -> f64 {
+        match reply {
+            Reply::Double(d) => d,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn channels_read_back() {
+        let mut server = Server::with_colors(&[("c", "#ff800080")]);
+        assert_eq!(server.run(&["COLOR.GETCHANNEL", "c", "r"]), Reply::Integer(255));
+        assert_eq!(server.run(&["COLOR.GETCHANNEL", "c", "g"]), Reply::Integer(128));
+        assert_eq!(server.run(&["COLOR.GETCHANNEL", "c", "a"]), Reply::Integer(128));
+        assert!((double(server.run(&["COLOR.GETCHANNEL", "c", "h"])) - 30.1).abs() < 0.1);
+        assert_eq!(double(server.run(&["COLOR.GETCHANNEL", "c", "s"])), 1.0);
+        assert!((double(server.run(&["COLOR.GETCHANNEL", "c", "l"])) - 0.5).abs() < 0.01);
+        let lch = "#ff8000".parse::<Color>().unwrap().to_oklch();
+        assert_eq!(double(server.run(&["COLOR.GETCHANNEL", "c", "L"])), lch.l);
+        assert_eq!(double(server.run(&["COLOR.GETCHANNEL", "c", "C"])), lch.c);
+        assert_eq!(server.run(&["COLOR.GETCHANNEL", "nope", "r"]), Reply::Null);
+        assert!(server.run(&["COLOR.GETCHANNEL", "c", "x"]).is_error("ERR syntax"));
+    }
+
+    #[test]
+    fn setchannel_keeps_other_channels() {
+        let mut server = Server::with_colors(&[("c", "#ff8000")]);
+        assert_eq!(server.run(&["COLOR.SETCHANNEL", "c", "b", "64"]), Reply::Integer(64));
+        assert_eq!(server.run(&["COLOR.GET", "c"]), Reply::simple("#ff8040"));
+        assert_eq!(server.run(&["COLOR.SETCHANNEL", "c", "h", "480", "WRAP"]), Reply::Double(120.0));
+        assert_eq!(server.run(&["COLOR.GET", "c"]), Reply::simple("#40ff40"));
+        server.run(&["COLOR.SETCHANNEL", "c", "L", "0.5"]);
+        let lch = server.run(&["COLOR.GET", "c"]).into_simple().parse::<Color>().unwrap().to_oklch();
+        assert!((lch.l - 0.5).abs() < 0.01 && (lch.h - 142.0).abs() < 2.0, "{:?}", lch);
+        assert_eq!(server.replicated.last().unwrap()[0], "color.set");
+
+        assert!(server.run(&["COLOR.SETCHANNEL", "c", "r", "256"]).is_error("ERR r goes from 0 to 255"));
+        assert_eq!(server.run(&["COLOR.SETCHANNEL", "c", "r", "256", "CLAMP"]), Reply::Integer(255));
+        assert!(server.run(&["COLOR.SETCHANNEL", "c", "r", "10", "WRAP"]).is_error("ERR syntax"));
+        assert!(server.run(&["COLOR.SETCHANNEL", "c", "r", "10", "CLAMP", "x"]).is_error("ERR syntax"));
+        assert!(server.run(&["COLOR.SETCHANNEL", "nope", "r", "10"]).is_error("NOTFOUND"));
+    }
+
+    #[test]
+    fn incrby_nudges_a_channel() {
+        let mut server = Server::with_colors(&[("light", "#808080")]);
+        assert_eq!(server.run(&["COLOR.INCRBY", "light", "r", "-0.6"]), Reply::Integer(127));
+        assert_eq!(server.run(&["COLOR.INCRBY", "light", "a", "-300", "CLAMP"]), Reply::Integer(0));
+        assert!(server.run(&["COLOR.INCRBY", "light", "g", "200"]).is_error("ERR"));
+        assert_eq!(server.run(&["COLOR.GET", "light"]), Reply::simple("#7f808000"));
+
+        let before = double(server.run(&["COLOR.GETCHANNEL", "light", "l"]));
+        let after = double(server.run(&["COLOR.INCRBY", "light", "l", "0.1"]));
+        assert!((after - before - 0.1).abs() < 0.01, "{} {}", before, after);
+
+        server.run(&["COLOR.SET", "hue", "#ff0000"]);
+        assert_eq!(server.run(&["COLOR.INCRBY", "hue", "h", "-120", "WRAP"]), Reply::Double(240.0));
+        assert_eq!(server.run(&["COLOR.GET", "hue"]), Reply::simple("#0000ff"));
+        assert!(server.run(&["COLOR.INCRBY", "hue", "h", "-300"]).is_error("ERR"));
+    }
+}
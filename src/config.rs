@@ -0,0 +1,271 @@
+// Module configuration, given as arguments to `loadmodule` (or `MODULE LOAD`):
+//
+//     loadmodule redis-color.so DEFAULT-ALPHA 255 STRICT-PARSING yes
+//
+// Options come as name/value pairs, names being case insensitive. Any option
+// that doesn't parse fails the load. The values can be read back with
+// COLOR.CONFIG GET.
+
+use std::str::FromStr;
+
+use error::ColorError;
+use glob;
+use redis;
+use redis::{Command, CommandFlags, KeySpec, LogLevel, PanicPolicy};
+use Format;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// Alpha of colors written without one, e.g. `#ff55ef`.
+    pub default_alpha: u8,
+    /// How colors are written in replies.
+    pub default_format: Format,
+    /// Only accept `#rrggbb` and `#rrggbbaa`, with the `#`.
+    pub strict_parsing: bool,
+    /// The least severe level the module logs at.
+    pub log_level: LogLevel,
+    /// Replaces `color` in the names of the COLOR.* commands.
+    pub command_prefix: &'static str,
+    /// Most keys COLOR.SEARCH replies with, 0 for no limit.
+    pub max_search_results: usize,
+    /// Longest transition COLOR.TRANSITION accepts in milliseconds, 0 for no
+    /// limit.
+    pub max_transition_ms: i64,
+    /// Writes each color key remembers for COLOR.HISTORY and COLOR.ROLLBACK,
+    /// 0 for none.
+    pub history_length: usize,
+    /// Whether the hue index COLOR.SEARCH uses is kept up to date.
+    pub index: bool,
+    /// The channel change events are published on, if any. Off by default, as
+    /// every write then pays for a PUBLISH.
+    pub events_channel: Option<&'static str>,
+    pub panic_policy: PanicPolicy,
+}
+
+pub const DEFAULT: Config = Config {
+    default_alpha: 0xff,
+    default_format: Format::Hex,
+    strict_parsing: false,
+    log_level: LogLevel::Debug,
+    command_prefix: "color",
+    max_search_results: 0,
+    max_transition_ms: 0,
+    history_length: 0,
+    index: false,
+    events_channel: None,
+    panic_policy: PanicPolicy::Contain,
+};
+
+static mut CONFIG: Config = DEFAULT;
+
+/// The configuration the module was loaded with.
+pub fn get() -> Config {
+    unsafe { CONFIG }
+}
+
+/// Makes `config` the module's configuration.
+pub fn set(config: Config) {
+    unsafe {
+        CONFIG = config;
+        redis::LOG_LEVEL = config.log_level;
+        redis::PANIC_POLICY = config.panic_policy;
+    }
+}
+
+impl Config {
+    /// Parses module arguments, starting from the defaults.
+    pub fn from_args(args: &[redis::RedisStr]) -> Result<Config, ColorError> {
+        let mut config = DEFAULT;
+        if !args.len().is_multiple_of(2) {
+            return Err(ColorError::Syntax(format!("{} needs a value", args[args.len() - 1].to_str()?)))
+        }
+        for pair in args.chunks(2) {
+            let (name, value) = (&pair[0], &pair[1]);
+            match name.to_str()?.to_uppercase().as_str() {
+                "DEFAULT-ALPHA" => config.default_alpha = value.parse("an alpha within 0 and 255")?,
+                "DEFAULT-FORMAT" => config.default_format = value.parse("hex, rgb or oklch")?,
+                "STRICT-PARSING" => config.strict_parsing = parse_bool(value)?,
+                "LOG-LEVEL" => config.log_level = parse_log_level(value)?,
+                "COMMAND-PREFIX" => {
+                    let prefix = value.to_str()?;
+                    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                        return Err(value.parse_error("a command prefix made of letters, digits, - and _"))
+                    }
+                    config.command_prefix = leak(prefix.to_lowercase());
+                }
+                "MAX-SEARCH-RESULTS" => config.max_search_results = value.parse("a count")?,
+                "MAX-TRANSITION-MS" => config.max_transition_ms = value.parse::<u32>("a duration in milliseconds")? as i64,
+                "HISTORY-LENGTH" => config.history_length = value.parse("a count")?,
+                "INDEX" => config.index = parse_bool(value)?,
+                "EVENTS-CHANNEL" => {
+                    config.events_channel = match value.to_str()? {
+                        c if c.eq_ignore_ascii_case("none") => None,
+                        c => Some(leak(c.to_string())),
+                    }
+                }
+                "PANIC-POLICY" => {
+                    config.panic_policy = match value.to_str()?.to_lowercase().as_str() {
+                        "contain" => PanicPolicy::Contain,
+                        "abort" => PanicPolicy::Abort,
+                        _ => return Err(value.parse_error("contain or abort")),
+                    }
+                }
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The name `name`, one of ours, is registered under.
+    pub fn command_name(&self, name: &str) -> String {
+        match name.strip_prefix("color.") {
+            Some(rest) => format!("{}.{}", self.command_prefix, rest),
+            None => name.to_string(),
+        }
+    }
+
+    /// Every option with its value, as COLOR.CONFIG GET reports them.
+    pub fn options(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("default-alpha", self.default_alpha.to_string()),
+            ("default-format", self.default_format.to_string()),
+            ("strict-parsing", yes_no(self.strict_parsing)),
+            ("log-level", format!("{:?}", self.log_level).to_lowercase()),
+            ("command-prefix", self.command_prefix.to_string()),
+            ("max-search-results", self.max_search_results.to_string()),
+            ("max-transition-ms", self.max_transition_ms.to_string()),
+            ("history-length", self.history_length.to_string()),
+            ("index", yes_no(self.index)),
+            ("events-channel", self.events_channel.unwrap_or("none").to_string()),
+            ("panic-policy", format!("{:?}", self.panic_policy).to_lowercase()),
+        ]
+    }
+}
+
+// Configuration is read once per load, so the few strings in it are simply
+// leaked to give them the lifetime of the module.
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn parse_bool(value: &redis::RedisStr) -> Result<bool, ColorError> {
+    match value.to_str()?.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(value.parse_error("yes or no")),
+    }
+}
+
+fn parse_log_level(value: &redis::RedisStr) -> Result<LogLevel, ColorError> {
+    match value.to_str()?.to_lowercase().as_str() {
+        "debug" => Ok(LogLevel::Debug),
+        "verbose" => Ok(LogLevel::Verbose),
+        "notice" => Ok(LogLevel::Notice),
+        "warning" => Ok(LogLevel::Warning),
+        _ => Err(value.parse_error("debug, verbose, notice or warning")),
+    }
+}
+
+fn yes_no(b: bool) -> String {
+    String::from(if b { "yes" } else { "no" })
+}
+
+impl FromStr for Format {
+    type Err = ColorError;
+
+    fn from_str(s: &str) -> Result<Format, ColorError> {
+        match s.to_lowercase().as_str() {
+            "hex" => Ok(Format::Hex),
+            "rgb" => Ok(Format::Rgb),
+            "oklch" => Ok(Format::Oklch),
+            _ => Err(ColorError::parse(0, s, "hex, rgb or oklch")),
+        }
+    }
+}
+
+// Implements COLOR.CONFIG GET pattern
+//
+// Replies with the name and value of every option matching the glob
+// `pattern`, like CONFIG GET does for Redis' own options.
+pub struct ConfigCommand {}
+impl Command for ConfigCommand {
+    fn name(&self) -> &'static str { "color.config" }
+    fn arity(&self) -> i32 { 3 }
+    fn flags(&self) -> CommandFlags {
+        CommandFlags::READONLY | CommandFlags::ALLOW_LOADING | CommandFlags::ALLOW_STALE
+    }
+    fn key_spec(&self) -> KeySpec { KeySpec::NONE }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        if !args[1].eq_ignore_case("get") {
+            return Err(ColorError::Syntax(format!("unknown subcommand {}", args[1].to_str()?)))
+        }
+        let pattern = args[2].as_bytes();
+        let mut reply = r.reply_postponed_array()?;
+        for (name, value) in get().options() {
+            if glob::matches(pattern, name.as_bytes(), true) {
+                reply.string(name)?;
+                reply.string(value)?;
+            }
+        }
+        reply.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+
+    #[test]
+    fn config_get_matches_pattern() {
+        let mut server = Server::with_args(&["default-alpha", "128", "MAX-SEARCH-RESULTS", "2"]).unwrap();
+        assert_eq!(server.run(&["COLOR.CONFIG", "GET", "max-*"]), Reply::bulks(&["max-search-results", "2", "max-transition-ms", "0"]));
+        assert_eq!(server.run(&["COLOR.CONFIG", "GET", "DEFAULT-ALPHA"]), Reply::bulks(&["default-alpha", "128"]));
+        assert_eq!(server.run(&["COLOR.CONFIG", "GET", "nope"]), Reply::bulks(&[]));
+        assert!(server.run(&["COLOR.CONFIG", "SET", "index"]).is_error("ERR"));
+    }
+
+    #[test]
+    fn invalid_arguments_fail_the_load() {
+        let log = Server::with_args(&["DEFAULT-ALPHA", "256"]).err().unwrap();
+        assert!(log.contains("Invalid module arguments"), "{}", log);
+        assert!(Server::with_args(&["STRICT-PARSING", "maybe"]).is_err());
+        assert!(Server::with_args(&["COMMAND-PREFIX", "a.b"]).is_err());
+        assert!(Server::with_args(&["LOG-LEVEL"]).is_err());
+        assert!(Server::with_args(&["COLOURS", "yes"]).is_err());
+    }
+
+    #[test]
+    fn command_prefix_renames_commands() {
+        let mut server = Server::with_args(&["COMMAND-PREFIX", "Paint"]).unwrap();
+        assert_eq!(server.run(&["PAINT.SET", "pink", "#ff55ef"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["PAINT.GET", "pink"]), Reply::simple("#ff55ef"));
+        assert!(server.run(&["COLOR.GET", "pink"]).is_error("ERR unknown command"));
+    }
+
+    #[test]
+    fn default_alpha_applies_to_short_colors() {
+        let mut server = Server::with_args(&["DEFAULT-ALPHA", "128"]).unwrap();
+        server.set_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff55ef80"));
+        server.run(&["COLOR.SET", "pink", "#ff55efff"]);
+        // Written in full, so the AOF means the same under any DEFAULT-ALPHA.
+        assert_eq!(server.rewrite_aof("pink"), vec![vec!["color.set", "pink", "#ff55efff"]]);
+    }
+
+    #[test]
+    fn strict_parsing_wants_full_hex() {
+        let mut server = Server::with_args(&["STRICT-PARSING", "yes"]).unwrap();
+        assert!(server.run(&["COLOR.SET", "pink", "ff55ef"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SET", "pink", "#f5e"]).is_error("ERR"));
+        assert_eq!(server.run(&["COLOR.SET", "pink", "#ff55ef"]), Reply::simple("OK"));
+    }
+
+    #[test]
+    fn default_format_is_used_in_replies() {
+        let mut server = Server::with_args(&["DEFAULT-FORMAT", "rgb"]).unwrap();
+        server.set_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("rgb(255 85 239)"));
+        server.run(&["COLOR.SET", "pink", "#ff55ef80"]);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("rgb(255 85 239 / 0.502)"));
+    }
+}
@@ -2,17 +2,79 @@ use std;
 use std::error;
 use std::fmt;
 
+use redis::raw;
+
 #[derive(Debug)]
 pub enum ColorError {
   Generic(GenericError),
   FromUtf8(std::string::FromUtf8Error),
   ParseInt(std::num::ParseIntError),
+
+  /// The key holds a value of another type.
+  WrongType,
+
+  /// The command was called with the wrong number of arguments. Holds the
+  /// command name.
+  WrongArity(String),
+
+  /// The arguments don't follow the command's syntax, e.g. an unknown option.
+  Syntax(String),
+
+  /// An argument couldn't be parsed. `position` is the index of the argument
+  /// in the command line, the command name being at 0.
+  Parse { position: usize, value: String, expected: String },
+
+  /// A value was understood but is outside of the accepted range.
+  OutOfRange(String),
+
+  /// Redis itself replied with an error. Holds the error verbatim, including
+  /// its prefix.
+  Redis(String),
+
+  /// A key that needs to exist doesn't.
+  NotFound(String),
 }
 
 impl ColorError {
   pub fn generic(message: &str) -> ColorError {
     ColorError::Generic(GenericError::new(message))
   }
+
+  pub fn parse(position: usize, value: &str, expected: &str) -> ColorError {
+    ColorError::Parse {
+      position,
+      value: String::from(value),
+      expected: String::from(expected),
+    }
+  }
+
+  /// The prefix of the error reply sent to clients, which they use to tell
+  /// kinds of errors apart.
+  pub fn prefix(&self) -> &str {
+    match *self {
+      ColorError::WrongType => "WRONGTYPE",
+      ColorError::NotFound(_) => "NOTFOUND",
+      ColorError::Redis(ref msg) => msg.split(' ').next().unwrap_or("ERR"),
+      _ => "ERR",
+    }
+  }
+
+  /// Whether the error comes from arguments that couldn't be made sense of,
+  /// rather than from what the command did with them.
+  pub fn is_parse_failure(&self) -> bool {
+    matches!(*self,
+      ColorError::FromUtf8(_) | ColorError::ParseInt(_) |
+      ColorError::WrongArity(_) | ColorError::Syntax(_) | ColorError::Parse { .. })
+  }
+
+  /// The full error reply for clients: the prefix followed by the message.
+  pub fn reply(&self) -> String {
+    match *self {
+      ColorError::WrongType => String::from(raw::ERRORMSG_WRONGTYPE),
+      ColorError::Redis(ref msg) => msg.clone(),
+      _ => format!("{} {}", self.prefix(), self),
+    }
+  }
 }
 
 impl From<std::string::FromUtf8Error> for ColorError {
@@ -33,24 +95,26 @@ impl fmt::Display for ColorError {
       ColorError::Generic(ref err) => write!(f, "{}", err),
       ColorError::FromUtf8(ref err) => write!(f, "{}", err),
       ColorError::ParseInt(ref err) => write!(f, "{}", err),
+      ColorError::WrongType => write!(f, "Operation against a key holding the wrong kind of value"),
+      ColorError::WrongArity(ref cmd) => write!(f, "wrong number of arguments for '{}' command", cmd),
+      ColorError::Syntax(ref msg) => write!(f, "syntax error, {}", msg),
+      ColorError::Parse { position, ref value, ref expected } => {
+        write!(f, "invalid argument {:?} at position {}, expected {}", value, position, expected)
+      }
+      ColorError::OutOfRange(ref msg) => write!(f, "{}", msg),
+      ColorError::Redis(ref msg) => write!(f, "{}", msg),
+      ColorError::NotFound(ref key) => write!(f, "no such key '{}'", key),
     }
   }
 }
 
 impl error::Error for ColorError {
-  fn description(&self) -> &str {
-    match *self {
-      ColorError::Generic(ref err) => err.description(),
-      ColorError::FromUtf8(ref err) => err.description(),
-      ColorError::ParseInt(ref err) => err.description(),
-    }
-  }
-
-  fn cause(&self) -> Option<&error::Error> {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
     match *self {
       ColorError::Generic(ref err) => Some(err),
       ColorError::FromUtf8(ref err) => Some(err),
       ColorError::ParseInt(ref err) => Some(err),
+      _ => None,
     }
   }
 }
@@ -63,14 +127,10 @@ impl GenericError {
   }
 }
 
-impl<'a> fmt::Display for GenericError {
+impl fmt::Display for GenericError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Store error: {}", self.message)
+    write!(f, "{}", self.message)
   }
 }
 
-impl<'a> error::Error for GenericError {
-  fn description(&self) -> &str { self.message.as_str() }
-
-  fn cause(&self) -> Option<&error::Error> { None }
-}
\ No newline at end of file
+impl error::Error for GenericError {}
@@ -0,0 +1,91 @@
+// Change events for color keys.
+//
+// Every command that creates, overwrites or adjusts a color publishes a small
+// JSON document on a Pub/Sub channel so that clients can react to changes
+// instead of polling COLOR.GET:
+//
+//     {"command":"color.set","key":"pink","old":"#ff55ef","new":"#fe55fe"}
+//
+// `old` is `null` when the key didn't hold a color before.
+//
+// Events are off unless the module is loaded with an EVENTS-CHANNEL. They're
+// a side effect of a write that already happened, so a failed PUBLISH is
+// logged rather than failing the command.
+
+use config;
+use redis;
+use redis::LogLevel;
+use Color;
+
+/// Publishes a change event for `key` on the EVENTS-CHANNEL. Does nothing when
+/// events are disabled.
+pub fn publish(
+    r: &redis::Redis,
+    command: &str,
+    key: &[u8],
+    old: Option<&Color>,
+    new: &Color,
+) {
+    let channel = match config::get().events_channel {
+        Some(channel) => channel,
+        None => return,
+    };
+    let old = match old {
+        Some(c) => format!("\"{}\"", c),
+        None => "null".to_string(),
+    };
+    let message = format!(
+        "{{\"command\":{},\"key\":{},\"old\":{},\"new\":\"{}\"}}",
+        json_string(command), json_string(&String::from_utf8_lossy(key)), old, new,
+    );
+    if let Err(err) = r.call("PUBLISH", &[channel, message.as_str()]) {
+        r.log(LogLevel::Warning, &format!("Can't publish change event on {}: {}", channel, err));
+    }
+}
+
+// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+
+    #[test]
+    fn set_publishes_change() {
+        let server = Server::with_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.published, vec![]);
+        drop(server);
+
+        let mut server = Server::with_args(&["EVENTS-CHANNEL", "color:events"]).unwrap();
+        server.set_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.published, vec![(
+            "color:events".to_string(),
+            r##"{"command":"color.set","key":"pink","old":null,"new":"#ff55ef"}"##.to_string(),
+        )]);
+    }
+
+    #[test]
+    fn set_survives_failed_publish() {
+        let mut server = Server::with_args(&["EVENTS-CHANNEL", "color:events"]).unwrap();
+        server.publish_error = Some("NOPERM this user has no permissions to access the channel".to_string());
+        assert_eq!(server.run(&["COLOR.SET", "pink", "#ff55ef"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff55ef"));
+        assert!(server.log.iter().any(|(level, m)| level == "warning" && m.contains("NOPERM")));
+    }
+}
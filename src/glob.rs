@@ -0,0 +1,89 @@
+// Glob-style pattern matching, as used by KEYS, SCAN MATCH and CONFIG GET:
+//
+//     *      any sequence of bytes, including none
+//     ?      any single byte
+//     [abc]  one of the listed bytes; [^abc] any other byte; [a-z] a range
+//     \x     the byte x itself
+
+/// Whether `s` matches the glob `pattern`. With `nocase` ASCII letters match
+/// regardless of case.
+pub fn matches(pattern: &[u8], s: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| if nocase { a.eq_ignore_ascii_case(&b) } else { a == b };
+    let (mut p, mut i) = (0, 0);
+    // Where to resume after the last `*`: the pattern position after it and
+    // the input position it currently stands for.
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        let step = if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p + 1, i));
+                    p += 1;
+                    continue
+                }
+                b'?' => Some(p + 1),
+                b'[' => match_class(pattern, p, s[i], &eq),
+                b'\\' if p + 1 < pattern.len() => {
+                    if eq(pattern[p + 1], s[i]) { Some(p + 2) } else { None }
+                }
+                c => if eq(c, s[i]) { Some(p + 1) } else { None },
+            }
+        } else {
+            None
+        };
+        match (step, star) {
+            (Some(next), _) => { p = next; i += 1; }
+            // Let the last `*` swallow one more byte and retry.
+            (None, Some((after, at))) => { star = Some((after, at + 1)); p = after; i = at + 1; }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches `c` against the class starting at `pattern[start]`, which is a `[`.
+// Returns the position after the class if `c` is in it. An unterminated class
+// runs to the end of the pattern.
+fn match_class<F: Fn(u8, u8) -> bool>(pattern: &[u8], start: usize, c: u8, eq: &F) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+    let mut found = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            found |= eq(pattern[p + 1], c);
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            found |= (lo..=hi).any(|x| eq(x, c));
+            p += 3;
+        } else {
+            found |= eq(pattern[p], c);
+            p += 1;
+        }
+    }
+    if found != negate { Some((p + 1).min(pattern.len())) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn globs() {
+        assert!(matches(b"*", b"", false));
+        assert!(matches(b"h?llo", b"hello", false));
+        assert!(matches(b"h*llo", b"heeeello", false));
+        assert!(matches(b"h[ae]llo", b"hallo", false));
+        assert!(!matches(b"h[^e]llo", b"hello", false));
+        assert!(matches(b"h[a-b]llo", b"hbllo", false));
+        assert!(matches(b"max-*", b"max-search-results", false));
+        assert!(!matches(b"max-*", b"default-alpha", false));
+        assert!(matches(b"a\\*", b"a*", false));
+        assert!(!matches(b"a\\*", b"ab", false));
+        assert!(matches(b"DEFAULT-*", b"default-alpha", true));
+        assert!(!matches(b"DEFAULT-*", b"default-alpha", false));
+    }
+}
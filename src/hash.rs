@@ -0,0 +1,146 @@
+// Colors kept in the fields of plain Redis hashes, e.g. a theme stored as
+// token => hex string. The hashes stay readable by any client; these commands
+// only add the module's parsing on the way in and its formatting on the way
+// out.
+
+use std::str;
+
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags};
+use {Color, Format};
+
+// Implements COLOR.HSET key field color [field color ...]
+//
+// Sets each field of the hash at `key` to its color, written in canonical hex
+// notation (see `Color::to_canonical_hex`). Every color is checked before
+// anything is written, so either all fields are set or none is. Replies with
+// the number of fields that were added, like HSET.
+pub struct HSetColorCommand {}
+impl Command for HSetColorCommand {
+    fn name(&self) -> &'static str { "color.hset" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM | CommandFlags::FAST }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        if !args.len().is_multiple_of(2) {
+            return Err(ColorError::WrongArity(self.name().to_string()))
+        }
+        let fields = args[2..].chunks(2)
+            .map(|pair| Ok((pair[0].as_bytes(), pair[1].parse::<Color>("a hex color")?)))
+            .collect::<Result<Vec<(&[u8], Color)>, ColorError>>()?;
+
+        let key = r.open_key_writable(args[1]);
+        if !key.is_empty() && !key.is_hash() {
+            return Err(ColorError::WrongType)
+        }
+        let mut added = 0;
+        for (field, color) in fields {
+            if key.hash_set(field, color.to_canonical_hex().as_bytes())? {
+                added += 1;
+            }
+        }
+        r.reply_integer(added)
+    }
+}
+
+// Implements COLOR.HGET key field [FORMAT hex|rgb|oklch]
+//
+// Replies with the color in `field` of the hash at `key`, written in FORMAT
+// or the DEFAULT-FORMAT option, or nil if there's no such field. Fails if the
+// field holds something that isn't a color.
+pub struct HGetColorCommand {}
+impl Command for HGetColorCommand {
+    fn name(&self) -> &'static str { "color.hget" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::FAST }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let format = match args.len() {
+            3 => config::get().default_format,
+            5 if args[3].eq_ignore_case("format") => args[4].parse::<Format>("hex, rgb or oklch")?,
+            5 => return Err(ColorError::Syntax(format!("unknown option {}", args[3].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+        let key = r.open_key(args[1]);
+        let value = match key.hash_get(args[2].as_bytes())? {
+            Some(value) => value,
+            None => return r.reply_null(),
+        };
+        let color = String::from_utf8(value).ok()
+            .and_then(|s| s.parse::<Color>().ok())
+            .ok_or_else(|| ColorError::OutOfRange(format!(
+                "field {} doesn't hold a color", String::from_utf8_lossy(args[2].as_bytes()),
+            )))?;
+        r.reply_simple_string(&color.format(format))
+    }
+}
+
+/// The colors in the palette at `key`, a hash whose values are colors, by
+/// field. Fields are any bytes, like in any hash. A missing palette is an
+/// empty one.
+pub fn read_palette(r: &redis::Redis, key: &[u8]) -> Result<Vec<(Vec<u8>, Color)>, ColorError> {
+    let values = match r.call("HGETALL", &[key])? {
+        redis::Reply::Array(values) => values,
+        other => return Err(error!("Unexpected reply to HGETALL: {:?}", other)),
+    };
+    values.chunks(2)
+        .map(|pair| match (&pair[0], &pair[1]) {
+            (redis::Reply::String(field), redis::Reply::String(value)) => {
+                match str::from_utf8(value).ok().and_then(|v| v.parse::<Color>().ok()) {
+                    Some(color) => Ok((field.clone(), color)),
+                    None => Err(error!(
+                        "palette {} holds {:?}, which isn't a color",
+                        String::from_utf8_lossy(key), String::from_utf8_lossy(value),
+                    )),
+                }
+            }
+            _ => Err(error!("Unexpected reply to HGETALL: {:?}", pair)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+
+    #[test]
+    fn hset_canonicalizes_colors() {
+        let mut server = Server::new();
+        server.hset("theme", "bg", "#FFF");
+        assert_eq!(server.run(&["COLOR.HSET", "theme", "fg", "#F5E", "bg", "#00000080"]), Reply::Integer(1));
+        assert_eq!(server.hget("theme", "fg"), Some("#ff55ee".to_string()));
+        assert_eq!(server.hget("theme", "bg"), Some("#00000080".to_string()));
+
+        // Nothing is written unless every color parses.
+        assert!(server.run(&["COLOR.HSET", "theme", "fg", "#000", "link", "blue"]).is_error("ERR"));
+        assert_eq!(server.hget("theme", "fg"), Some("#ff55ee".to_string()));
+        assert!(server.run(&["COLOR.HSET", "theme", "fg"]).is_error("ERR wrong number"));
+        assert!(server.run(&["COLOR.HSET", "theme", "fg", "#000", "bg"]).is_error("ERR wrong number"));
+
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert!(server.run(&["COLOR.HSET", "pink", "fg", "#000"]).is_error("WRONGTYPE"));
+    }
+
+    #[test]
+    fn hset_writes_alpha_when_it_isnt_the_default() {
+        let mut server = Server::with_args(&["DEFAULT-ALPHA", "0"]).unwrap();
+        server.run(&["COLOR.HSET", "theme", "fg", "#ff55eeff"]);
+        assert_eq!(server.hget("theme", "fg"), Some("#ff55eeff".to_string()));
+        assert_eq!(server.run(&["COLOR.HGET", "theme", "fg"]), Reply::simple("#ff55ee"));
+    }
+
+    #[test]
+    fn hget_formats_colors() {
+        let mut server = Server::new();
+        server.hset("theme", "fg", "F5E");
+        server.hset("theme", "font", "Helvetica");
+        assert_eq!(server.run(&["COLOR.HGET", "theme", "fg"]), Reply::simple("#ff55ee"));
+        assert_eq!(server.run(&["COLOR.HGET", "theme", "fg", "FORMAT", "rgb"]), Reply::simple("rgb(255 85 238)"));
+        assert_eq!(server.run(&["COLOR.HGET", "theme", "bg"]), Reply::Null);
+        assert_eq!(server.run(&["COLOR.HGET", "nope", "bg"]), Reply::Null);
+        assert!(server.run(&["COLOR.HGET", "theme", "font"]).is_error("ERR field font"));
+        assert!(server.run(&["COLOR.HGET", "theme", "fg", "FORMAT", "cmyk"]).is_error("ERR"));
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert!(server.run(&["COLOR.HGET", "pink", "fg"]).is_error("WRONGTYPE"));
+    }
+}
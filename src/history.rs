@@ -0,0 +1,252 @@
+// The history of color keys, so that a color changed by mistake can be traced
+// back to when it changed and who changed it, and put back.
+//
+// Keys only remember writes while the HISTORY-LENGTH option is set. The
+// history is part of the value: it goes into RDB files, AOF rewrites replay
+// every write in it, and each write replicates as a single command carrying
+// the time and client it was recorded with (REVISION ms client), so that
+// replicas and the AOF remember the same writes as the master. Clients can't
+// send REVISION themselves, or they could make up the history.
+
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use {color_changed, replicate_color_value, ColorValue};
+
+/// Reads the time and client that follow REVISION at `args[at]`, for writes
+/// replayed with the revision they were recorded with. Only the master and the
+/// AOF replay writes.
+pub fn parse_revision(r: &redis::Redis, args: &[redis::RedisStr], at: usize) -> Result<(i64, u64), ColorError> {
+    if !r.is_replayed() {
+        return Err(ColorError::Syntax("REVISION is only accepted from the master or the AOF".to_string()))
+    }
+    if at + 2 >= args.len() {
+        return Err(ColorError::Syntax("REVISION needs a time and a client".to_string()))
+    }
+    Ok((args[at + 1].to_i64()?, args[at + 2].parse("a client ID")?))
+}
+
+// Implements COLOR.HISTORY key [COUNT n]
+//
+// Replies with the last writes of the color at `key`, the latest first, as
+// [color, Unix milliseconds, client ID] triples. COUNT caps their number. A
+// missing key has no history.
+pub struct HistoryColorCommand {}
+impl Command for HistoryColorCommand {
+    fn name(&self) -> &'static str { "color.history" }
+    fn arity(&self) -> i32 { -2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let count = match args.len() {
+            2 => usize::MAX,
+            4 if args[2].eq_ignore_case("count") => args[3].parse::<usize>("a count")?,
+            4 => return Err(ColorError::Syntax(format!("unknown option {}", args[2].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+        let key = r.open_key(args[1]);
+        let history = match key.value::<ColorValue>()? {
+            Some(value) => value.history.iter().rev().take(count).cloned().collect(),
+            None => Vec::new(),
+        };
+
+        let format = config::get().default_format;
+        let mut reply = r.reply_array(history.len())?;
+        for revision in history {
+            let mut triple = reply.array(3)?;
+            triple.string(revision.color.format(format))?;
+            triple.integer(revision.ms)?;
+            triple.integer(revision.client as i64)?;
+            triple.finish()?;
+        }
+        reply.finish()
+    }
+}
+
+// Implements COLOR.ROLLBACK key [STEPS n]
+//
+// Sets the color at `key` back to what the write `n` writes before the last
+// one wrote, 1 by default, stopping any transition. The rollback is itself a
+// write, so it goes into the history and can be rolled back in turn. Fails if
+// the history doesn't go back that far. Replies with the color.
+//
+// The rollback is replicated as the COLOR.SET of the color it restored, with
+// its revision.
+pub struct RollbackColorCommand {}
+impl Command for RollbackColorCommand {
+    fn name(&self) -> &'static str { "color.rollback" }
+    fn arity(&self) -> i32 { -2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let steps = match args.len() {
+            2 => 1,
+            4 if args[2].eq_ignore_case("steps") => args[3].parse::<usize>("a number of steps")?,
+            4 => return Err(ColorError::Syntax(format!("unknown option {}", args[2].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+        if steps == 0 {
+            return Err(ColorError::OutOfRange("STEPS must be at least 1".to_string()))
+        }
+
+        let key = r.open_key_writable(args[1]);
+        let (old, target) = match key.value::<ColorValue>()? {
+            Some(value) => {
+                let target = match value.history.len().checked_sub(steps + 1) {
+                    Some(i) => value.history[i].color,
+                    None => return Err(ColorError::OutOfRange(format!(
+                        "the history of {} only goes back {} writes", args[1].to_str()?,
+                        value.history.len().saturating_sub(1),
+                    ))),
+                };
+                (value.at(redis::milliseconds()), target)
+            }
+            None => return Err(ColorError::NotFound(args[1].to_str()?.to_string())),
+        };
+
+        let value = key.write(ColorValue::new(target))?;
+        replicate_color_value(&r, args[1].as_bytes(), &value)?;
+        color_changed(&r, self.name(), args[1].as_bytes(), Some(&old), &target);
+        r.reply_simple_string(&target.format(config::get().default_format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Run, Server, START_MS};
+    use {ColorValue, Revision};
+
+    fn revision(color: &str, ms: i64, client: i64) -> Reply {
+        Reply::Array(vec![Reply::Bulk(color.as_bytes().to_vec()), Reply::Integer(ms), Reply::Integer(client)])
+    }
+
+    #[test]
+    fn history_records_writes() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "3"]).unwrap();
+        let start = START_MS;
+        server.run(&["COLOR.SET", "brand", "#ff0000"]);
+        server.set_client_id(7);
+        server.set_time(start + 10);
+        server.run(&["COLOR.TRANSITION", "brand", "#00ff00", "DURATION", "1000"]);
+        server.set_time(start + 20);
+        server.run(&["COLOR.SET", "brand", "#0000ff"]);
+        server.set_time(start + 30);
+        server.run(&["COLOR.SET", "brand", "#ffffff"]);
+        assert_eq!(server.run(&["COLOR.HISTORY", "brand"]), Reply::Array(vec![
+            revision("#ffffff", start + 30, 7),
+            revision("#0000ff", start + 20, 7),
+            revision("#00ff00", start + 10, 7),
+        ]));
+        assert_eq!(server.run(&["COLOR.HISTORY", "brand", "COUNT", "1"]), Reply::Array(vec![
+            revision("#ffffff", start + 30, 7),
+        ]));
+        assert_eq!(server.run(&["COLOR.HISTORY", "nope"]), Reply::Array(vec![]));
+
+        let rdb = server.dump("brand");
+        server.restore("copy", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.HISTORY", "copy"]), server.run(&["COLOR.HISTORY", "brand"]));
+        let size = ::std::mem::size_of::<ColorValue>();
+        assert!(server.mem_usage("brand") >= size + 3 * ::std::mem::size_of::<Revision>());
+
+        server.hset("palette", "a", "#000");
+        assert!(server.run(&["COLOR.HISTORY", "palette"]).is_error("WRONGTYPE"));
+    }
+
+    #[test]
+    fn history_is_the_same_on_replicas_and_reloads() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "3"]).unwrap();
+        let start = START_MS;
+        server.run(&["COLOR.SET", "brand", "#ff0000"]);
+        server.set_client_id(7);
+        server.set_time(start + 10);
+        server.run(&["COLOR.TRANSITION", "brand", "#00ff00", "DURATION", "1000"]);
+        server.set_time(start + 20);
+        server.run(&["COLOR.INCRBY", "brand", "b", "255"]);
+        let history = server.run(&["COLOR.HISTORY", "brand"]);
+        let color = server.run(&["COLOR.GET", "brand"]);
+        let replicated = server.replicated.clone();
+        assert_eq!(replicated.len(), 3);
+        let aof = server.rewrite_aof("brand");
+        assert_eq!(aof.len(), 3);
+        drop(server);
+
+        let runs: [(Run, _); 2] = [(Server::run_replicated, replicated), (Server::run_loading, aof)];
+        for (run, commands) in &runs {
+            let mut server = Server::with_args(&["HISTORY-LENGTH", "3"]).unwrap();
+            server.set_client_id(9);
+            server.set_time(start + 20);
+            server.replay(*run, commands);
+            assert_eq!(server.run(&["COLOR.HISTORY", "brand"]), history);
+            assert_eq!(server.run(&["COLOR.GET", "brand"]), color);
+            // Rolling back gives the same answer as on the master.
+            assert_eq!(server.run(&["COLOR.ROLLBACK", "brand", "STEPS", "2"]), Reply::simple("#ff0000"));
+        }
+    }
+
+    #[test]
+    fn long_histories_rewrite_in_full() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "100"]).unwrap();
+        for i in 0..80 {
+            server.set_time(START_MS + i);
+            server.run(&["COLOR.SET", "brand", &format!("#0000{:02x}", i)]);
+        }
+        let history = server.run(&["COLOR.HISTORY", "brand"]);
+        let aof = server.rewrite_aof("brand");
+        assert_eq!(aof.len(), 80);
+        drop(server);
+
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "100"]).unwrap();
+        server.replay(Server::run_loading, &aof);
+        assert_eq!(server.run(&["COLOR.HISTORY", "brand"]), history);
+    }
+
+    #[test]
+    fn clients_cant_forge_revisions() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "3"]).unwrap();
+        let forged = ["COLOR.SET", "brand", "#ff0000", "REVISION", "1000", "42"];
+        assert!(server.run(&forged).is_error("ERR syntax error, REVISION is only accepted from the master or the AOF"));
+        assert!(server.run(&["COLOR.TRANSITION", "brand", "#00ff00", "DURATION", "10", "REVISION", "1000", "42"])
+            .is_error("ERR syntax error, REVISION"));
+        assert!(!server.exists("brand"));
+
+        assert_eq!(server.run_replicated(&forged), Reply::simple("OK"));
+        assert_eq!(server.run_loading(&["COLOR.SET", "brand", "#0000ff", "REVISION", "2000", "43"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.HISTORY", "brand"]), Reply::Array(vec![
+            revision("#0000ff", 2000, 43),
+            revision("#ff0000", 1000, 42),
+        ]));
+    }
+
+    #[test]
+    fn history_is_off_by_default() {
+        let mut server = Server::with_colors(&[("brand", "#ff0000")]);
+        server.run(&["COLOR.SET", "brand", "#00ff00"]);
+        assert_eq!(server.run(&["COLOR.HISTORY", "brand"]), Reply::Array(vec![]));
+        assert!(server.run(&["COLOR.ROLLBACK", "brand"]).is_error("ERR"));
+        assert_eq!(server.mem_usage("brand"), 0);
+    }
+
+    #[test]
+    fn rollback_restores_and_is_recorded() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "5"]).unwrap();
+        for color in &["#ff0000", "#00ff00", "#0000ff"] {
+            server.run(&["COLOR.SET", "brand", color]);
+        }
+        server.replicated.clear();
+        assert_eq!(server.run(&["COLOR.ROLLBACK", "brand", "STEPS", "2"]), Reply::simple("#ff0000"));
+        assert_eq!(server.run(&["COLOR.GET", "brand"]), Reply::simple("#ff0000"));
+        let start = START_MS.to_string();
+        assert_eq!(server.replicated, vec![vec!["color.set", "brand", "#ff0000ff", "REVISION", &start, "1"]]);
+        // Undoing the rollback.
+        assert_eq!(server.run(&["COLOR.ROLLBACK", "brand"]), Reply::simple("#0000ff"));
+        match server.run(&["COLOR.HISTORY", "brand"]) {
+            Reply::Array(revisions) => assert_eq!(revisions.len(), 5),
+            other => panic!("{:?}", other),
+        }
+
+        assert!(server.run(&["COLOR.ROLLBACK", "brand", "STEPS", "5"]).is_error("ERR the history of brand only goes back 4"));
+        assert!(server.run(&["COLOR.ROLLBACK", "brand", "STEPS", "0"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.ROLLBACK", "nope"]).is_error("NOTFOUND"));
+        assert!(server.run(&["COLOR.ROLLBACK", "brand", "COUNT", "1"]).is_error("ERR syntax"));
+    }
+}
@@ -0,0 +1,138 @@
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, KeySpec};
+use theme;
+use {COLOR_ENCODING_VERSION, MODULE_NAME, MODULE_VERSION};
+
+const SECTIONS: &[&str] = &["module", "types", "commands"];
+
+// Implements COLOR.INFO [section]
+//
+// Replies with statistics about the module in the format of INFO: a bulk
+// string of `# Section` headers, each followed by `field:value` lines. The
+// sections are:
+//
+//     module    name and versions
+//     types     for each of our data types, the number of keys holding one,
+//               the memory their values allocated (none for plain colors,
+//               which are packed in the key) and their RDB encoding version
+//     commands  for each command called since the module was loaded, its
+//               calls, errors and errors caused by unparsable arguments
+//
+// Without a section every section is included.
+pub struct InfoCommand {}
+impl Command for InfoCommand {
+    fn name(&self) -> &'static str { "color.info" }
+    fn arity(&self) -> i32 { -1 }
+    fn flags(&self) -> CommandFlags {
+        CommandFlags::READONLY | CommandFlags::ALLOW_LOADING | CommandFlags::ALLOW_STALE
+    }
+    fn key_spec(&self) -> KeySpec { KeySpec::NONE }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let sections: Vec<&str> = match args.len() {
+            1 => SECTIONS.to_vec(),
+            2 => {
+                let name = args[1].to_str()?.to_lowercase();
+                match SECTIONS.iter().find(|&&s| s == name) {
+                    Some(&s) => vec![s],
+                    None => return Err(args[1].parse_error("module, types or commands")),
+                }
+            }
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+
+        let mut info = String::new();
+        for section in sections {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str(&format!("# {}\r\n", capitalize(section)));
+            for (field, value) in fields(section) {
+                info.push_str(&format!("{}:{}\r\n", field, value));
+            }
+        }
+        r.reply_string(info)
+    }
+}
+
+fn fields(section: &str) -> Vec<(String, String)> {
+    match section {
+        "module" => vec![
+            ("module_name".to_string(), MODULE_NAME.to_string()),
+            ("module_version".to_string(), MODULE_VERSION.to_string()),
+            ("crate_version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ],
+        "types" => vec![
+            ("type_dvd-color".to_string(), format!(
+                "keys={},memory={},encver={}",
+                redis::COLOR_STATS.keys(), redis::COLOR_STATS.memory(), COLOR_ENCODING_VERSION,
+            )),
+            ("type_dvd-theme".to_string(), format!(
+                "keys={},memory={},encver={}",
+                redis::THEME_STATS.keys(), redis::THEME_STATS.memory(), theme::ENCODING_VERSION,
+            )),
+        ],
+        _ => {
+            let config = config::get();
+            redis::stats::commands().into_iter()
+                .map(|(name, stats)| (
+                    format!("cmdstat_{}", config.command_name(name)),
+                    format!("calls={},errors={},parse_failures={}", stats.calls, stats.errors, stats.parse_failures),
+                ))
+                .collect()
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::Server;
+    use MODULE_VERSION;
+
+    #[test]
+    fn info_counts_keys_and_memory() {
+        let mut server = Server::new();
+        assert_eq!(server.run(&["COLOR.INFO", "types"]).info_field("type_dvd-color"),
+            Some("keys=0,memory=0,encver=2".to_string()));
+        server.run(&["COLOR.SET", "a", "#ff0000"]);
+        server.run(&["COLOR.SET", "b", "#00ff00"]);
+        server.run(&["COLOR.SET", "b", "#0000ff"]);
+        let size = server.mem_usage("a");
+        let usage = |keys: usize| format!("keys={},memory={},encver=2", keys, keys * size);
+        assert_eq!(server.run(&["COLOR.INFO"]).info_field("type_dvd-color"), Some(usage(2)));
+
+        let rdb = server.dump("a");
+        server.del("a");
+        assert_eq!(server.run(&["COLOR.INFO"]).info_field("type_dvd-color"), Some(usage(1)));
+        server.restore("c", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.INFO"]).info_field("type_dvd-color"), Some(usage(2)));
+    }
+
+    #[test]
+    fn info_counts_commands() {
+        let mut server = Server::with_args(&["COMMAND-PREFIX", "paint"]).unwrap();
+        server.run(&["PAINT.SET", "a", "#ff0000"]);
+        server.run(&["PAINT.SET", "a", "#ff00zz"]);
+        server.run(&["PAINT.SET", "a"]);
+        server.hset("palette", "pink", "#ff55ef");
+        server.run(&["PAINT.GET", "palette"]);
+        let info = server.run(&["PAINT.INFO", "COMMANDS"]);
+        assert_eq!(info.info_field("cmdstat_paint.set"), Some("calls=3,errors=2,parse_failures=2".to_string()));
+        assert_eq!(info.info_field("cmdstat_paint.get"), Some("calls=1,errors=1,parse_failures=0".to_string()));
+        assert_eq!(info.info_field("module_version"), None);
+
+        let info = server.run(&["PAINT.INFO", "module"]);
+        assert_eq!(info.info_field("module_name"), Some("redis-color".to_string()));
+        assert_eq!(info.info_field("module_version"), Some(MODULE_VERSION.to_string()));
+        assert!(server.run(&["PAINT.INFO", "keyspace"]).is_error("ERR"));
+    }
+}
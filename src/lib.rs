@@ -6,120 +6,967 @@ extern crate time;
 #[macro_use]
 mod macros;
 
+mod cas;
+mod channel;
+mod config;
 pub mod error;
+mod events;
+mod glob;
+mod hash;
+mod history;
+mod info;
+mod random;
 mod redis;
+mod scale;
+mod scan;
+mod search;
+mod theme;
+mod transition;
+mod wait;
 
+use std::borrow::Cow;
 use std::fmt;
+use std::mem;
+use std::ptr;
+use std::str::FromStr;
 use error::ColorError;
-use libc::c_int;
-use redis::Command;
+use libc::{c_int, c_void};
+use redis::{Command, CommandFlags, ModuleValue};
 use redis::raw;
 
 const MODULE_NAME: &str = "redis-color";
 const MODULE_VERSION: c_int = 1;
 
-#[derive(Debug)]
+// Version of the RDB encoding of dvd-color values. Version 0 only held the
+// color, version 1 adds an optional running transition and version 2 the
+// history, the key's last writes as their color, time and client.
+const COLOR_ENCODING_VERSION: c_int = 2;
+
+// Everything we allocate is accounted for by Redis. See `redis::allocator`.
+#[global_allocator]
+static ALLOCATOR: redis::allocator::RedisAlloc = redis::allocator::RedisAlloc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color { r: u8, g: u8, b: u8, a: u8 }
 
+/// A color in the OKLCH color space: perceptual lightness `l` in [0, 1],
+/// chroma `c` (roughly [0, 0.37] for colors inside sRGB) and hue `h` in
+/// degrees [0, 360).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Oklch { pub l: f64, pub c: f64, pub h: f64 }
+
 impl Color {
-    pub fn new() -> Color {
-        Color{r:58,g:64,b:12,a:78}
+    // Packs the color into a u32 as 0xRRGGBBAA.
+    fn pack(&self) -> u32 {
+        (self.r as u32) << 24 | (self.g as u32) << 16 | (self.b as u32) << 8 | self.a as u32
+    }
+
+    fn unpack(v: u32) -> Color {
+        Color { r: (v >> 24) as u8, g: (v >> 16) as u8, b: (v >> 8) as u8, a: v as u8 }
+    }
+
+    /// Converts the color to OKLCH. Alpha is ignored. Achromatic colors (grays)
+    /// have no meaningful hue and are reported with a hue of 0.
+    pub fn to_oklch(&self) -> Oklch {
+        let (r, g, b) = (to_linear(self.r), to_linear(self.g), to_linear(self.b));
+
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+        let lightness = 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s;
+        let a = 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s;
+        let b = 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s;
+
+        let chroma = (a * a + b * b).sqrt();
+        let hue = if chroma < ACHROMATIC_THRESHOLD {
+            0.0
+        } else {
+            let h = b.atan2(a).to_degrees();
+            if h < 0.0 { h + 360.0 } else { h }
+        };
+        Oklch { l: lightness, c: chroma, h: hue }
+    }
+
+    /// Writes the color in `format`.
+    pub fn format(&self, format: Format) -> String {
+        let alpha = if self.a == 0xff {
+            String::new()
+        } else {
+            format!(" / {}", trim_number(self.a as f64 / 255.0, 3))
+        };
+        match format {
+            Format::Hex => self.to_string(),
+            Format::Rgb => format!("rgb({} {} {}{})", self.r, self.g, self.b, alpha),
+            Format::Oklch => {
+                let lch = self.to_oklch();
+                format!("oklch({} {} {}{})",
+                    trim_number(lch.l, 4), trim_number(lch.c, 4), trim_number(lch.h, 2), alpha)
+            }
+        }
+    }
+
+    /// The shortest hex notation that reads back as the same color under the
+    /// current DEFAULT-ALPHA: `#rrggbb` when the color has the default alpha
+    /// of 255, `#rrggbbaa` otherwise.
+    pub fn to_canonical_hex(&self) -> String {
+        if self.a == 0xff && config::get().default_alpha == 0xff {
+            self.to_string()
+        } else {
+            self.to_full_hex()
+        }
+    }
+
+    /// Hex notation with the alpha always written out, so that reading it
+    /// back doesn't depend on DEFAULT-ALPHA. Used wherever colors are
+    /// persisted as commands.
+    pub fn to_full_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+    }
+
+    /// The WCAG 2 contrast ratio between `self` and `other`, from 1 (none)
+    /// to 21 (black on white). Alpha is ignored.
+    pub fn contrast(&self, other: &Color) -> f64 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    // Relative luminance as defined by WCAG 2.
+    fn luminance(&self) -> f64 {
+        0.2126 * to_linear(self.r) + 0.7152 * to_linear(self.g) + 0.0722 * to_linear(self.b)
+    }
+
+    /// How different `self` and `other` look: their distance in OKLab,
+    /// scaled by 100 so that, like with the CIE formulas, about 2 is the
+    /// smallest difference most people notice. Alpha is ignored.
+    pub fn delta_e(&self, other: &Color) -> f64 {
+        let (p, q) = (self.to_oklch(), other.to_oklch());
+        let (pa, pb) = (p.c * p.h.to_radians().cos(), p.c * p.h.to_radians().sin());
+        let (qa, qb) = (q.c * q.h.to_radians().cos(), q.c * q.h.to_radians().sin());
+        100.0 * ((p.l - q.l).powi(2) + (pa - qa).powi(2) + (pb - qb).powi(2)).sqrt()
+    }
+
+    /// Mixes `self` and `other`, `t` being the share of `other` in [0, 1].
+    /// Alpha is always mixed linearly.
+    pub fn mix(&self, other: &Color, t: f64, space: Space) -> Color {
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        let a = round_channel(lerp(self.a as f64, other.a as f64) / 255.0);
+        match space {
+            Space::Srgb => Color {
+                r: round_channel(lerp(self.r as f64, other.r as f64) / 255.0),
+                g: round_channel(lerp(self.g as f64, other.g as f64) / 255.0),
+                b: round_channel(lerp(self.b as f64, other.b as f64) / 255.0),
+                a,
+            },
+            Space::Oklch => {
+                let (from, to) = (self.to_oklch(), other.to_oklch());
+                // A gray has no hue of its own and takes the other color's.
+                let (h1, h2) = match (from.c < ACHROMATIC_THRESHOLD, to.c < ACHROMATIC_THRESHOLD) {
+                    (true, false) => (to.h, to.h),
+                    (false, true) => (from.h, from.h),
+                    _ => (from.h, to.h),
+                };
+                // Go around the shorter arc of the hue circle.
+                let mut dh = h2 - h1;
+                if dh > 180.0 { dh -= 360.0 } else if dh < -180.0 { dh += 360.0 }
+                let h = (h1 + dh * t).rem_euclid(360.0);
+                Oklch { l: lerp(from.l, to.l), c: lerp(from.c, to.c), h }.to_color(a)
+            }
+        }
+    }
+
+    /// A tonal scale anchored on `self`, like those of Tailwind or Radix: one
+    /// color per step in `steps`, steps going from 0 (the lightest) to 1000
+    /// (the darkest). The step nearest to where `self`'s lightness falls on
+    /// the scale is `self` exactly, and the others are spread evenly in OKLCH
+    /// lightness towards the ends of the scale.
+    ///
+    /// In `Space::Oklch` the hue is kept and chroma tapers off towards the
+    /// ends, colors being brought into the sRGB gamut. In `Space::Srgb` the
+    /// colors are `self` mixed with the ends of the scale instead, which
+    /// shifts their hue a little. Alpha is kept.
+    pub fn tonal_scale(&self, steps: &[u32], space: Space) -> Vec<Color> {
+        let base = self.to_oklch();
+        let natural = (SCALE_LIGHTEST - base.l) / (SCALE_LIGHTEST - SCALE_DARKEST) * 1000.0;
+        let anchor = match steps.iter().min_by(|&&a, &&b| (a as f64 - natural).abs().total_cmp(&(b as f64 - natural).abs())) {
+            Some(&step) => step as f64,
+            None => return Vec::new(),
+        };
+        let lightest = Oklch { l: SCALE_LIGHTEST, c: 0.0, h: 0.0 };
+        let darkest = Oklch { l: SCALE_DARKEST, c: 0.0, h: 0.0 };
+
+        steps.iter()
+            .map(|&step| {
+                let step = step as f64;
+                // How far along the step is from the anchor to the end of the
+                // scale on its side, from 0 to 1.
+                let (t, end) = if step < anchor {
+                    ((anchor - step) / anchor, lightest)
+                } else if step > anchor {
+                    ((step - anchor) / (1000.0 - anchor), darkest)
+                } else {
+                    return *self
+                };
+                match space {
+                    Space::Oklch => Oklch {
+                        l: base.l + (end.l - base.l) * t,
+                        c: base.c * (1.0 - SCALE_CHROMA_TAPER * t * t),
+                        h: base.h,
+                    }.to_color(self.a),
+                    Space::Srgb => self.mix(&end.to_color(self.a), t, Space::Srgb),
+                }
+            })
+            .collect()
+    }
+
+    /// The value of one channel of the color, within `channel.range()`.
+    pub fn channel(&self, channel: Channel) -> f64 {
+        match channel {
+            Channel::R => self.r as f64,
+            Channel::G => self.g as f64,
+            Channel::B => self.b as f64,
+            Channel::A => self.a as f64,
+            Channel::Hue => self.to_hsl().0,
+            Channel::Saturation => self.to_hsl().1,
+            Channel::Lightness => self.to_hsl().2,
+            Channel::OklchL => self.to_oklch().l,
+            Channel::OklchC => self.to_oklch().c,
+        }
+    }
+
+    /// The color with `channel` set to `value`, the other channels of the
+    /// same model staying put. Values outside `channel.range()` are clamped,
+    /// except for hues, which wrap around. Converting back to sRGB rounds, so
+    /// the channel may read back slightly differently, and out of gamut
+    /// OKLCH colors are brought in by reducing chroma.
+    pub fn with_channel(&self, channel: Channel, value: f64) -> Color {
+        let (min, max) = channel.range();
+        let value = if channel == Channel::Hue { value.rem_euclid(max) } else { value.clamp(min, max) };
+        let byte = value.round() as u8;
+        match channel {
+            Channel::R => Color { r: byte, ..*self },
+            Channel::G => Color { g: byte, ..*self },
+            Channel::B => Color { b: byte, ..*self },
+            Channel::A => Color { a: byte, ..*self },
+            Channel::Hue | Channel::Saturation | Channel::Lightness => {
+                let (h, s, l) = self.to_hsl();
+                match channel {
+                    Channel::Hue => Color::from_hsl(value, s, l, self.a),
+                    Channel::Saturation => Color::from_hsl(h, value, l, self.a),
+                    _ => Color::from_hsl(h, s, value, self.a),
+                }
+            }
+            Channel::OklchL => Oklch { l: value, ..self.to_oklch() }.to_color(self.a),
+            Channel::OklchC => Oklch { c: value, ..self.to_oklch() }.to_color(self.a),
+        }
+    }
+
+    // The color in HSL: hue in degrees, saturation and lightness in [0, 1].
+    // Grays get a hue of 0.
+    fn to_hsl(self) -> (f64, f64, f64) {
+        let (r, g, b) = (self.r as f64 / 255.0, self.g as f64 / 255.0, self.b as f64 / 255.0);
+        let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+        let l = (max + min) / 2.0;
+        let d = max - min;
+        if d == 0.0 {
+            return (0.0, 0.0, l)
+        }
+        let s = d / (1.0 - (2.0 * l - 1.0).abs());
+        let h = if max == r {
+            ((g - b) / d).rem_euclid(6.0)
+        } else if max == g {
+            (b - r) / d + 2.0
+        } else {
+            (r - g) / d + 4.0
+        };
+        (h * 60.0, s, l)
+    }
+
+    fn from_hsl(h: f64, s: f64, l: f64, a: u8) -> Color {
+        let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let h = h.rem_euclid(360.0) / 60.0;
+        let x = c * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = l - c / 2.0;
+        Color { r: round_channel(r + m), g: round_channel(g + m), b: round_channel(b + m), a }
+    }
+}
+
+/// The value held by a dvd-color key: a color and, while one is running, the
+/// transition that leads up to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorValue {
+    /// The color the key holds once any transition has finished.
+    pub color: Color,
+    pub transition: Option<transition::Transition>,
+
+    /// The last writes of the key, oldest first and ending with the one that
+    /// wrote this value, as many as HISTORY-LENGTH asks for.
+    pub history: Vec<Revision>,
+}
+
+/// A write of a color key, as remembered in its history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Revision {
+    /// The color written, or the target of the transition started.
+    pub color: Color,
+    /// When, in Unix milliseconds.
+    pub ms: i64,
+    /// The ID of the client that wrote it.
+    pub client: u64,
+}
+
+impl ColorValue {
+    pub fn new(color: Color) -> ColorValue {
+        ColorValue { color, transition: None, history: Vec::new() }
+    }
+
+    /// `self`, about to replace `old`, with the history of `old` carried over
+    /// and the write by `client` at `now_ms` added to it. Without
+    /// HISTORY-LENGTH there's no history to keep and any left is dropped.
+    pub fn recorded(mut self, old: Option<&ColorValue>, now_ms: i64, client: u64) -> ColorValue {
+        let length = config::get().history_length;
+        self.history = match old {
+            Some(old) if length > 0 => old.history.clone(),
+            _ => Vec::new(),
+        };
+        if length > 0 {
+            self.history.push(Revision { color: self.color, ms: now_ms, client });
+            let excess = self.history.len().saturating_sub(length);
+            self.history.drain(..excess);
+        }
+        self
+    }
+
+    // Whether the value fits in its pointer, see `PACKED`.
+    fn packs(&self) -> bool {
+        cfg!(target_pointer_width = "64") && self.transition.is_none() && self.history.is_empty()
+    }
+
+    /// The color as seen at `now_ms`.
+    pub fn at(&self, now_ms: i64) -> Color {
+        match self.transition {
+            Some(ref t) => t.at(&self.color, now_ms),
+            None => self.color,
+        }
+    }
+}
+
+impl ModuleValue for ColorValue {
+    fn module_type() -> *mut raw::RedisModuleType {
+        unsafe { redis::COLOR_TYPE }
+    }
+
+    fn stats() -> &'static redis::stats::TypeStats {
+        &redis::COLOR_STATS
+    }
+
+    // A packed value allocates nothing: MEMORY USAGE already counts the key
+    // and the pointer Redis keeps for it, which is all there is.
+    fn memory(&self) -> usize {
+        if self.packs() {
+            return 0
+        }
+        let history = match self.history.capacity() {
+            0 => 0,
+            n => n * mem::size_of::<Revision>(),
+        };
+        mem::size_of::<ColorValue>() + history
+    }
+
+    fn into_raw(self) -> *mut c_void {
+        if !self.packs() {
+            return Box::into_raw(Box::new(self)) as *mut c_void
+        }
+        ((self.color.pack() as usize) << 1 | PACKED) as *mut c_void
+    }
+
+    unsafe fn from_raw(raw: *mut c_void) -> ColorValue {
+        match unpacked(raw) {
+            Some(value) => value,
+            None => *Box::from_raw(raw as *mut ColorValue),
+        }
+    }
+
+    unsafe fn get<'a>(raw: *mut c_void) -> Cow<'a, ColorValue> {
+        match unpacked(raw) {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(&*(raw as *const ColorValue)),
+        }
+    }
+
+    unsafe fn get_mut<'a>(raw: *mut c_void) -> Option<&'a mut ColorValue> {
+        match unpacked(raw) {
+            Some(_) => None,
+            None => Some(&mut *(raw as *mut ColorValue)),
+        }
+    }
+}
+
+// Most keys hold a plain color, without a transition or a history. Rather
+// than boxing those, their four bytes go in the value pointer itself, shifted
+// left by one bit with the lowest bit set. Boxes are aligned, so a pointer to
+// one always has its lowest bit clear. A plain color thus costs no allocation
+// of its own, only what Redis spends on any key.
+//
+// That takes 33 bits, so on 32-bit targets every value is boxed.
+const PACKED: usize = 1;
+
+// The value packed in `raw`, or `None` if it's boxed.
+fn unpacked(raw: *mut c_void) -> Option<ColorValue> {
+    let bits = raw as usize;
+    if bits & PACKED == 0 {
+        return None
+    }
+    Some(ColorValue::new(Color::unpack((bits >> 1) as u32)))
+}
+
+/// The color space colors are mixed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Space {
+    Srgb,
+    Oklch,
+}
+
+impl FromStr for Space {
+    type Err = ColorError;
+
+    fn from_str(s: &str) -> Result<Space, ColorError> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(Space::Srgb),
+            "oklch" => Ok(Space::Oklch),
+            _ => Err(ColorError::Syntax(format!("unknown color space {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Space {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Space::Srgb => write!(f, "srgb"),
+            Space::Oklch => write!(f, "oklch"),
+        }
+    }
+}
+
+/// A notation colors are written in, see `Color::format`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `#rrggbb` or `#rrggbbaa`.
+    Hex,
+    /// CSS `rgb(r g b)` or `rgb(r g b / alpha)`.
+    Rgb,
+    /// CSS `oklch(l c h)` or `oklch(l c h / alpha)`.
+    Oklch,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Hex => write!(f, "hex"),
+            Format::Rgb => write!(f, "rgb"),
+            Format::Oklch => write!(f, "oklch"),
+        }
+    }
+}
+
+/// A channel of a color that can be read and set on its own: the sRGB
+/// channels and alpha, the hue, saturation and lightness of HSL, and the
+/// lightness and chroma of OKLCH. Named `r`, `g`, `b`, `a`, `h`, `s`, `l`,
+/// `L` and `C`, the case telling HSL's `l` from OKLCH's `L`. All but the
+/// lightnesses can also go by their full name, such as `hue`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    R,
+    G,
+    B,
+    A,
+    Hue,
+    Saturation,
+    Lightness,
+    OklchL,
+    OklchC,
+}
+
+impl Channel {
+    /// The values the channel goes through. Hues wrap around at the max.
+    pub fn range(self) -> (f64, f64) {
+        match self {
+            Channel::R | Channel::G | Channel::B | Channel::A => (0.0, 255.0),
+            Channel::Hue => (0.0, 360.0),
+            Channel::Saturation | Channel::Lightness | Channel::OklchL => (0.0, 1.0),
+            Channel::OklchC => (0.0, MAX_CHROMA),
+        }
+    }
+
+    /// Whether the channel only takes whole numbers.
+    pub fn is_integer(self) -> bool {
+        matches!(self, Channel::R | Channel::G | Channel::B | Channel::A)
+    }
+}
+
+impl FromStr for Channel {
+    type Err = ColorError;
+
+    fn from_str(s: &str) -> Result<Channel, ColorError> {
+        match s {
+            "r" | "red" => Ok(Channel::R),
+            "g" | "green" => Ok(Channel::G),
+            "b" | "blue" => Ok(Channel::B),
+            "a" | "alpha" => Ok(Channel::A),
+            "h" | "hue" => Ok(Channel::Hue),
+            "s" | "saturation" => Ok(Channel::Saturation),
+            "l" => Ok(Channel::Lightness),
+            "L" => Ok(Channel::OklchL),
+            "C" | "chroma" => Ok(Channel::OklchC),
+            _ => Err(ColorError::Syntax(format!("unknown channel {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Channel::R => "r",
+            Channel::G => "g",
+            Channel::B => "b",
+            Channel::A => "a",
+            Channel::Hue => "h",
+            Channel::Saturation => "s",
+            Channel::Lightness => "l",
+            Channel::OklchL => "L",
+            Channel::OklchC => "C",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Oklch {
+    /// Whether the color can be shown in sRGB as it is.
+    pub fn in_gamut(&self) -> bool {
+        in_gamut(oklch_to_linear_srgb(self.l, self.c, self.h))
+    }
+
+    /// Converts back to sRGB. Colors outside the sRGB gamut are brought in by
+    /// reducing chroma while keeping lightness and hue, which preserves the
+    /// perceived color far better than clipping each channel.
+    pub fn to_color(&self, alpha: u8) -> Color {
+        let l = self.l.clamp(0.0, 1.0);
+        let mut rgb = oklch_to_linear_srgb(l, self.c, self.h);
+        if !in_gamut(rgb) {
+            let (mut lo, mut hi) = (0.0, self.c);
+            for _ in 0..24 {
+                let mid = (lo + hi) / 2.0;
+                if in_gamut(oklch_to_linear_srgb(l, mid, self.h)) { lo = mid } else { hi = mid }
+            }
+            rgb = oklch_to_linear_srgb(l, lo, self.h);
+        }
+        Color {
+            r: round_channel(from_linear(rgb.0)),
+            g: round_channel(from_linear(rgb.1)),
+            b: round_channel(from_linear(rgb.2)),
+            a: alpha,
+        }
+    }
+}
+
+fn oklch_to_linear_srgb(l: f64, c: f64, h: f64) -> (f64, f64, f64) {
+    let (a, b) = (c * h.to_radians().cos(), c * h.to_radians().sin());
+
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+
+    (
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+    )
+}
+
+fn in_gamut(rgb: (f64, f64, f64)) -> bool {
+    let ok = |c: f64| (-1e-4..=1.0 + 1e-4).contains(&c);
+    ok(rgb.0) && ok(rgb.1) && ok(rgb.2)
+}
+
+// OKLCH lightness at the ends of tonal scales (see `Color::tonal_scale`), step
+// 0 and step 1000, and the share of chroma lost by the time they're reached.
+const SCALE_LIGHTEST: f64 = 1.0;
+const SCALE_DARKEST: f64 = 0.22;
+const SCALE_CHROMA_TAPER: f64 = 0.6;
+
+/// Chroma of the most saturated colors inside sRGB.
+pub const MAX_CHROMA: f64 = 0.37;
+
+// Below this chroma a color is considered a gray and its hue is meaningless.
+const ACHROMATIC_THRESHOLD: f64 = 1e-4;
+
+// Converts a gamma encoded sRGB channel to linear light in [0, 1].
+fn to_linear(channel: u8) -> f64 {
+    let c = channel as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Converts linear light back to a gamma encoded sRGB channel in [0, 1].
+fn from_linear(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn round_channel(c: f64) -> u8 {
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+// Writes `v` with at most `decimals` decimals and no trailing zeros.
+fn trim_number(v: f64, decimals: usize) -> String {
+    let s = format!("{:.*}", decimals, v);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0".to_string() } else { s.to_string() }
+}
+
+impl FromStr for Color {
+    type Err = ColorError;
+
+    /// Parses hex notation: `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`. The
+    /// leading `#` is optional and alpha defaults to DEFAULT-ALPHA when
+    /// omitted. With STRICT-PARSING only `#rrggbb` and `#rrggbbaa` are taken.
+    fn from_str(s: &str) -> Result<Color, ColorError> {
+        let config = config::get();
+        let hex = match s.strip_prefix('#') {
+            Some(hex) => hex,
+            None if config.strict_parsing => return Err(ColorError::parse(0, s, "#rrggbb or #rrggbbaa")),
+            None => s,
+        };
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ColorError::parse(0, s, "a hex color"));
+        }
+        let digits: Vec<u8> = match hex.len() {
+            3 | 4 if config.strict_parsing => return Err(ColorError::parse(0, s, "#rrggbb or #rrggbbaa")),
+            3 | 4 => hex.chars().map(|c| {
+                let d = c.to_digit(16).unwrap() as u8;
+                d << 4 | d
+            }).collect(),
+            6 | 8 => (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?,
+            _ => return Err(ColorError::parse(0, s, "a hex color")),
+        };
+        let a = if digits.len() == 4 { digits[3] } else { config.default_alpha };
+        Ok(Color { r: digits[0], g: digits[1], b: digits[2], a })
     }
 }
 
 impl fmt::Display for Color {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "#{:x}{:x}{:x}", self.r, self.g, self.b)
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 0xff {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
     }
 }
 
+// Keeps the search index, change events and waiting clients up to date. Every
+// command that writes a color calls this after the write went through, and
+// after replicating it for commands that replicate explicitly. None of it can
+// fail the command: the write already happened.
+fn color_changed(
+    r: &redis::Redis,
+    command: &str,
+    key: &[u8],
+    old: Option<&Color>,
+    new: &Color,
+) {
+    search::update(r, key, new);
+    wait::signal(key);
+    events::publish(r, command, key, old, new);
+}
+
 // Implement a redis command to set and get color data.
 // Colors can be SET using RGBA hex notation, e.g. cl.COLOR SET pink #ff55efff where the last two bytes are the alpha (will be set to ff if omitted).
 // Read colors back with cl.COLOR GET pink
-// Colors are stored as bitfields for efficiency.
-// -----
-// Use WrongArity
-//     if argc < 4 {
-//        return ffi::RedisModule_WrongArity.unwrap()(ctx);
-//    }
+// Plain colors are packed into the pointer Redis keeps for the key, see `PACKED`.
+// REVISION ms client records the write in the history as made at `ms` by
+// `client`, which is how writes replicate (see `canonical_command`). It's only
+// accepted from the master and the AOF, so clients can't forge the history.
 struct SetColorCommand {}
 impl Command for SetColorCommand {
     fn name(&self) -> &'static str { "color.set" }
-    fn run(&self, r: redis::Redis, args: &[&str]) -> Result<(), ColorError> {
-        if args.len() != 3 {
-            return Err(error!("Usage: {} COLOR.SET pink #fe55fe", self.name() ));
-        }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn replication(&self) -> redis::Replication { redis::Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let c: Color = args[2].parse("a hex color")?;
+        let (ms, client) = match args.len() {
+            3 => (redis::milliseconds(), r.client_id()),
+            6 if args[3].eq_ignore_case("revision") => history::parse_revision(&r, args, 3)?,
+            6 => return Err(ColorError::Syntax(format!("unknown option {}", args[3].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
         let key = r.open_key_writable(args[1]);
-        if key.is_empty() {
-            log_debug!(r, "Key {:?} is empty. Writing {:?}.", key, args[2]);
-            // TODO: parse color and write bytes back
-            let c = Color::new();
-            let out = key.write(&c)?;
-            log_debug!(r, "Wrote value {:?} to key {:?}. Result: {:?}", c, key, out);
-        } else {
-            // r.log_debug("Key is NOT empty. TODO: check type, bail if not our type, overwrite otherwise");
-            // let kt = key.key_type();
-            // log_debug!(r, "KEY TYPE: {:?}", kt);
-            if !key.valid_key_type() {
-                return Err(error!(raw::ERRORMSG_WRONGTYPE)) // TODO: better error msg
-            }
+        if !key.is_empty() && !key.valid_key_type() {
+            return Err(ColorError::WrongType)
         }
+        let old = key.read()?.map(|v| v.at(redis::milliseconds()));
+        let value = key.write_as(ColorValue::new(c), ms, client)?;
+        log_debug!(r, "Wrote value {:?} to key {:?}", c, key);
+        replicate_color_value(&r, args[1].as_bytes(), &value)?;
+        color_changed(&r, self.name(), args[1].as_bytes(), old.as_ref(), &c);
         r.reply_simple_string(raw::SIMPLE_OK)?;
         Ok(())
     }
-    fn str_flags(&self) -> &'static str { "write" }
 }
 struct GetColorCommand{}
 impl Command for GetColorCommand {
     fn name(&self) -> &'static str { "color.get" }
-    fn run(&self, r: redis::Redis, args: &[&str]) -> Result<(), ColorError> {
-        // TODO: check arity
+    fn arity(&self) -> i32 { 2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::FAST }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
         let key = r.open_key(args[1]);
+        if key.is_empty() {
+            return r.reply_null()
+        }
         if !key.valid_key_type() {
-            return Err(error!(raw::ERRORMSG_WRONGTYPE))
-        }
-        let color_res = key.read();
-        match color_res {
-            Ok(cr) => {
-                log_debug!(r, "Read color from key {:?}: {:?}", key, cr);
-                let c: &mut Color = unsafe {&mut *(cr as *mut Color)};
-                log_debug!(r, "Cast color: {:?}", c);
-                r.reply_simple_string(&format!("{}", c))?;
-                Ok(())
-            },
-            Err(e) => {
-                Err(error!("No."))
+            return Err(ColorError::WrongType)
+        }
+        let cr = key.read()?;
+        log_debug!(r, "Read color from key {:?}: {:?}", key, cr);
+        let c = cr.at(redis::milliseconds());
+        r.reply_simple_string(&c.format(config::get().default_format))?;
+        Ok(())
+    }
+}
+
+// Type callbacks for dvd-color. A color is saved as a single unsigned integer
+// holding the RGBA bytes, followed by a flag telling whether a transition is
+// running and, if so, its parameters, then by the number of revisions in the
+// history and each revision's color, time and client.
+//
+// The callbacks themselves only contain panics (see `redis::catch_panic`) and
+// defer the actual work to the functions below them.
+unsafe extern "C" fn color_rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
+    // Redis takes a null value as a failed load and reports it.
+    redis::catch_panic("dvd-color rdb_load", redis::PanicLog::Io(rdb), || {
+        let value = load_color_value(rdb, encver);
+        redis::COLOR_STATS.created(value.memory());
+        value.into_raw()
+    }).unwrap_or(ptr::null_mut())
+}
+
+unsafe extern "C" fn color_rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+    redis::catch_panic("dvd-color rdb_save", redis::PanicLog::Io(rdb), || {
+        save_color_value(rdb, &ColorValue::get(value))
+    });
+}
+
+unsafe extern "C" fn color_aof_rewrite(
+    aof: *mut raw::RedisModuleIO,
+    key: *mut raw::RedisModuleString,
+    value: *mut c_void,
+) {
+    redis::catch_panic("dvd-color aof_rewrite", redis::PanicLog::Io(aof), || {
+        rewrite_color_value(aof, key, &ColorValue::get(value))
+    });
+}
+
+unsafe extern "C" fn color_mem_usage(value: *const c_void) -> usize {
+    redis::catch_panic("dvd-color mem_usage", redis::PanicLog::Stderr, || {
+        ColorValue::get(value as *mut c_void).memory()
+    }).unwrap_or(0)
+}
+
+unsafe extern "C" fn color_free(value: *mut c_void) {
+    redis::catch_panic("dvd-color free", redis::PanicLog::Stderr, || {
+        let value = ColorValue::from_raw(value);
+        redis::COLOR_STATS.freed(value.memory());
+    });
+}
+
+fn load_color_value(rdb: *mut raw::RedisModuleIO, encver: c_int) -> ColorValue {
+    let mut value = ColorValue::new(Color::unpack(raw::load_unsigned(rdb) as u32));
+    if encver >= 1 && raw::load_unsigned(rdb) == 1 {
+        let from = Color::unpack(raw::load_unsigned(rdb) as u32);
+        let start_ms = raw::load_signed(rdb);
+        let duration = time::Duration::milliseconds(raw::load_signed(rdb));
+        let space = if raw::load_unsigned(rdb) == 1 { Space::Oklch } else { Space::Srgb };
+        let easing = match raw::load_unsigned(rdb) {
+            1 => transition::Easing::CubicBezier(
+                raw::load_double(rdb), raw::load_double(rdb),
+                raw::load_double(rdb), raw::load_double(rdb),
+            ),
+            2 => transition::Easing::Steps(raw::load_unsigned(rdb) as u32),
+            _ => transition::Easing::Linear,
+        };
+        value.transition = Some(transition::Transition { from, start_ms, duration, easing, space });
+    }
+    if encver >= 2 {
+        value.history = (0..raw::load_unsigned(rdb))
+            .map(|_| Revision {
+                color: Color::unpack(raw::load_unsigned(rdb) as u32),
+                ms: raw::load_signed(rdb),
+                client: raw::load_unsigned(rdb),
+            })
+            .collect();
+    }
+    value
+}
+
+fn save_color_value(rdb: *mut raw::RedisModuleIO, v: &ColorValue) {
+    raw::save_unsigned(rdb, v.color.pack() as u64);
+    match v.transition {
+        Some(ref t) if !t.is_finished(redis::milliseconds()) => {
+            raw::save_unsigned(rdb, 1);
+            raw::save_unsigned(rdb, t.from.pack() as u64);
+            raw::save_signed(rdb, t.start_ms);
+            raw::save_signed(rdb, t.duration.whole_milliseconds() as i64);
+            raw::save_unsigned(rdb, if t.space == Space::Oklch { 1 } else { 0 });
+            match t.easing {
+                transition::Easing::Linear => raw::save_unsigned(rdb, 0),
+                transition::Easing::CubicBezier(x1, y1, x2, y2) => {
+                    raw::save_unsigned(rdb, 1);
+                    for p in &[x1, y1, x2, y2] {
+                        raw::save_double(rdb, *p);
+                    }
+                }
+                transition::Easing::Steps(n) => {
+                    raw::save_unsigned(rdb, 2);
+                    raw::save_unsigned(rdb, n as u64);
+                }
             }
         }
+        _ => raw::save_unsigned(rdb, 0),
+    }
+    raw::save_unsigned(rdb, v.history.len() as u64);
+    for revision in &v.history {
+        raw::save_unsigned(rdb, revision.color.pack() as u64);
+        raw::save_signed(rdb, revision.ms);
+        raw::save_unsigned(rdb, revision.client);
     }
-    fn str_flags(&self) -> &'static str { "readonly" }
 }
-#[allow(non_snake_case)]
-#[allow(unused_variables)]
-#[no_mangle]
-pub extern "C" fn SetColor_RedisCommand(
-    ctx: *mut raw::RedisModuleCtx,
-    argv: *mut *mut raw::RedisModuleString,
-    argc: c_int,
-) -> raw::Status {
-    Command::harness(&SetColorCommand{}, ctx, argv, argc)
+
+// Replays the history before the value itself, so that it's rebuilt along
+// with the value.
+fn rewrite_color_value(aof: *mut raw::RedisModuleIO, key: *mut raw::RedisModuleString, v: &ColorValue) {
+    let (last, earlier) = match v.history.split_last() {
+        Some((last, earlier)) => (Some(last), earlier),
+        None => (None, &[][..]),
+    };
+    let set = config::get().command_name("color.set");
+    for revision in earlier {
+        let mut args = vec![revision.color.to_full_hex()];
+        args.extend(revision_args(revision));
+        redis::emit_aof(aof, &set, key, &args);
+    }
+    let (command, args) = canonical_command(v, last);
+    redis::emit_aof(aof, &command, key, &args);
+}
+
+// The command that recreates `v` from scratch, as the command name and the
+// arguments that follow the key. It ends AOF rewrites and replaces commands
+// whose effect depends on when they ran, so colors are written in full and a
+// running transition gets its start pinned with AT and FROM. `revision`, the
+// write that stored `v` if the history is kept, is carried with REVISION so
+// that replicas and reloads record the same history.
+fn canonical_command(v: &ColorValue, revision: Option<&Revision>) -> (String, Vec<String>) {
+    let config = config::get();
+    let (command, mut args) = match v.transition {
+        Some(ref t) if !t.is_finished(redis::milliseconds()) => (config.command_name("color.transition"), vec![
+            v.color.to_full_hex(),
+            "DURATION".to_string(), t.duration.whole_milliseconds().to_string(),
+            "EASING".to_string(), t.easing.to_string(),
+            "SPACE".to_string(), t.space.to_string(),
+            "AT".to_string(), t.start_ms.to_string(),
+            "FROM".to_string(), t.from.to_full_hex(),
+        ]),
+        _ => (config.command_name("color.set"), vec![v.color.to_full_hex()]),
+    };
+    if let Some(revision) = revision {
+        args.extend(revision_args(revision));
+    }
+    (command, args)
+}
+
+fn revision_args(revision: &Revision) -> Vec<String> {
+    vec!["REVISION".to_string(), revision.ms.to_string(), revision.client.to_string()]
+}
+
+/// Replicates `v`, just written to `key`, as its canonical command. For
+/// commands using `Replication::Explicit`.
+pub fn replicate_color_value(r: &redis::Redis, key: &[u8], v: &ColorValue) -> Result<(), ColorError> {
+    let (command, args) = canonical_command(v, v.history.last());
+    let mut argv: Vec<&[u8]> = vec![key];
+    argv.extend(args.iter().map(|a| a.as_bytes()));
+    r.replicate(&command, &argv)
+}
+
+/// The color at `key` as of `now_ms`. Fails if there's no such key.
+pub fn read_color(r: &redis::Redis, key: redis::RedisStr, now_ms: i64) -> Result<Color, ColorError> {
+    let k = r.open_key(key);
+    if k.is_empty() {
+        return Err(ColorError::NotFound(String::from_utf8_lossy(key.as_bytes()).into_owned()))
+    }
+    if !k.valid_key_type() {
+        return Err(ColorError::WrongType)
+    }
+    Ok(k.read()?.at(now_ms))
+}
+
+commands! {
+    SetColor_RedisCommand => SetColorCommand{},
+    GetColor_RedisCommand => GetColorCommand{},
+    SearchColor_RedisCommand => search::SearchColorCommand{},
+    TransitionColor_RedisCommand => transition::TransitionColorCommand{},
+    WaitColor_RedisCommand => wait::WaitColorCommand{},
+    ConfigColor_RedisCommand => config::ConfigCommand{},
+    InfoColor_RedisCommand => info::InfoCommand{},
+    RandomColor_RedisCommand => random::RandomColorCommand{},
+    HSetColor_RedisCommand => hash::HSetColorCommand{},
+    HGetColor_RedisCommand => hash::HGetColorCommand{},
+    HistoryColor_RedisCommand => history::HistoryColorCommand{},
+    RollbackColor_RedisCommand => history::RollbackColorCommand{},
+    CasColor_RedisCommand => cas::CasColorCommand{},
+    GetChannelColor_RedisCommand => channel::GetChannelCommand{},
+    SetChannelColor_RedisCommand => channel::SetChannelCommand{},
+    IncrByColor_RedisCommand => channel::IncrByCommand{},
+    ScanColor_RedisCommand => scan::ScanColorCommand{},
+    SetTheme_RedisCommand => theme::SetCommand{},
+    AliasTheme_RedisCommand => theme::AliasCommand{},
+    PairTheme_RedisCommand => theme::PairCommand{},
+    InheritTheme_RedisCommand => theme::InheritCommand{},
+    ResolveTheme_RedisCommand => theme::ResolveCommand{},
+    FlattenTheme_RedisCommand => theme::FlattenCommand{},
+    DiffTheme_RedisCommand => theme::DiffCommand{},
+    DeriveDarkTheme_RedisCommand => theme::DeriveDarkCommand{},
+    ScaleColor_RedisCommand => scale::ScaleColorCommand{},
 }
 
 #[allow(non_snake_case)]
 #[allow(unused_variables)]
 #[no_mangle]
-pub extern "C" fn GetColor_RedisCommand(
+pub extern "C" fn RedisModule_OnLoad(
     ctx: *mut raw::RedisModuleCtx,
     argv: *mut *mut raw::RedisModuleString,
-    argc: c_int
+    argc: c_int,
 ) -> raw::Status {
-    Command::harness(&GetColorCommand{}, ctx, argv, argc)
+    redis::catch_panic("module loading", redis::PanicLog::Ctx(ctx), || on_load(ctx, argv, argc))
+        .unwrap_or(raw::Status::Err)
 }
 
-#[allow(non_snake_case)]
-#[allow(unused_variables)]
-#[no_mangle]
-pub extern "C" fn RedisModule_OnLoad(
+fn on_load(
     ctx: *mut raw::RedisModuleCtx,
     argv: *mut *mut raw::RedisModuleString,
     argc: c_int,
@@ -128,37 +975,233 @@ pub extern "C" fn RedisModule_OnLoad(
         return raw::Status::Err;
     }
 
+    let args = redis::parse_args(argv, argc);
+    let config = match config::Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            let message = format!("Invalid module arguments: {}\0", e);
+            raw::log(ctx, "warning\0".as_ptr(), message.as_ptr());
+            return raw::Status::Err
+        }
+    };
+    config::set(config);
+    redis::stats::reset();
+    wait::reset();
+
     let mut type_functions = raw::RedisModuleTypeMethods {
         version: 1,
-        rdb_load: None,
-        rdb_save: None,
-        aof_rewrite: None,
-        free: None,
-        mem_usage: None,
+        rdb_load: Some(color_rdb_load),
+        rdb_save: Some(color_rdb_save),
+        aof_rewrite: Some(color_aof_rewrite),
+        free: Some(color_free),
+        mem_usage: Some(color_mem_usage),
         digest: None,
     };
 
     let type_name = format!("{}\0", "dvd-color");
-    if raw::create_type(ctx, type_name.as_ptr(), 0, &mut type_functions) == raw::Status::Err {
+    let color_type = raw::create_type(ctx, type_name.as_ptr(), COLOR_ENCODING_VERSION, &mut type_functions);
+    if color_type.is_null() {
         return raw::Status::Err
     }
+    unsafe { redis::COLOR_TYPE = color_type; }
 
-    let set_cmd = SetColorCommand{};
-    if raw::create_command(
-        ctx, 
-        format!("{}\0", set_cmd.name()).as_ptr(), 
-        Some(SetColor_RedisCommand), 
-        format!("{}\0", set_cmd.str_flags() ).as_ptr(), 0, 0, 0) == raw::Status::Err {
-        return raw::Status::Err;
+    let mut theme_functions = raw::RedisModuleTypeMethods {
+        version: 1,
+        rdb_load: Some(theme::rdb_load),
+        rdb_save: Some(theme::rdb_save),
+        aof_rewrite: Some(theme::aof_rewrite),
+        free: Some(theme::free),
+        mem_usage: Some(theme::mem_usage),
+        digest: None,
+    };
+    let theme_type = raw::create_type(ctx, "dvd-theme\0".as_ptr(), theme::ENCODING_VERSION, &mut theme_functions);
+    if theme_type.is_null() {
+        return raw::Status::Err
     }
+    unsafe { redis::THEME_TYPE = theme_type; }
 
-    let get_cmd = GetColorCommand{};
-    if raw::create_command(
-        ctx, 
-        format!("{}\0", get_cmd.name()).as_ptr(), 
-        Some(GetColor_RedisCommand), 
-        format!("{}\0", get_cmd.str_flags() ).as_ptr(), 0, 0, 0) == raw::Status::Err {
+    for (command, handler) in commands() {
+        let name = config.command_name(command.name());
+        if <dyn Command>::register(command.as_ref(), &name, ctx, handler) == raw::Status::Err {
             return raw::Status::Err;
         }
+    }
     raw::Status::Ok
 }
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server, START_MS};
+
+    #[test]
+    fn set_then_get() {
+        let mut server = Server::new();
+        assert_eq!(server.run(&["COLOR.SET", "pink", "#ff55ef"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff55ef"));
+        assert_eq!(server.run(&["COLOR.SET", "pink", "#f0f8"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff00ff88"));
+    }
+
+    #[test]
+    fn commands_are_registered() {
+        let server = Server::new();
+        assert_eq!(server.command_flags("color.set"), Some("write deny-oom"));
+        assert_eq!(server.command_flags("color.get"), Some("readonly fast"));
+    }
+
+    #[test]
+    fn get_missing_key_is_nil() {
+        let mut server = Server::new();
+        assert_eq!(server.run(&["COLOR.GET", "nope"]), Reply::Null);
+    }
+
+    #[test]
+    fn set_rejects_bad_input() {
+        let mut server = Server::new();
+        assert!(server.run(&["COLOR.SET", "pink", "#ff55eg"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SET", "pink"]).is_error("ERR wrong number of arguments"));
+        assert!(!server.exists("pink"));
+    }
+
+    #[test]
+    fn rdb_round_trip() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef80")]);
+        let rdb = server.dump("pink");
+        server.restore("copy", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.GET", "copy"]), Reply::simple("#ff55ef80"));
+    }
+
+    #[test]
+    fn rdb_round_trip_keeps_transition() {
+        let mut server = Server::with_colors(&[("fade", "#000000")]);
+        server.run(&["COLOR.TRANSITION", "fade", "#ffffff", "DURATION", "1000", "EASING", "steps(4)"]);
+        let rdb = server.dump("fade");
+        server.restore("copy", &rdb).unwrap();
+        server.set_time(START_MS + 500);
+        assert_eq!(server.run(&["COLOR.GET", "copy"]), server.run(&["COLOR.GET", "fade"]));
+        assert_eq!(server.run(&["COLOR.GET", "copy"]), Reply::simple("#808080"));
+    }
+
+    #[test]
+    fn rdb_loads_version_0() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        let mut rdb = server.dump("pink");
+        rdb.encver = 0;
+        rdb.values.truncate(1);
+        server.restore("old", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.GET", "old"]), Reply::simple("#ff55ef"));
+    }
+
+    #[test]
+    fn rdb_loads_version_1() {
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "3"]).unwrap();
+        server.set_colors(&[("pink", "#ff55ef")]);
+        let mut rdb = server.dump("pink");
+        rdb.encver = 1;
+        rdb.values.truncate(2);
+        server.restore("old", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.GET", "old"]), Reply::simple("#ff55ef"));
+        assert_eq!(server.run(&["COLOR.HISTORY", "old"]), Reply::Array(vec![]));
+    }
+
+    #[test]
+    fn rdb_rejects_truncated_value() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        let mut rdb = server.dump("pink");
+        rdb.values.clear();
+        assert!(server.restore("bad", &rdb).is_err());
+    }
+
+    #[test]
+    fn mem_usage_counts_allocator_header() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        server.run(&["COLOR.TRANSITION", "pink", "#000000", "DURATION", "1000"]);
+        assert_eq!(server.mem_usage("pink"), ::std::mem::size_of::<::ColorValue>());
+    }
+
+    #[test]
+    fn plain_colors_are_packed() {
+        use redis::ModuleValue;
+        use ColorValue;
+
+        for hex in &["#00000000", "#ffffffff", "#ff55ef80", "#80000001"] {
+            let value = ColorValue::new(hex.parse().unwrap());
+            let raw = value.clone().into_raw();
+            assert_eq!(raw as usize & 1, 1, "{}", hex);
+            assert_eq!(unsafe { ColorValue::get(raw) }.into_owned(), value);
+            assert!(unsafe { ColorValue::get_mut(raw) }.is_none());
+            assert_eq!(unsafe { ColorValue::from_raw(raw) }, value);
+        }
+
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.mem_usage("pink"), 0);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff55ef"));
+        server.run(&["COLOR.TRANSITION", "pink", "#000000", "DURATION", "1000"]);
+        assert!(server.mem_usage("pink") > 0);
+        server.run(&["COLOR.SET", "pink", "#000000"]);
+        assert_eq!(server.mem_usage("pink"), 0);
+        let rdb = server.dump("pink");
+        server.restore("copy", &rdb).unwrap();
+        assert_eq!(server.run(&["COLOR.GET", "copy"]), Reply::simple("#000000"));
+        assert!(server.del("pink") && server.del("copy"));
+        assert_eq!(server.run(&["COLOR.INFO"]).info_field("type_dvd-color"),
+            Some("keys=0,memory=0,encver=2".to_string()));
+        drop(server);
+
+        // Keeping a history needs a box.
+        let mut server = Server::with_args(&["HISTORY-LENGTH", "2"]).unwrap();
+        server.set_colors(&[("pink", "#ff55ef")]);
+        assert!(server.mem_usage("pink") > 0);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff55ef"));
+    }
+
+    // Holds a million plain colors at once, packed and then boxed as they used
+    // to be, and measures what Redis allocated for them.
+    #[test]
+    fn million_colors_packed_and_boxed() {
+        use redis::ModuleValue;
+        use std::mem::size_of;
+        use ColorValue;
+
+        const KEYS: usize = 1_000_000;
+        let values = || (0..KEYS as u32).map(|i| ColorValue::new(super::Color::unpack(i.wrapping_mul(2_654_435_761))));
+        let server = Server::new();
+        let mut held = Vec::with_capacity(KEYS);
+
+        let before = server.used_memory();
+        held.extend(values().map(|v| v.into_raw()));
+        assert_eq!(server.used_memory() - before, 0);
+        assert_eq!(held.iter().map(|&p| unsafe { ColorValue::get(p) }.memory()).sum::<usize>(), 0);
+        for p in held.drain(..) {
+            drop(unsafe { ColorValue::from_raw(p) });
+        }
+
+        let before = server.used_memory();
+        held.extend(values().map(|v| Box::into_raw(Box::new(v)) as *mut ::libc::c_void));
+        let boxed = (server.used_memory() - before) as usize;
+        // The boxes themselves, and at most malloc's rounding on top.
+        assert!(boxed >= KEYS * size_of::<ColorValue>() && boxed <= KEYS * (size_of::<ColorValue>() + 16),
+            "{} bytes", boxed);
+        for p in held {
+            drop(unsafe { Box::from_raw(p as *mut ColorValue) });
+        }
+    }
+
+    #[test]
+    fn aof_rewrite_replays() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.rewrite_aof("pink"), vec![vec!["color.set", "pink", "#ff55efff"]]);
+
+        server.run(&["COLOR.TRANSITION", "pink", "#000000", "DURATION", "1000"]);
+        let aof = server.rewrite_aof("pink");
+        assert_eq!(aof.len(), 1);
+        server.set_time(START_MS + 250);
+        let expected = server.run(&["COLOR.GET", "pink"]);
+        drop(server);
+
+        let mut server = Server::new();
+        server.replay(Server::run_loading, &aof);
+        server.set_time(START_MS + 250);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), expected);
+    }
+}
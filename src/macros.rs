@@ -19,3 +19,26 @@ macro_rules! log_debug {
         }
     }
 }
+
+// Declares the commands of the module. For each `handler => command` pair this
+// generates the `extern "C"` function Redis calls, which runs the command
+// through `Command::harness`, and adds the pair to the table returned by
+// `commands()` that `RedisModule_OnLoad` registers from.
+macro_rules! commands {
+    ($($handler:ident => $command:expr),* $(,)*) => {
+        $(
+            #[allow(non_snake_case)]
+            extern "C" fn $handler(
+                ctx: *mut $crate::redis::raw::RedisModuleCtx,
+                argv: *mut *mut $crate::redis::raw::RedisModuleString,
+                argc: ::libc::c_int,
+            ) -> $crate::redis::raw::Status {
+                <dyn $crate::redis::Command>::harness(&$command, ctx, argv, argc)
+            }
+        )*
+
+        fn commands() -> Vec<(Box<dyn $crate::redis::Command>, $crate::redis::raw::RedisModuleCmdFunc)> {
+            vec![$((Box::new($command), $handler as $crate::redis::raw::RedisModuleCmdFunc)),*]
+        }
+    };
+}
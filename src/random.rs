@@ -0,0 +1,258 @@
+// Random colors, for things like avatars and tags that need a color of their
+// own rather than any particular one.
+//
+// Candidates are drawn uniformly within the requested OKLCH ranges and kept
+// only if they are inside the sRGB gamut and meet every constraint. Bringing
+// a candidate into the gamut would lower its chroma, possibly below the
+// requested range, so those are dropped instead.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use config;
+use error::ColorError;
+use hash::read_palette;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use search::{parse_range, Range};
+use {color_changed, read_color, replicate_color_value, Color, ColorValue, Oklch, MAX_CHROMA};
+
+// Candidates tried before giving up on constraints that can't be met, or
+// hardly can.
+const MAX_ATTEMPTS: usize = 1000;
+
+/// SplitMix64: small, fast and good enough to pick colors, but not for
+/// anything that needs to be unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in [min, max).
+    pub fn between(&mut self, min: f64, max: f64) -> f64 {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        min + (max - min) * unit
+    }
+}
+
+// Calls without SEED each take the next seed after the clock, so that calls
+// within the same millisecond differ.
+static CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn fresh_seed() -> u64 {
+    let calls = CALLS.fetch_add(1, Ordering::Relaxed);
+    (redis::milliseconds() as u64).rotate_left(20) ^ calls
+}
+
+// Picks a hue in `range`, which wraps around 360 when its min is greater than
+// its max.
+fn random_hue(rng: &mut Rng, range: Range) -> f64 {
+    if range.min <= range.max {
+        return rng.between(range.min, range.max)
+    }
+    let h = rng.between(range.min, range.max + 360.0);
+    if h >= 360.0 { h - 360.0 } else { h }
+}
+
+// Implements COLOR.RANDOM dest [SEED n] [HUE min max] [LIGHTNESS min max] [CHROMA min max]
+//     [MINCONTRAST against-key ratio] [DISTINCTFROM palette-key deltaE]
+//
+// Stores a random color at `dest` and replies with it. HUE, LIGHTNESS and
+// CHROMA bound its OKLCH components, like for COLOR.SEARCH. MINCONTRAST asks
+// for a WCAG contrast ratio of at least `ratio` with the color at
+// `against-key`, and DISTINCTFROM for a difference (see `Color::delta_e`) of
+// at least `deltaE` with every color in `palette-key`, a hash whose values are
+// colors. Both can be given several times. The same SEED and constraints
+// always give the same color.
+//
+// Fails if no color meeting the constraints turns up after MAX_ATTEMPTS
+// tries. Replicated as the COLOR.SET of the color picked.
+//
+// The keys of MINCONTRAST and DISTINCTFROM are at no fixed position, so the
+// command tells where its keys are itself (getkeys-api) rather than with a
+// key spec, which lets cluster mode check they share a slot with `dest`.
+pub struct RandomColorCommand {}
+impl Command for RandomColorCommand {
+    fn name(&self) -> &'static str { "color.random" }
+    fn arity(&self) -> i32 { -2 }
+    fn flags(&self) -> CommandFlags {
+        CommandFlags::WRITE | CommandFlags::DENY_OOM | CommandFlags::RANDOM | CommandFlags::GETKEYS_API
+    }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn keys(&self, args: &[redis::RedisStr]) -> Vec<usize> {
+        let mut keys = vec![1];
+        let mut i = 2;
+        while i < args.len() {
+            let option = match args[i].to_str() {
+                Ok(option) => option.to_uppercase(),
+                Err(_) => break,
+            };
+            i += match option.as_str() {
+                "SEED" => 2,
+                "HUE" | "LIGHTNESS" | "CHROMA" => 3,
+                "MINCONTRAST" | "DISTINCTFROM" if i + 1 < args.len() => {
+                    keys.push(i + 1);
+                    3
+                }
+                _ => break,
+            };
+        }
+        keys
+    }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let mut seed: Option<u64> = None;
+        let mut hue = Range { min: 0.0, max: 360.0 };
+        let mut lightness = Range { min: 0.0, max: 1.0 };
+        let mut chroma = Range { min: 0.0, max: MAX_CHROMA };
+        let mut contrasts: Vec<(Color, f64)> = Vec::new();
+        let mut palettes: Vec<(Vec<Color>, f64)> = Vec::new();
+        let now = redis::milliseconds();
+
+        let mut i = 2;
+        while i < args.len() {
+            match args[i].to_str()?.to_uppercase().as_str() {
+                "HUE" => { hue = parse_range(args, i)?; i += 3; }
+                "LIGHTNESS" => { lightness = parse_range(args, i)?; i += 3; }
+                "CHROMA" => { chroma = parse_range(args, i)?; i += 3; }
+                "SEED" => {
+                    if i + 1 >= args.len() {
+                        return Err(ColorError::Syntax("SEED needs a value".to_string()))
+                    }
+                    seed = Some(args[i + 1].to_i64()? as u64);
+                    i += 2;
+                }
+                "MINCONTRAST" => {
+                    if i + 2 >= args.len() {
+                        return Err(ColorError::Syntax("MINCONTRAST needs a key and a ratio".to_string()))
+                    }
+                    contrasts.push((read_color(&r, args[i + 1], now)?, args[i + 2].to_f64()?));
+                    i += 3;
+                }
+                "DISTINCTFROM" => {
+                    if i + 2 >= args.len() {
+                        return Err(ColorError::Syntax("DISTINCTFROM needs a key and a deltaE".to_string()))
+                    }
+                    let palette = read_palette(&r, args[i + 1].as_bytes())?.into_iter().map(|(_, c)| c).collect();
+                    palettes.push((palette, args[i + 2].to_f64()?));
+                    i += 3;
+                }
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+        }
+        let degrees = 0.0..=360.0;
+        if !degrees.contains(&hue.min) || !degrees.contains(&hue.max) {
+            return Err(ColorError::OutOfRange("HUE must be within 0 and 360".to_string()))
+        }
+        if lightness.min > lightness.max || chroma.min > chroma.max {
+            return Err(ColorError::OutOfRange("LIGHTNESS and CHROMA need a min no greater than their max".to_string()))
+        }
+        lightness = Range { min: lightness.min.max(0.0), max: lightness.max.min(1.0) };
+        chroma = Range { min: chroma.min.max(0.0), max: chroma.max.min(MAX_CHROMA) };
+
+        let key = r.open_key_writable(args[1]);
+        if !key.is_empty() && !key.valid_key_type() {
+            return Err(ColorError::WrongType)
+        }
+
+        let mut rng = Rng::new(seed.unwrap_or_else(fresh_seed));
+        let alpha = config::get().default_alpha;
+        let color = (0..MAX_ATTEMPTS)
+            .filter_map(|_| {
+                let lch = Oklch {
+                    l: rng.between(lightness.min, lightness.max),
+                    c: rng.between(chroma.min, chroma.max),
+                    h: random_hue(&mut rng, hue),
+                };
+                if !lch.in_gamut() {
+                    return None
+                }
+                Some(lch.to_color(alpha))
+            })
+            .find(|c| {
+                contrasts.iter().all(|(other, min)| c.contrast(other) >= *min)
+                    && palettes.iter().all(|(palette, min)| palette.iter().all(|p| c.delta_e(p) >= *min))
+            });
+        let color = match color {
+            Some(c) => c,
+            None => return Err(error!("no color meets the constraints after {} attempts", MAX_ATTEMPTS)),
+        };
+
+        let old = key.read()?.map(|v| v.at(now));
+        let value = key.write(ColorValue::new(color))?;
+        replicate_color_value(&r, args[1].as_bytes(), &value)?;
+        color_changed(&r, self.name(), args[1].as_bytes(), old.as_ref(), &color);
+        r.reply_simple_string(&color.format(config::get().default_format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+    use Color;
+
+    #[test]
+    fn random_meets_constraints() {
+        let mut server = Server::with_colors(&[("white", "#ffffff")]);
+        server.hset("tags", "red", "#e03030");
+        server.hset("tags", "blue", "#3050e0");
+        for seed in 0..20 {
+            let seed = seed.to_string();
+            let color = server.run(&[
+                "COLOR.RANDOM", "tag", "SEED", &seed, "HUE", "300", "60", "LIGHTNESS", "0.3", "0.7",
+                "CHROMA", "0.1", "0.2", "MINCONTRAST", "white", "4.5", "DISTINCTFROM", "tags", "10",
+            ]).into_simple();
+            let c: Color = color.parse().unwrap();
+            let lch = c.to_oklch();
+            assert!(lch.h >= 299.0 || lch.h <= 61.0, "{} {:?}", color, lch);
+            assert!(lch.l >= 0.29 && lch.l <= 0.71, "{} {:?}", color, lch);
+            assert!(c.contrast(&"#ffffff".parse().unwrap()) >= 4.5, "{}", color);
+            assert!(c.delta_e(&"#e03030".parse().unwrap()) >= 10.0, "{}", color);
+            assert_eq!(server.run(&["COLOR.GET", "tag"]), Reply::Simple(color));
+        }
+    }
+
+    #[test]
+    fn random_declares_its_keys() {
+        let mut server = Server::new();
+        assert_eq!(server.command_flags("color.random"), Some("write deny-oom random getkeys-api"));
+        assert_eq!(server.getkeys(&[
+            "COLOR.RANDOM", "tag", "SEED", "1", "HUE", "0", "60", "MINCONTRAST", "white", "4.5",
+            "DISTINCTFROM", "tags", "10", "MINCONTRAST", "black", "3",
+        ]), vec!["tag", "white", "tags", "black"]);
+        assert_eq!(server.getkeys(&["COLOR.RANDOM", "tag", "MINCONTRAST"]), vec!["tag"]);
+        assert_eq!(server.getkeys(&["COLOR.SET", "pink", "#ff55ef"]), vec!["pink"]);
+        // Asking for the keys doesn't run the command.
+        assert!(!server.exists("tag"));
+        assert_eq!(server.run(&["COLOR.INFO", "commands"]).info_field("cmdstat_color.random"), None);
+    }
+
+    #[test]
+    fn random_is_seeded_and_replicated_as_set() {
+        let mut server = Server::new();
+        let first = server.run(&["COLOR.RANDOM", "a", "SEED", "42"]).into_simple();
+        assert_eq!(server.run(&["COLOR.RANDOM", "b", "SEED", "42"]).into_simple(), first);
+        assert_eq!(server.replicated[1], vec!["color.set".to_string(), "b".to_string(), format!("{}ff", first)]);
+        let unseeded: Vec<String> = (0..3).map(|_| server.run(&["COLOR.RANDOM", "c"]).into_simple()).collect();
+        assert!(unseeded[0] != unseeded[1] || unseeded[1] != unseeded[2]);
+    }
+
+    #[test]
+    fn random_gives_up_on_impossible_constraints() {
+        let mut server = Server::with_colors(&[("gray", "#777777")]);
+        assert!(server.run(&["COLOR.RANDOM", "a", "MINCONTRAST", "gray", "10"]).is_error("ERR no color"));
+        assert!(!server.exists("a"));
+        assert!(server.run(&["COLOR.RANDOM", "a", "MINCONTRAST", "nope", "3"]).is_error("NOTFOUND"));
+        assert!(server.run(&["COLOR.RANDOM", "a", "DISTINCTFROM", "gray", "3"]).is_error("WRONGTYPE"));
+        assert!(server.run(&["COLOR.RANDOM", "a", "HUE", "0", "400"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.RANDOM", "a", "SEED"]).is_error("ERR syntax"));
+    }
+}
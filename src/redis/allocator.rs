@@ -0,0 +1,178 @@
+// A global allocator on top of Redis' own, so that the memory we use shows up
+// in INFO memory and counts towards maxmemory.
+//
+// Redis' allocation functions only exist once the module API was initialised,
+// which isn't the case before RedisModule_OnLoad runs nor in unit tests until
+// a mock server started. The few allocations made until then come from
+// EARLY, a static arena that is never freed, and are told apart from Redis'
+// blocks by their address. Blocks from Redis are handed out as they are, so
+// they cost nothing on top of what Redis itself charges.
+//
+// Redis guarantees 8 byte alignment only (zmalloc may prefix its blocks with
+// their size), so blocks with larger alignments are cut from a larger Redis
+// block, with a pointer to the start of that block right before them:
+//
+//     | ... | start | block handed out ... |
+//     ^-- start     ^-- block - 8
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::c_void;
+use redis::raw;
+
+const REDIS_ALIGN: usize = 8;
+
+// Enough for OnLoad's allocations before it initialises the API, and for
+// what the test harness allocates before the first mock server starts.
+const EARLY_SIZE: usize = 1 << 20;
+
+#[repr(align(4096))]
+struct Early(UnsafeCell<[u8; EARLY_SIZE]>);
+
+// Only ever handed out in disjoint pieces, see `early_alloc`.
+unsafe impl Sync for Early {}
+
+static EARLY: Early = Early(UnsafeCell::new([0; EARLY_SIZE]));
+static EARLY_USED: AtomicUsize = AtomicUsize::new(0);
+
+// Cuts a block out of EARLY. The arena starts zeroed and is never reused, so
+// blocks are zeroed too.
+fn early_alloc(layout: Layout) -> *mut u8 {
+    let base = EARLY.0.get() as usize;
+    let mut used = EARLY_USED.load(Ordering::Relaxed);
+    loop {
+        let start = (base + used + layout.align() - 1) & !(layout.align() - 1);
+        let end = match start.checked_add(layout.size()) {
+            Some(end) if end <= base + EARLY_SIZE => end,
+            _ => return ptr::null_mut(),
+        };
+        match EARLY_USED.compare_exchange_weak(used, end - base, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return start as *mut u8,
+            Err(current) => used = current,
+        }
+    }
+}
+
+fn is_early(block: *mut u8) -> bool {
+    let base = EARLY.0.get() as usize;
+    (base..base + EARLY_SIZE).contains(&(block as usize))
+}
+
+fn over_aligned(layout: Layout) -> bool {
+    layout.align() > REDIS_ALIGN
+}
+
+unsafe fn start_slot(block: *mut u8) -> *mut *mut c_void {
+    block.sub(mem::size_of::<usize>()) as *mut *mut c_void
+}
+
+pub struct RedisAlloc;
+
+impl RedisAlloc {
+    unsafe fn allocate(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        if !over_aligned(layout) {
+            let mem = if zeroed { raw::calloc(1, layout.size()) } else { raw::alloc(layout.size()) };
+            return match mem {
+                Some(mem) => mem as *mut u8,
+                None => early_alloc(layout),
+            }
+        }
+
+        // Room to align the block and to keep the start of the Redis block
+        // before it.
+        let size = match layout.size().checked_add(layout.align()) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+        let mem = match if zeroed { raw::calloc(1, size) } else { raw::alloc(size) } {
+            Some(mem) if mem.is_null() => return ptr::null_mut(),
+            Some(mem) => mem,
+            None => return early_alloc(layout),
+        };
+        let block = (mem as *mut u8).add(layout.align() - (mem as usize) % layout.align());
+        *start_slot(block) = mem;
+        block
+    }
+}
+
+unsafe impl GlobalAlloc for RedisAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout, false)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout, true)
+    }
+
+    // Early blocks are leaked. Other blocks came from Redis, which can't have
+    // gone away while one of them is alive, short of the module being
+    // unloaded, in which case we leak too.
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        if is_early(block) {
+            return
+        }
+        if over_aligned(layout) {
+            raw::free(*start_slot(block));
+        } else {
+            raw::free(block as *mut c_void);
+        }
+    }
+
+    // Only plain Redis blocks can be reallocated in place. The others move to
+    // a new block.
+    unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if !is_early(block) && !over_aligned(layout) {
+            return match raw::realloc(block as *mut c_void, new_size) {
+                Some(mem) => mem as *mut u8,
+                None => ptr::null_mut(),
+            }
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_block = self.alloc(new_layout);
+        if !new_block.is_null() {
+            ptr::copy_nonoverlapping(block, new_block, cmp::min(layout.size(), new_size));
+            self.dealloc(block, layout);
+        }
+        new_block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::mock::Server;
+
+    #[test]
+    fn blocks_move_between_arenas() {
+        let _server = Server::new();
+        let alloc = RedisAlloc;
+        unsafe {
+            // An early block moves to Redis when it grows.
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            let early = early_alloc(layout);
+            assert!(is_early(early));
+            early.write_bytes(7, 16);
+            let grown = alloc.realloc(early, layout, 64);
+            assert!(!is_early(grown));
+            assert_eq!(*grown.add(15), 7);
+            alloc.dealloc(grown, Layout::from_size_align(64, 8).unwrap());
+
+            // Over-aligned blocks are aligned and keep their content as they
+            // grow.
+            let layout = Layout::from_size_align(24, 64).unwrap();
+            let block = alloc.alloc_zeroed(layout);
+            assert_eq!(block as usize % 64, 0);
+            assert_eq!(*block.add(23), 0);
+            block.write_bytes(9, 24);
+            let grown = alloc.realloc(block, layout, 200);
+            assert_eq!(grown as usize % 64, 0);
+            assert_eq!(*grown.add(23), 9);
+            alloc.dealloc(grown, Layout::from_size_align(200, 64).unwrap());
+        }
+    }
+}
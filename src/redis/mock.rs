@@ -0,0 +1,1473 @@
+// An in-process stand-in for Redis' side of the module API, so that commands
+// and type callbacks can be run from `cargo test`.
+//
+// `Server::new` loads the module against a fake context whose first word is
+// our own `GetApi`, which is all `RedisModule_Init` needs to fill in the
+// function pointers in `raw`. From then on every API call lands in this file
+// and works on an in-memory keyspace. API functions the mock doesn't
+// implement are left null, so a command calling one crashes the test.
+//
+// The function pointers, like the color type and the module's settings, are
+// process wide, so servers are serialized: a test holds a lock from
+// `Server::new` until its server is dropped.
+
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::CStr;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use libc::{c_char, c_double, c_int, c_long, c_longlong, c_void, size_t};
+use redis::raw::{
+    self, KeyMode, KeyType, ReplyType, Status, RedisModuleBlockedClient, RedisModuleCallReply,
+    RedisModuleCmdFunc, RedisModuleCtx, RedisModuleIO, RedisModuleKey, RedisModuleString, RedisModuleType,
+    RedisModuleTypeMethods,
+};
+
+/// A reply as a client would see it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Double(f64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn simple(s: &str) -> Reply {
+        Reply::Simple(s.to_string())
+    }
+
+    /// An array of bulk strings, as replied by commands listing keys or
+    /// fields.
+    pub fn bulks(items: &[&str]) -> Reply {
+        Reply::Array(items.iter().map(|i| Reply::Bulk(i.as_bytes().to_vec())).collect())
+    }
+
+    /// The string of a simple string reply. Panics on other replies.
+    pub fn into_simple(self) -> String {
+        match self {
+            Reply::Simple(s) => s,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    /// Picks the `field:value` line for `field` out of an INFO-like bulk
+    /// reply.
+    pub fn info_field(&self, field: &str) -> Option<String> {
+        let info = match *self {
+            Reply::Bulk(ref info) => String::from_utf8_lossy(info),
+            ref other => panic!("{:?}", other),
+        };
+        let prefix = format!("{}:", field);
+        info.lines().find(|l| l.starts_with(&prefix)).map(|l| l[prefix.len()..].to_string())
+    }
+
+    /// Whether this is an error whose message starts with `prefix`.
+    pub fn is_error(&self, prefix: &str) -> bool {
+        match *self {
+            Reply::Error(ref e) => e.starts_with(prefix),
+            _ => false,
+        }
+    }
+}
+
+/// One value written by an `rdb_save` callback.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RdbValue {
+    Unsigned(u64),
+    Signed(i64),
+    Double(f64),
+    Buffer(Vec<u8>),
+}
+
+/// A module value as saved in an RDB file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rdb {
+    pub type_name: String,
+    pub encver: c_int,
+    pub values: Vec<RdbValue>,
+}
+
+struct ModuleType {
+    name: String,
+    encver: c_int,
+    methods: RedisModuleTypeMethods,
+}
+
+enum Value {
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    Zset(BTreeMap<Vec<u8>, f64>),
+    Module(*mut ModuleType, *mut c_void),
+}
+
+struct CommandInfo {
+    handler: RedisModuleCmdFunc,
+    flags: String,
+    keys: (c_int, c_int, c_int),
+}
+
+/// A Redis server with the module loaded.
+pub struct Server {
+    keys: HashMap<Vec<u8>, Value>,
+    commands: HashMap<String, CommandInfo>,
+    /// Everything the module logged, as (level, message).
+    pub log: Vec<(String, String)>,
+    /// Everything the module published, as (channel, message).
+    pub published: Vec<(String, String)>,
+    /// When set, PUBLISH fails with this error.
+    pub publish_error: Option<String>,
+    /// When set, replies other than errors and arrays fail. The client gets
+    /// `ERR reply failed` instead.
+    pub fail_replies: bool,
+    /// Every command propagated to replicas and the AOF, with its arguments.
+    pub replicated: Vec<Vec<String>>,
+    // Clients that ran a command, and those of them that disconnected since.
+    clients: BTreeSet<u64>,
+    disconnected: HashSet<u64>,
+    // Handles of blocked clients, until the module unblocks them and they're
+    // dealt with by `unblocked`.
+    blocked: Vec<*mut Blocked>,
+    // Dropped last, once the values above are freed.
+    _serial: MutexGuard<'static, ()>,
+}
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+// Module types outlive servers so that, like in Redis, a type keeps its
+// address for as long as the process runs. Pointers are kept as usize, which
+// unlike raw pointers can sit in a static.
+static TYPES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+static NOW_MS: AtomicI64 = AtomicI64::new(0);
+static CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The time servers start at, in Unix milliseconds.
+pub const START_MS: i64 = 1_500_000_000_000;
+
+/// How a server runs a command: `Server::run_replicated` or
+/// `Server::run_loading`.
+pub type Run = fn(&mut Server, &[&str]) -> Reply;
+
+impl Server {
+    /// Starts a server and loads the module into it.
+    pub fn new() -> Server {
+        match Server::with_args(&[]) {
+            Ok(server) => server,
+            Err(log) => panic!("module failed to load: {}", log),
+        }
+    }
+
+    /// Starts a server and loads the module with `args`, as given to
+    /// `loadmodule`. Fails with what the module logged if it refused to load.
+    pub fn with_args(args: &[&str]) -> Result<Server, String> {
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        install_hooks();
+        NOW_MS.store(START_MS, Ordering::SeqCst);
+        CLIENT_ID.store(1, Ordering::SeqCst);
+        let mut server = Server {
+            keys: HashMap::new(),
+            commands: HashMap::new(),
+            log: Vec::new(),
+            published: Vec::new(),
+            publish_error: None,
+            fail_replies: false,
+            replicated: Vec::new(),
+            clients: BTreeSet::new(),
+            disconnected: HashSet::new(),
+            blocked: Vec::new(),
+            _serial: serial,
+        };
+        let argv: Vec<*mut RedisModuleString> =
+            args.iter().map(|a| new_string(a.as_bytes())).collect();
+        let mut ctx = Ctx::new(&mut server);
+        let status = ::RedisModule_OnLoad(ctx.as_raw(), argv.as_ptr() as *mut _, argv.len() as c_int);
+        drop(ctx);
+        for s in argv {
+            free_string(ptr::null_mut(), s);
+        }
+        match status {
+            Status::Ok => Ok(server),
+            Status::Err => Err(server.log.iter().map(|(_, m)| m.as_str()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    /// Starts a server and sets the `(key, color)` pairs in it.
+    pub fn with_colors(colors: &[(&str, &str)]) -> Server {
+        let mut server = Server::new();
+        server.set_colors(colors);
+        server
+    }
+
+    /// Sets each key to its color with COLOR.SET.
+    pub fn set_colors(&mut self, colors: &[(&str, &str)]) {
+        for &(key, color) in colors {
+            assert_eq!(self.run(&["COLOR.SET", key, color]), Reply::simple("OK"), "{} {}", key, color);
+        }
+    }
+
+    /// Moves the clock to `ms`, in Unix milliseconds.
+    pub fn set_time(&self, ms: i64) {
+        NOW_MS.store(ms, Ordering::SeqCst);
+    }
+
+    /// Makes the commands that follow run as the client with ID `id`. They
+    /// run as client 1 by default.
+    pub fn set_client_id(&self, id: u64) {
+        CLIENT_ID.store(id, Ordering::SeqCst);
+    }
+
+    /// Runs a command the way a client would and returns its reply.
+    pub fn run(&mut self, args: &[&str]) -> Reply {
+        match self.run_blocking(args) {
+            Some(reply) => reply,
+            None => panic!("{} blocked the client", args[0]),
+        }
+    }
+
+    /// Runs a command the way a replica applies the replication stream, as
+    /// sent by its master.
+    pub fn run_replicated(&mut self, args: &[&str]) -> Reply {
+        self.run_with_flags(args, raw::ContextFlags::REPLICATED).expect("replicated command blocked")
+    }
+
+    /// Runs a command the way Redis replays the AOF while loading it.
+    pub fn run_loading(&mut self, args: &[&str]) -> Reply {
+        self.run_with_flags(args, raw::ContextFlags::LOADING).expect("AOF command blocked")
+    }
+
+    /// Runs `commands` with `run`, as a replica or an AOF load would, and
+    /// checks each of them replied OK.
+    pub fn replay(&mut self, run: Run, commands: &[Vec<String>]) {
+        for command in commands {
+            let args: Vec<&str> = command.iter().map(|a| a.as_str()).collect();
+            assert_eq!(run(self, &args), Reply::simple("OK"), "{:?}", command);
+        }
+    }
+
+    /// Like `run`, for a command that may block the client, in which case
+    /// there's no reply until the client is unblocked (see `unblocked`) or
+    /// times out (see `time_out_blocked`).
+    pub fn run_blocking(&mut self, args: &[&str]) -> Option<Reply> {
+        self.run_with_flags(args, raw::ContextFlags::empty())
+    }
+
+    fn run_with_flags(&mut self, args: &[&str], flags: raw::ContextFlags) -> Option<Reply> {
+        let handler = match self.commands.get(&args[0].to_lowercase()) {
+            Some(info) => info.handler,
+            None => return Some(Reply::Error(format!("ERR unknown command '{}'", args[0]))),
+        };
+        self.clients.insert(CLIENT_ID.load(Ordering::SeqCst));
+        let command: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (replies, blocked) = self.call_handler(handler, command, flags);
+        if blocked {
+            assert!(replies.is_empty(), "{} blocked after sending {:?}", args[0], replies);
+            return None
+        }
+        assert_eq!(replies.len(), 1, "{} sent {:?}", args[0], replies);
+        replies.into_iter().next()
+    }
+
+    /// The keys of a command, like COMMAND GETKEYS: those at the positions the
+    /// command was registered with, or that it tells itself for commands
+    /// registered with getkeys-api.
+    pub fn getkeys(&mut self, args: &[&str]) -> Vec<String> {
+        let info = &self.commands[&args[0].to_lowercase()];
+        let positions = if info.flags.split(' ').any(|f| f == "getkeys-api") {
+            let handler = info.handler;
+            let argv: Vec<*mut RedisModuleString> = args.iter().map(|a| new_string(a.as_bytes())).collect();
+            let mut ctx = Ctx::new(self);
+            ctx.keys = Some(Vec::new());
+            handler(ctx.as_raw(), argv.as_ptr() as *mut _, argv.len() as c_int);
+            for s in argv {
+                free_string(ptr::null_mut(), s);
+            }
+            assert!(ctx.replies.is_empty(), "{} replied to a keys position request", args[0]);
+            ctx.keys.unwrap()
+        } else {
+            let (first, last, step) = info.keys;
+            let last = if last < 0 { args.len() as c_int + last } else { last };
+            if first == 0 { Vec::new() } else { (first..=last).step_by(step as usize).map(|p| p as usize).collect() }
+        };
+        positions.into_iter().map(|p| args[p].to_string()).collect()
+    }
+
+    // Runs `handler` for `command`, returning what it replied and whether it
+    // blocked the client.
+    fn call_handler(&mut self, handler: RedisModuleCmdFunc, command: Vec<String>, flags: raw::ContextFlags)
+        -> (Vec<Reply>, bool) {
+        let argv: Vec<*mut RedisModuleString> =
+            command.iter().map(|a| new_string(a.as_bytes())).collect();
+        let mut ctx = Ctx::new(self);
+        ctx.command = command;
+        ctx.flags = flags;
+        handler(ctx.as_raw(), argv.as_ptr() as *mut _, argv.len() as c_int);
+        for s in argv {
+            free_string(ptr::null_mut(), s);
+        }
+        assert!(ctx.open.is_empty(), "{} left an array reply open", ctx.command[0]);
+        (ctx.replies, ctx.blocked)
+    }
+
+    // Runs a blocked client's callback as that client.
+    fn call_back(&mut self, bc: *mut Blocked, callback: Option<RedisModuleCmdFunc>) -> Reply {
+        let (client, command) = unsafe { ((*bc).client, (*bc).command.clone()) };
+        let previous = CLIENT_ID.swap(client, Ordering::SeqCst);
+        let (mut replies, blocked) = self.call_handler(callback.expect("no callback"), command, raw::ContextFlags::empty());
+        CLIENT_ID.store(previous, Ordering::SeqCst);
+        assert!(!blocked && replies.len() == 1, "blocked client callback sent {:?}", replies);
+        replies.pop().unwrap()
+    }
+
+    /// Deals with the clients the module unblocked, like Redis does on its
+    /// next event loop iteration: the reply callback of each runs, unless the
+    /// client is gone, and the handle is freed. Returns the replies, by client.
+    pub fn unblocked(&mut self) -> Vec<(u64, Reply)> {
+        let mut replies = Vec::new();
+        for bc in self.blocked.clone() {
+            let b = unsafe { &*bc };
+            if !b.unblocked {
+                continue
+            }
+            if !b.gone {
+                replies.push((b.client, self.call_back(bc, b.reply_callback)));
+            }
+            let b = unsafe { Box::from_raw(bc) };
+            if let (Some(free), false) = (b.free_privdata, b.privdata.is_null()) {
+                free(b.privdata);
+            }
+            self.blocked.retain(|&other| other != bc);
+        }
+        replies
+    }
+
+    /// Times out the blocked clients whose timeout passed, running their
+    /// timeout callback. As in Redis, their handle lives on until the module
+    /// unblocks them. Returns the replies, by client.
+    pub fn time_out_blocked(&mut self) -> Vec<(u64, Reply)> {
+        let now = NOW_MS.load(Ordering::SeqCst);
+        let mut replies = Vec::new();
+        for bc in self.blocked.clone() {
+            let b = unsafe { &*bc };
+            if b.gone || b.unblocked || b.deadline.is_none_or(|deadline| deadline > now) {
+                continue
+            }
+            replies.push((b.client, self.call_back(bc, b.timeout_callback)));
+            unsafe { (*bc).gone = true };
+        }
+        replies
+    }
+
+    /// Disconnects the client with ID `id`. As in Redis, the handle of a
+    /// blocked client lives on until the module unblocks it.
+    pub fn disconnect(&mut self, id: u64) {
+        self.disconnected.insert(id);
+        for &bc in &self.blocked {
+            if unsafe { (*bc).client } == id {
+                unsafe { (*bc).gone = true };
+            }
+        }
+    }
+
+    /// The number of blocked client handles the module didn't unblock yet.
+    pub fn blocked_handles(&self) -> usize {
+        self.blocked.iter().filter(|&&bc| !unsafe { (*bc).unblocked }).count()
+    }
+
+    /// The bytes the module allocated from Redis and didn't free yet, like
+    /// used_memory in INFO. Only what the calling thread allocated and freed
+    /// counts, so that tests running alongside don't get in the way.
+    pub fn used_memory(&self) -> isize {
+        USED_MEMORY.with(|used| used.get())
+    }
+
+    /// The flags `command` was registered with.
+    pub fn command_flags(&self, command: &str) -> Option<&str> {
+        self.commands.get(command).map(|info| info.flags.as_str())
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.keys.contains_key(key.as_bytes())
+    }
+
+    /// Sets `field` of the hash at `key`, like HSET. Both can be any bytes.
+    pub fn hset<K: AsRef<[u8]>, F: AsRef<[u8]>>(&mut self, key: K, field: F, value: &str) {
+        let key = key.as_ref();
+        match *self.keys.entry(key.to_vec()).or_insert_with(|| Value::Hash(BTreeMap::new())) {
+            Value::Hash(ref mut fields) => { fields.insert(field.as_ref().to_vec(), value.as_bytes().to_vec()); }
+            _ => panic!("{} doesn't hold a hash", String::from_utf8_lossy(key)),
+        }
+    }
+
+    /// Moves the value at `from` to `to`, like RENAME. Unlike with `run`,
+    /// `to` can be any bytes.
+    pub fn rename(&mut self, from: &str, to: &[u8]) {
+        let value = self.keys.remove(from.as_bytes()).unwrap_or_else(|| panic!("no such key {}", from));
+        self.keys.insert(to.to_vec(), value);
+    }
+
+    /// The value of `field` in the hash at `key`, like HGET.
+    pub fn hget(&self, key: &str, field: &str) -> Option<String> {
+        match self.keys.get(key.as_bytes()) {
+            Some(Value::Hash(fields)) => fields.get(field.as_bytes()).map(|v| String::from_utf8_lossy(v).into_owned()),
+            _ => None,
+        }
+    }
+
+    /// Deletes `key`, freeing its value like DEL does.
+    pub fn del(&mut self, key: &str) -> bool {
+        match self.keys.remove(key.as_bytes()) {
+            Some(value) => {
+                drop_value(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Saves the module value at `key` as it would go into an RDB file.
+    pub fn dump(&mut self, key: &str) -> Rdb {
+        let (mt, value) = match self.keys.get(key.as_bytes()) {
+            Some(&Value::Module(mt, value)) => (mt, value),
+            _ => panic!("{} doesn't hold a module value", key),
+        };
+        let mut io = Io::new(self);
+        let mt = unsafe { &*mt };
+        unsafe { mt.methods.rdb_save.unwrap()(io.as_raw(), value) };
+        Rdb { type_name: mt.name.clone(), encver: mt.encver, values: io.rdb.into_iter().collect() }
+    }
+
+    /// Loads `rdb` into `key`, like Redis does when it starts from an RDB file.
+    /// Fails if the type's `rdb_load` callback fails or doesn't read the value
+    /// exactly.
+    pub fn restore(&mut self, key: &str, rdb: &Rdb) -> Result<(), String> {
+        let mt = find_type(&rdb.type_name).ok_or(format!("no type {}", rdb.type_name))?;
+        let mut io = Io::new(self);
+        io.rdb = rdb.values.iter().cloned().collect();
+        let value = unsafe { (*mt).methods.rdb_load.unwrap()(io.as_raw(), rdb.encver) };
+        if value.is_null() || io.errors > 0 || !io.rdb.is_empty() {
+            if !value.is_null() {
+                free_value(mt, value);
+            }
+            return Err(format!("bad RDB for {}", key))
+        }
+        self.set_value(key.as_bytes(), Value::Module(mt, value));
+        Ok(())
+    }
+
+    /// Rewrites the module value at `key` into the commands an AOF rewrite
+    /// would hold for it.
+    pub fn rewrite_aof(&mut self, key: &str) -> Vec<Vec<String>> {
+        let (mt, value) = match self.keys.get(key.as_bytes()) {
+            Some(&Value::Module(mt, value)) => (mt, value),
+            _ => panic!("{} doesn't hold a module value", key),
+        };
+        let mut io = Io::new(self);
+        let name = new_string(key.as_bytes());
+        unsafe { (*mt).methods.aof_rewrite.unwrap()(io.as_raw(), name, value) };
+        free_string(ptr::null_mut(), name);
+        io.aof.iter()
+            .map(|cmd| cmd.iter().map(|a| String::from_utf8_lossy(a).into_owned()).collect())
+            .collect()
+    }
+
+    /// Reports the memory used by the module value at `key`.
+    pub fn mem_usage(&self, key: &str) -> usize {
+        match self.keys.get(key.as_bytes()) {
+            Some(&Value::Module(mt, value)) => unsafe { (*mt).methods.mem_usage.unwrap()(value) },
+            _ => panic!("{} doesn't hold a module value", key),
+        }
+    }
+
+    fn set_value(&mut self, key: &[u8], value: Value) {
+        if let Some(old) = self.keys.insert(key.to_vec(), value) {
+            drop_value(old);
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for (_, value) in self.keys.drain() {
+            drop_value(value);
+        }
+        for bc in self.blocked.drain(..) {
+            drop(unsafe { Box::from_raw(bc) });
+        }
+    }
+}
+
+// A client blocked by the module, behind the handle BlockClient returns.
+struct Blocked {
+    client: u64,
+    // The command that blocked the client, which its callbacks get too.
+    command: Vec<String>,
+    reply_callback: Option<RedisModuleCmdFunc>,
+    timeout_callback: Option<RedisModuleCmdFunc>,
+    free_privdata: Option<extern "C" fn(*mut c_void)>,
+    privdata: *mut c_void,
+    deadline: Option<i64>,
+    // The module called UnblockClient.
+    unblocked: bool,
+    // The client timed out or disconnected.
+    gone: bool,
+}
+
+fn drop_value(value: Value) {
+    if let Value::Module(mt, v) = value {
+        free_value(mt, v);
+    }
+}
+
+fn free_value(mt: *mut ModuleType, value: *mut c_void) {
+    if let Some(free) = unsafe { (*mt).methods.free } {
+        unsafe { free(value) }
+    }
+}
+
+fn find_type(name: &str) -> Option<*mut ModuleType> {
+    TYPES.lock().unwrap().iter()
+        .map(|&t| t as *mut ModuleType)
+        .find(|&t| unsafe { (*t).name == name })
+}
+
+// A context handed to the module. `get_api` has to come first: that's where
+// `RedisModule_Init` looks for it.
+#[repr(C)]
+struct Ctx {
+    get_api: extern "C" fn(*const c_char, *mut c_void) -> c_int,
+    server: *mut Server,
+    replies: Vec<Reply>,
+    open: Vec<OpenArray>,
+    // The command being run, for ReplicateVerbatim.
+    command: Vec<String>,
+    // Whether the command blocked the client.
+    blocked: bool,
+    // The key positions declared, during a keys position request.
+    keys: Option<Vec<usize>>,
+    // What GetContextFlags tells about where the command comes from.
+    flags: raw::ContextFlags,
+}
+
+// An array reply that hasn't got all its elements yet. `len` is None while a
+// postponed array's length isn't set.
+struct OpenArray {
+    len: Option<usize>,
+    items: Vec<Reply>,
+}
+
+impl Ctx {
+    fn new(server: &mut Server) -> Ctx {
+        Ctx {
+            get_api,
+            server,
+            replies: Vec::new(),
+            open: Vec::new(),
+            command: Vec::new(),
+            blocked: false,
+            keys: None,
+            flags: raw::ContextFlags::empty(),
+        }
+    }
+
+    fn as_raw(&mut self) -> *mut RedisModuleCtx {
+        self as *mut Ctx as *mut RedisModuleCtx
+    }
+
+    fn reply(&mut self, reply: Reply) {
+        match self.open.last_mut() {
+            Some(array) => array.items.push(reply),
+            None => self.replies.push(reply),
+        }
+        self.close_arrays();
+    }
+
+    // Closes the innermost arrays for as long as they are complete.
+    fn close_arrays(&mut self) {
+        loop {
+            let done = match self.open.last() {
+                Some(array) => {
+                    assert!(array.len.is_none_or(|len| array.items.len() <= len), "array reply overflow");
+                    array.len == Some(array.items.len())
+                }
+                None => false,
+            };
+            if !done {
+                return
+            }
+            let array = Reply::Array(self.open.pop().unwrap().items);
+            match self.open.last_mut() {
+                Some(parent) => parent.items.push(array),
+                None => self.replies.push(array),
+            }
+        }
+    }
+}
+
+unsafe fn ctx<'a>(ctx: *mut RedisModuleCtx) -> &'a mut Ctx {
+    &mut *(ctx as *mut Ctx)
+}
+
+// A key opened by the module.
+struct Key {
+    server: *mut Server,
+    name: Vec<u8>,
+    writable: bool,
+    // What's left of a sorted set range being iterated.
+    range: VecDeque<(Vec<u8>, f64)>,
+}
+
+unsafe fn key<'a>(key: *mut RedisModuleKey) -> &'a mut Key {
+    &mut *(key as *mut Key)
+}
+
+unsafe fn key_value<'a>(k: *mut RedisModuleKey) -> Option<&'a mut Value> {
+    let k = key(k);
+    (*k.server).keys.get_mut(&k.name)
+}
+
+// The handle of an RDB or AOF being written or read.
+struct Io {
+    server: *mut Server,
+    rdb: VecDeque<RdbValue>,
+    aof: Vec<Vec<Vec<u8>>>,
+    errors: usize,
+    // The context GetContextFromIO hands out, once asked for.
+    ctx: Option<Box<Ctx>>,
+}
+
+impl Io {
+    fn new(server: &mut Server) -> Io {
+        Io { server, rdb: VecDeque::new(), aof: Vec::new(), errors: 0, ctx: None }
+    }
+
+    fn as_raw(&mut self) -> *mut RedisModuleIO {
+        self as *mut Io as *mut RedisModuleIO
+    }
+}
+
+unsafe fn io<'a>(io: *mut RedisModuleIO) -> &'a mut Io {
+    &mut *(io as *mut Io)
+}
+
+// Strings are boxed byte vectors.
+fn new_string(bytes: &[u8]) -> *mut RedisModuleString {
+    Box::into_raw(Box::new(bytes.to_vec())) as *mut RedisModuleString
+}
+
+unsafe fn string_bytes<'a>(s: *mut RedisModuleString) -> &'a [u8] {
+    &*(s as *mut Vec<u8>)
+}
+
+// Call replies are boxed replies. Array elements point into their array.
+fn new_call_reply(reply: Reply) -> *mut RedisModuleCallReply {
+    Box::into_raw(Box::new(reply)) as *mut RedisModuleCallReply
+}
+
+unsafe fn call_reply<'a>(reply: *mut RedisModuleCallReply) -> &'a mut Reply {
+    &mut *(reply as *mut Reply)
+}
+
+extern "C" {
+    static mut MockRedis_CallHook: Option<
+        extern "C" fn(*mut RedisModuleCtx, *const c_char, *mut *mut RedisModuleString, size_t)
+            -> *mut RedisModuleCallReply,
+    >;
+    static mut MockRedis_EmitAOFHook: Option<
+        extern "C" fn(*mut RedisModuleIO, *const c_char, *const *const u8, *const size_t, size_t),
+    >;
+    static mut MockRedis_ReplicateHook: Option<
+        extern "C" fn(*mut RedisModuleCtx, *const c_char, *mut *mut RedisModuleString, size_t) -> Status,
+    >;
+    static mut MockRedis_HashSetHook: Option<
+        extern "C" fn(*mut RedisModuleKey, c_int, *mut RedisModuleString, *mut RedisModuleString) -> c_int,
+    >;
+    static mut MockRedis_HashGetHook: Option<
+        extern "C" fn(*mut RedisModuleKey, c_int, *mut RedisModuleString, *mut c_void) -> Status,
+    >;
+    static mut MockRedis_LogHook: Option<
+        extern "C" fn(*mut RedisModuleCtx, *mut RedisModuleIO, *const c_char, *const c_char),
+    >;
+
+    fn MockRedis_Call(ctx: *mut RedisModuleCtx, cmdname: *const c_char, fmt: *const c_char, ...)
+        -> *mut RedisModuleCallReply;
+    fn MockRedis_Replicate(ctx: *mut RedisModuleCtx, cmdname: *const c_char, fmt: *const c_char, ...) -> Status;
+    fn MockRedis_HashSet(key: *mut RedisModuleKey, flags: c_int, ...) -> c_int;
+    fn MockRedis_HashGet(key: *mut RedisModuleKey, flags: c_int, ...) -> Status;
+    fn MockRedis_EmitAOF(io: *mut RedisModuleIO, cmdname: *const c_char, fmt: *const c_char, ...);
+    fn MockRedis_Log(ctx: *mut RedisModuleCtx, level: *const c_char, fmt: *const c_char, ...);
+    fn MockRedis_LogIOError(io: *mut RedisModuleIO, level: *const c_char, fmt: *const c_char, ...);
+}
+
+fn install_hooks() {
+    unsafe {
+        MockRedis_CallHook = Some(call);
+        MockRedis_EmitAOFHook = Some(emit_aof);
+        MockRedis_ReplicateHook = Some(replicate);
+        MockRedis_HashSetHook = Some(hash_set);
+        MockRedis_HashGetHook = Some(hash_get);
+        MockRedis_LogHook = Some(log);
+    }
+}
+
+macro_rules! api {
+    ($name:expr, $($api:expr => $f:expr),* $(,)*) => {
+        match $name {
+            $($api => $f as *const c_void,)*
+            _ => return 1,
+        }
+    }
+}
+
+extern "C" fn get_api(name: *const c_char, target: *mut c_void) -> c_int {
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
+    let f = api! { name,
+        "RedisModule_Alloc" => alloc,
+        "RedisModule_Calloc" => calloc,
+        "RedisModule_Realloc" => realloc,
+        "RedisModule_Free" => free,
+        "RedisModule_SetModuleAttribs" => set_module_attribs,
+        "RedisModule_CreateCommand" => create_command,
+        "RedisModule_CreateDataType" => create_data_type,
+        "RedisModule_Log" => MockRedis_Log,
+        "RedisModule_LogIOError" => MockRedis_LogIOError,
+        "RedisModule_Milliseconds" => milliseconds,
+        "RedisModule_GetSelectedDb" => get_selected_db,
+        "RedisModule_GetClientId" => get_client_id,
+        "RedisModule_GetContextFlags" => get_context_flags,
+        "RedisModule_IsKeysPositionRequest" => is_keys_position_request,
+        "RedisModule_KeyAtPos" => key_at_pos,
+        "RedisModule_BlockClient" => block_client,
+        "RedisModule_UnblockClient" => unblock_client,
+
+        "RedisModule_CreateString" => create_string,
+        "RedisModule_FreeString" => free_string,
+        "RedisModule_StringPtrLen" => string_ptr_len,
+        "RedisModule_StringToLongLong" => string_to_long_long,
+        "RedisModule_StringToDouble" => string_to_double,
+
+        "RedisModule_OpenKey" => open_key,
+        "RedisModule_CloseKey" => close_key,
+        "RedisModule_KeyType" => key_type,
+        "RedisModule_DeleteKey" => delete_key,
+        "RedisModule_ModuleTypeSetValue" => module_type_set_value,
+        "RedisModule_ModuleTypeGetType" => module_type_get_type,
+        "RedisModule_ModuleTypeGetValue" => module_type_get_value,
+        "RedisModule_HashSet" => MockRedis_HashSet,
+        "RedisModule_HashGet" => MockRedis_HashGet,
+        "RedisModule_ZsetAdd" => zset_add,
+        "RedisModule_ZsetRem" => zset_rem,
+        "RedisModule_ZsetFirstInScoreRange" => zset_first_in_score_range,
+        "RedisModule_ZsetRangeCurrentElement" => zset_range_current_element,
+        "RedisModule_ZsetRangeNext" => zset_range_next,
+        "RedisModule_ZsetRangeEndReached" => zset_range_end_reached,
+        "RedisModule_ZsetRangeStop" => zset_range_stop,
+
+        "RedisModule_ReplyWithLongLong" => reply_with_long_long,
+        "RedisModule_ReplyWithError" => reply_with_error,
+        "RedisModule_ReplyWithSimpleString" => reply_with_simple_string,
+        "RedisModule_ReplyWithArray" => reply_with_array,
+        "RedisModule_ReplySetArrayLength" => reply_set_array_length,
+        "RedisModule_ReplyWithStringBuffer" => reply_with_string_buffer,
+        "RedisModule_ReplyWithString" => reply_with_string,
+        "RedisModule_ReplyWithNull" => reply_with_null,
+        "RedisModule_ReplyWithDouble" => reply_with_double,
+        "RedisModule_ReplyWithCallReply" => reply_with_call_reply,
+
+        "RedisModule_Call" => MockRedis_Call,
+        "RedisModule_Replicate" => MockRedis_Replicate,
+        "RedisModule_ReplicateVerbatim" => replicate_verbatim,
+        "RedisModule_FreeCallReply" => free_call_reply,
+        "RedisModule_CallReplyType" => call_reply_type,
+        "RedisModule_CallReplyInteger" => call_reply_integer,
+        "RedisModule_CallReplyStringPtr" => call_reply_string_ptr,
+        "RedisModule_CallReplyLength" => call_reply_length,
+        "RedisModule_CallReplyArrayElement" => call_reply_array_element,
+
+        "RedisModule_EmitAOF" => MockRedis_EmitAOF,
+        "RedisModule_GetContextFromIO" => get_context_from_io,
+        "RedisModule_SaveUnsigned" => save_unsigned,
+        "RedisModule_LoadUnsigned" => load_unsigned,
+        "RedisModule_SaveSigned" => save_signed,
+        "RedisModule_LoadSigned" => load_signed,
+        "RedisModule_SaveDouble" => save_double,
+        "RedisModule_LoadDouble" => load_double,
+        "RedisModule_SaveStringBuffer" => save_string_buffer,
+        "RedisModule_LoadStringBuffer" => load_string_buffer,
+    };
+    unsafe { *(target as *mut *const c_void) = f };
+    0
+}
+
+// Bytes allocated through the module API and not freed yet, by thread, see
+// `Server::used_memory`. Blocks are counted at their usable size, as Redis
+// counts them in used_memory.
+thread_local!(static USED_MEMORY: Cell<isize> = const { Cell::new(0) });
+
+fn count_memory(block: *mut c_void, sign: isize) {
+    if !block.is_null() {
+        let size = unsafe { libc::malloc_usable_size(block) } as isize;
+        // Nothing to count in once the thread is going away.
+        let _ = USED_MEMORY.try_with(|used| used.set(used.get() + sign * size));
+    }
+}
+
+extern "C" fn alloc(bytes: size_t) -> *mut c_void {
+    let block = unsafe { libc::malloc(bytes) };
+    count_memory(block, 1);
+    block
+}
+
+extern "C" fn calloc(nmemb: size_t, size: size_t) -> *mut c_void {
+    let block = unsafe { libc::calloc(nmemb, size) };
+    count_memory(block, 1);
+    block
+}
+
+extern "C" fn realloc(block: *mut c_void, bytes: size_t) -> *mut c_void {
+    count_memory(block, -1);
+    let new = unsafe { libc::realloc(block, bytes) };
+    // A failed realloc leaves the block as it was.
+    count_memory(if new.is_null() { block } else { new }, 1);
+    new
+}
+
+extern "C" fn free(block: *mut c_void) {
+    count_memory(block, -1);
+    unsafe { libc::free(block) }
+}
+
+extern "C" fn set_module_attribs(_ctx: *mut RedisModuleCtx, _name: *const c_char, _ver: c_int, _apiver: c_int) {}
+
+extern "C" fn create_command(
+    ctx: *mut RedisModuleCtx,
+    name: *const u8,
+    cmdfunc: Option<RedisModuleCmdFunc>,
+    strflags: *const u8,
+    firstkey: c_int,
+    lastkey: c_int,
+    keystep: c_int,
+) -> Status {
+    let name = unsafe { CStr::from_ptr(name as *const c_char) }.to_string_lossy().into_owned();
+    let flags = unsafe { CStr::from_ptr(strflags as *const c_char) }.to_string_lossy().into_owned();
+    let server = unsafe { &mut *self::ctx(ctx).server };
+    if server.commands.contains_key(&name) {
+        return Status::Err
+    }
+    server.commands.insert(name, CommandInfo { handler: cmdfunc.unwrap(), flags, keys: (firstkey, lastkey, keystep) });
+    Status::Ok
+}
+
+extern "C" fn create_data_type(
+    _ctx: *mut RedisModuleCtx,
+    name: *const u8,
+    encver: c_int,
+    methods: *mut RedisModuleTypeMethods,
+) -> *mut RedisModuleType {
+    let name = unsafe { CStr::from_ptr(name as *const c_char) }.to_string_lossy().into_owned();
+    let methods = unsafe { *methods };
+    let mt = match find_type(&name) {
+        Some(mt) => mt,
+        None => {
+            let mt = Box::into_raw(Box::new(ModuleType { name: name.clone(), encver, methods }));
+            TYPES.lock().unwrap().push(mt as usize);
+            mt
+        }
+    };
+    unsafe {
+        (*mt).encver = encver;
+        (*mt).methods = methods;
+    }
+    mt as *mut RedisModuleType
+}
+
+extern "C" fn log(ctx: *mut RedisModuleCtx, io: *mut RedisModuleIO, level: *const c_char, msg: *const c_char) {
+    let level = unsafe { CStr::from_ptr(level) }.to_string_lossy().into_owned();
+    let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned();
+    let server = unsafe {
+        if !ctx.is_null() {
+            self::ctx(ctx).server
+        } else if !io.is_null() {
+            self::io(io).server
+        } else {
+            ptr::null_mut()
+        }
+    };
+    if server.is_null() {
+        eprintln!("{}: {}", level, msg);
+    } else {
+        unsafe { (*server).log.push((level, msg)) };
+    }
+}
+
+extern "C" fn milliseconds() -> c_longlong {
+    NOW_MS.load(Ordering::SeqCst)
+}
+
+extern "C" fn get_selected_db(_ctx: *mut RedisModuleCtx) -> c_int {
+    0
+}
+
+extern "C" fn get_client_id(_ctx: *mut RedisModuleCtx) -> u64 {
+    CLIENT_ID.load(Ordering::SeqCst)
+}
+
+extern "C" fn get_context_flags(c: *mut RedisModuleCtx) -> c_int {
+    unsafe { ctx(c) }.flags.bits()
+}
+
+extern "C" fn is_keys_position_request(c: *mut RedisModuleCtx) -> c_int {
+    unsafe { ctx(c) }.keys.is_some() as c_int
+}
+
+extern "C" fn key_at_pos(c: *mut RedisModuleCtx, pos: c_int) {
+    unsafe { ctx(c) }.keys.as_mut().expect("not a keys position request").push(pos as usize);
+}
+
+extern "C" fn block_client(
+    c: *mut RedisModuleCtx,
+    reply_callback: Option<RedisModuleCmdFunc>,
+    timeout_callback: Option<RedisModuleCmdFunc>,
+    free_privdata: Option<extern "C" fn(*mut c_void)>,
+    timeout_ms: c_longlong,
+) -> *mut RedisModuleBlockedClient {
+    let c = unsafe { ctx(c) };
+    c.blocked = true;
+    let bc = Box::into_raw(Box::new(Blocked {
+        client: CLIENT_ID.load(Ordering::SeqCst),
+        command: c.command.clone(),
+        reply_callback,
+        timeout_callback,
+        free_privdata,
+        privdata: ptr::null_mut(),
+        deadline: if timeout_ms > 0 { Some(NOW_MS.load(Ordering::SeqCst) + timeout_ms) } else { None },
+        unblocked: false,
+        gone: false,
+    }));
+    unsafe { &mut *c.server }.blocked.push(bc);
+    bc as *mut RedisModuleBlockedClient
+}
+
+extern "C" fn unblock_client(bc: *mut RedisModuleBlockedClient, privdata: *mut c_void) -> Status {
+    let bc = unsafe { &mut *(bc as *mut Blocked) };
+    assert!(!bc.unblocked, "client {} unblocked twice", bc.client);
+    bc.unblocked = true;
+    bc.privdata = privdata;
+    Status::Ok
+}
+
+extern "C" fn create_string(_ctx: *mut RedisModuleCtx, ptr: *const u8, len: size_t) -> *mut RedisModuleString {
+    new_string(unsafe { slice::from_raw_parts(ptr, len) })
+}
+
+extern "C" fn free_string(_ctx: *mut RedisModuleCtx, s: *mut RedisModuleString) {
+    drop(unsafe { Box::from_raw(s as *mut Vec<u8>) })
+}
+
+extern "C" fn string_ptr_len(s: *mut RedisModuleString, len: *mut size_t) -> *const u8 {
+    let bytes = unsafe { string_bytes(s) };
+    if !len.is_null() {
+        unsafe { *len = bytes.len() };
+    }
+    bytes.as_ptr()
+}
+
+extern "C" fn string_to_long_long(s: *mut RedisModuleString, ll: *mut c_longlong) -> Status {
+    match std::str::from_utf8(unsafe { string_bytes(s) }).ok().and_then(|s| s.parse().ok()) {
+        Some(v) => {
+            unsafe { *ll = v };
+            Status::Ok
+        }
+        None => Status::Err,
+    }
+}
+
+extern "C" fn string_to_double(s: *mut RedisModuleString, d: *mut c_double) -> Status {
+    match std::str::from_utf8(unsafe { string_bytes(s) }).ok().and_then(|s| s.parse::<f64>().ok()) {
+        Some(v) if !v.is_nan() => {
+            unsafe { *d = v };
+            Status::Ok
+        }
+        _ => Status::Err,
+    }
+}
+
+// Like Redis, hands out a null key when a missing key is opened for reading.
+extern "C" fn open_key(ctx: *mut RedisModuleCtx, name: *mut RedisModuleString, mode: KeyMode) -> *mut RedisModuleKey {
+    let server = unsafe { self::ctx(ctx).server };
+    let name = unsafe { string_bytes(name) }.to_vec();
+    let writable = mode.contains(KeyMode::WRITE);
+    if !writable && !unsafe { (*server).keys.contains_key(&name) } {
+        return ptr::null_mut()
+    }
+    Box::into_raw(Box::new(Key { server, name, writable, range: VecDeque::new() })) as *mut RedisModuleKey
+}
+
+extern "C" fn close_key(k: *mut RedisModuleKey) {
+    if !k.is_null() {
+        drop(unsafe { Box::from_raw(k as *mut Key) })
+    }
+}
+
+extern "C" fn key_type(k: *mut RedisModuleKey) -> KeyType {
+    if k.is_null() {
+        return KeyType::Empty
+    }
+    match unsafe { key_value(k) } {
+        None => KeyType::Empty,
+        Some(&mut Value::Hash(_)) => KeyType::Hash,
+        Some(&mut Value::Zset(_)) => KeyType::Zset,
+        Some(&mut Value::Module(..)) => KeyType::Module,
+    }
+}
+
+extern "C" fn delete_key(k: *mut RedisModuleKey) -> Status {
+    let k = unsafe { key(k) };
+    if !k.writable {
+        return Status::Err
+    }
+    let server = unsafe { &mut *k.server };
+    if let Some(value) = server.keys.remove(&k.name) {
+        drop_value(value);
+    }
+    Status::Ok
+}
+
+extern "C" fn module_type_set_value(k: *mut RedisModuleKey, mt: *mut RedisModuleType, value: *mut c_void) -> Status {
+    let k = unsafe { key(k) };
+    if !k.writable {
+        return Status::Err
+    }
+    let server = unsafe { &mut *k.server };
+    server.set_value(&k.name, Value::Module(mt as *mut ModuleType, value));
+    Status::Ok
+}
+
+extern "C" fn module_type_get_type(k: *mut RedisModuleKey) -> *mut RedisModuleType {
+    match unsafe { key_value(k) } {
+        Some(&mut Value::Module(mt, _)) => mt as *mut RedisModuleType,
+        _ => ptr::null_mut(),
+    }
+}
+
+extern "C" fn module_type_get_value(k: *mut RedisModuleKey) -> *mut c_void {
+    match unsafe { key_value(k) } {
+        Some(&mut Value::Module(_, value)) => value,
+        _ => ptr::null_mut(),
+    }
+}
+
+extern "C" fn zset_add(k: *mut RedisModuleKey, score: c_double, ele: *mut RedisModuleString, _flags: *mut c_int) -> Status {
+    let k = unsafe { key(k) };
+    let server = unsafe { &mut *k.server };
+    if !k.writable {
+        return Status::Err
+    }
+    let zset = server.keys.entry(k.name.clone()).or_insert_with(|| Value::Zset(BTreeMap::new()));
+    match *zset {
+        Value::Zset(ref mut members) => {
+            members.insert(unsafe { string_bytes(ele) }.to_vec(), score);
+            Status::Ok
+        }
+        _ => Status::Err,
+    }
+}
+
+// One field of RedisModule_HashSet, as handed over by MockRedis_HashSet.
+// Returns 1 if the field existed, which is what Redis 4 counts.
+extern "C" fn hash_set(k: *mut RedisModuleKey, flags: c_int, field: *mut RedisModuleString, value: *mut RedisModuleString) -> c_int {
+    const NX: c_int = 1;
+    const XX: c_int = 1 << 1;
+    const DELETE: usize = 1;
+    let k = unsafe { key(k) };
+    let server = unsafe { &mut *k.server };
+    if !k.writable {
+        return 0
+    }
+    let field = unsafe { string_bytes(field) }.to_vec();
+    if value as usize == DELETE {
+        return match server.keys.get_mut(&k.name) {
+            Some(Value::Hash(fields)) => fields.remove(&field).is_some() as c_int,
+            _ => 0,
+        }
+    }
+    let hash = server.keys.entry(k.name.clone()).or_insert_with(|| Value::Hash(BTreeMap::new()));
+    match *hash {
+        Value::Hash(ref mut fields) => {
+            let exists = fields.contains_key(&field);
+            if (exists && flags & NX != 0) || (!exists && flags & XX != 0) {
+                return 0
+            }
+            fields.insert(field, unsafe { string_bytes(value) }.to_vec());
+            exists as c_int
+        }
+        _ => 0,
+    }
+}
+
+// One field of RedisModule_HashGet: `out` is an int* with the EXISTS flag and
+// a RedisModuleString** otherwise.
+extern "C" fn hash_get(k: *mut RedisModuleKey, flags: c_int, field: *mut RedisModuleString, out: *mut c_void) -> Status {
+    const EXISTS: c_int = 1 << 3;
+    let fields = match unsafe { key_value(k) } {
+        Some(&mut Value::Hash(ref fields)) => fields,
+        _ => return Status::Err,
+    };
+    let value = fields.get(unsafe { string_bytes(field) });
+    unsafe {
+        if flags & EXISTS != 0 {
+            *(out as *mut c_int) = value.is_some() as c_int;
+        } else {
+            *(out as *mut *mut RedisModuleString) = value.map_or(ptr::null_mut(), |v| new_string(v));
+        }
+    }
+    Status::Ok
+}
+
+extern "C" fn zset_rem(k: *mut RedisModuleKey, ele: *mut RedisModuleString, deleted: *mut c_int) -> Status {
+    if !unsafe { key(k) }.writable {
+        return Status::Err
+    }
+    let removed = match unsafe { key_value(k) } {
+        Some(&mut Value::Zset(ref mut members)) => members.remove(unsafe { string_bytes(ele) }).is_some(),
+        None => false,
+        _ => return Status::Err,
+    };
+    if !deleted.is_null() {
+        unsafe { *deleted = removed as c_int };
+    }
+    Status::Ok
+}
+
+extern "C" fn zset_first_in_score_range(
+    k: *mut RedisModuleKey,
+    min: c_double,
+    max: c_double,
+    minex: c_int,
+    maxex: c_int,
+) -> Status {
+    let mut range: Vec<(Vec<u8>, f64)> = match unsafe { key_value(k) } {
+        Some(&mut Value::Zset(ref members)) => members.iter()
+            .filter(|&(_, &s)| if minex != 0 { s > min } else { s >= min })
+            .filter(|&(_, &s)| if maxex != 0 { s < max } else { s <= max })
+            .map(|(m, &s)| (m.clone(), s))
+            .collect(),
+        _ => return Status::Err,
+    };
+    range.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+    unsafe { key(k) }.range = range.into_iter().collect();
+    Status::Ok
+}
+
+extern "C" fn zset_range_current_element(k: *mut RedisModuleKey, score: *mut c_double) -> *mut RedisModuleString {
+    match unsafe { key(k) }.range.front() {
+        Some(&(ref member, s)) => {
+            if !score.is_null() {
+                unsafe { *score = s };
+            }
+            new_string(member)
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+extern "C" fn zset_range_next(k: *mut RedisModuleKey) -> c_int {
+    let range = &mut unsafe { key(k) }.range;
+    range.pop_front();
+    !range.is_empty() as c_int
+}
+
+extern "C" fn zset_range_end_reached(k: *mut RedisModuleKey) -> c_int {
+    unsafe { key(k) }.range.is_empty() as c_int
+}
+
+extern "C" fn zset_range_stop(k: *mut RedisModuleKey) {
+    unsafe { key(k) }.range.clear()
+}
+
+// Replies with `reply`, unless the server makes replies fail.
+fn reply_scalar(c: *mut RedisModuleCtx, reply: Reply) -> Status {
+    let c = unsafe { ctx(c) };
+    if unsafe { &*c.server }.fail_replies {
+        c.reply(Reply::Error("ERR reply failed".to_string()));
+        return Status::Err
+    }
+    c.reply(reply);
+    Status::Ok
+}
+
+extern "C" fn reply_with_long_long(c: *mut RedisModuleCtx, ll: c_longlong) -> Status {
+    reply_scalar(c, Reply::Integer(ll))
+}
+
+extern "C" fn reply_with_error(c: *mut RedisModuleCtx, err: *const u8) {
+    let err = unsafe { CStr::from_ptr(err as *const c_char) }.to_string_lossy().into_owned();
+    unsafe { ctx(c) }.reply(Reply::Error(err));
+}
+
+extern "C" fn reply_with_simple_string(c: *mut RedisModuleCtx, msg: *const c_char) -> Status {
+    let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned();
+    reply_scalar(c, Reply::Simple(msg))
+}
+
+extern "C" fn reply_with_array(c: *mut RedisModuleCtx, len: c_long) -> Status {
+    let c = unsafe { ctx(c) };
+    let len = if len == raw::POSTPONED_ARRAY_LEN { None } else { Some(len as usize) };
+    c.open.push(OpenArray { len, items: Vec::new() });
+    c.close_arrays();
+    Status::Ok
+}
+
+extern "C" fn reply_set_array_length(c: *mut RedisModuleCtx, len: c_long) {
+    let c = unsafe { ctx(c) };
+    let array = c.open.iter_mut().rev().find(|a| a.len.is_none()).expect("no postponed array reply");
+    array.len = Some(len as usize);
+    c.close_arrays();
+}
+
+extern "C" fn reply_with_string_buffer(c: *mut RedisModuleCtx, buf: *const u8, len: size_t) -> Status {
+    let bytes = unsafe { slice::from_raw_parts(buf, len) }.to_vec();
+    reply_scalar(c, Reply::Bulk(bytes))
+}
+
+extern "C" fn reply_with_string(c: *mut RedisModuleCtx, s: *mut RedisModuleString) -> Status {
+    let bytes = unsafe { string_bytes(s) }.to_vec();
+    reply_scalar(c, Reply::Bulk(bytes))
+}
+
+extern "C" fn reply_with_null(c: *mut RedisModuleCtx) -> Status {
+    reply_scalar(c, Reply::Null)
+}
+
+extern "C" fn reply_with_double(c: *mut RedisModuleCtx, d: c_double) -> Status {
+    reply_scalar(c, Reply::Double(d))
+}
+
+extern "C" fn reply_with_call_reply(c: *mut RedisModuleCtx, reply: *mut RedisModuleCallReply) -> Status {
+    let reply = unsafe { call_reply(reply) }.clone();
+    unsafe { ctx(c) }.reply(reply);
+    Status::Ok
+}
+
+// The few commands the module calls. Anything else is unknown, for which
+// Redis returns a null reply.
+extern "C" fn call(
+    c: *mut RedisModuleCtx,
+    cmdname: *const c_char,
+    argv: *mut *mut RedisModuleString,
+    argc: size_t,
+) -> *mut RedisModuleCallReply {
+    let server = unsafe { &mut *ctx(c).server };
+    let command = unsafe { CStr::from_ptr(cmdname) }.to_string_lossy().to_uppercase();
+    // Key names are looked up as given, the rest is read as text.
+    let keys: Vec<&[u8]> = unsafe { slice::from_raw_parts(argv, argc) }.iter()
+        .map(|&a| unsafe { string_bytes(a) })
+        .collect();
+    let args: Vec<String> = keys.iter().map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+    let reply = match (command.as_str(), args.len()) {
+        ("PUBLISH", 2) => if let Some(ref err) = server.publish_error {
+            Reply::Error(err.clone())
+        } else {
+            server.published.push((args[0].clone(), args[1].clone()));
+            Reply::Integer(0)
+        }
+        ("CLIENT", 1) if args[0].eq_ignore_ascii_case("list") => Reply::Bulk(server.clients.iter()
+            .filter(|id| !server.disconnected.contains(id))
+            .map(|id| format!("id={} addr=127.0.0.1:{} fd=8 name= db=0 cmd=client\n", id, 40000 + id))
+            .collect::<String>()
+            .into_bytes()),
+        ("EXISTS", _) => Reply::Integer(keys.iter().filter(|&&k| server.keys.contains_key(k)).count() as i64),
+        ("HGETALL", 1) => match server.keys.get(keys[0]) {
+            Some(Value::Hash(fields)) => Reply::Array(fields.iter()
+                .flat_map(|(f, v)| vec![Reply::Bulk(f.clone()), Reply::Bulk(v.clone())])
+                .collect()),
+            Some(_) => Reply::Error(raw::ERRORMSG_WRONGTYPE.to_string()),
+            None => Reply::Array(Vec::new()),
+        },
+        ("HVALS", 1) => match server.keys.get(keys[0]) {
+            Some(Value::Hash(fields)) => Reply::Array(fields.values().map(|v| Reply::Bulk(v.clone())).collect()),
+            Some(_) => Reply::Error(raw::ERRORMSG_WRONGTYPE.to_string()),
+            None => Reply::Array(Vec::new()),
+        },
+        // The cursor is an offset in the sorted keys, so iterations are
+        // stable as long as no key is added or removed.
+        ("SCAN", _) => {
+            let cursor = match args[0].parse::<usize>() {
+                Ok(cursor) => cursor,
+                Err(_) => return new_call_reply(Reply::Error("ERR invalid cursor".to_string())),
+            };
+            let (mut pattern, mut count) = (None, 10);
+            for option in args[1..].chunks(2) {
+                match (option[0].to_uppercase().as_str(), option.get(1)) {
+                    ("MATCH", Some(p)) => pattern = Some(p.clone()),
+                    ("COUNT", Some(n)) => count = n.parse().unwrap_or(10),
+                    _ => return new_call_reply(Reply::Error("ERR syntax error".to_string())),
+                }
+            }
+            let mut keys: Vec<&Vec<u8>> = server.keys.keys().collect();
+            keys.sort();
+            let end = cmp::min(cursor + count, keys.len());
+            let next = if end >= keys.len() { 0 } else { end };
+            let found = keys.get(cursor..end).unwrap_or(&[]).iter()
+                .filter(|k| pattern.as_ref().is_none_or(|p| ::glob::matches(p.as_bytes(), k, false)))
+                .map(|&k| Reply::Bulk(k.clone()))
+                .collect();
+            Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(found)])
+        }
+        _ => return ptr::null_mut(),
+    };
+    new_call_reply(reply)
+}
+
+extern "C" fn replicate(
+    c: *mut RedisModuleCtx,
+    cmdname: *const c_char,
+    argv: *mut *mut RedisModuleString,
+    argc: size_t,
+) -> Status {
+    let server = unsafe { &mut *ctx(c).server };
+    let mut command = vec![unsafe { CStr::from_ptr(cmdname) }.to_string_lossy().into_owned()];
+    command.extend(unsafe { slice::from_raw_parts(argv, argc) }.iter()
+        .map(|&a| String::from_utf8_lossy(unsafe { string_bytes(a) }).into_owned()));
+    server.replicated.push(command);
+    Status::Ok
+}
+
+extern "C" fn replicate_verbatim(c: *mut RedisModuleCtx) -> Status {
+    let c = unsafe { ctx(c) };
+    unsafe { &mut *c.server }.replicated.push(c.command.clone());
+    Status::Ok
+}
+
+extern "C" fn free_call_reply(reply: *mut RedisModuleCallReply) {
+    drop(unsafe { Box::from_raw(reply as *mut Reply) })
+}
+
+extern "C" fn call_reply_type(reply: *mut RedisModuleCallReply) -> ReplyType {
+    match *unsafe { call_reply(reply) } {
+        Reply::Simple(_) | Reply::Bulk(_) | Reply::Double(_) => ReplyType::String,
+        Reply::Error(_) => ReplyType::Error,
+        Reply::Integer(_) => ReplyType::Integer,
+        Reply::Array(_) => ReplyType::Array,
+        Reply::Null => ReplyType::Nil,
+    }
+}
+
+extern "C" fn call_reply_integer(reply: *mut RedisModuleCallReply) -> c_longlong {
+    match *unsafe { call_reply(reply) } {
+        Reply::Integer(i) => i,
+        _ => 0,
+    }
+}
+
+extern "C" fn call_reply_string_ptr(reply: *mut RedisModuleCallReply, len: *mut size_t) -> *const u8 {
+    let bytes: &[u8] = match *unsafe { call_reply(reply) } {
+        Reply::Simple(ref s) | Reply::Error(ref s) => s.as_bytes(),
+        Reply::Bulk(ref b) => b,
+        _ => return ptr::null(),
+    };
+    if !len.is_null() {
+        unsafe { *len = bytes.len() };
+    }
+    bytes.as_ptr()
+}
+
+extern "C" fn call_reply_length(reply: *mut RedisModuleCallReply) -> size_t {
+    match *unsafe { call_reply(reply) } {
+        Reply::Simple(ref s) | Reply::Error(ref s) => s.len(),
+        Reply::Bulk(ref b) => b.len(),
+        Reply::Array(ref items) => items.len(),
+        _ => 0,
+    }
+}
+
+extern "C" fn call_reply_array_element(reply: *mut RedisModuleCallReply, idx: size_t) -> *mut RedisModuleCallReply {
+    match *unsafe { call_reply(reply) } {
+        Reply::Array(ref mut items) if idx < items.len() => &mut items[idx] as *mut Reply as *mut RedisModuleCallReply,
+        _ => ptr::null_mut(),
+    }
+}
+
+extern "C" fn emit_aof(
+    io: *mut RedisModuleIO,
+    cmdname: *const c_char,
+    bufs: *const *const u8,
+    lens: *const size_t,
+    argc: size_t,
+) {
+    let mut command = vec![unsafe { CStr::from_ptr(cmdname) }.to_bytes().to_vec()];
+    let (bufs, lens) = unsafe { (slice::from_raw_parts(bufs, argc), slice::from_raw_parts(lens, argc)) };
+    command.extend(bufs.iter().zip(lens).map(|(&buf, &len)| unsafe { slice::from_raw_parts(buf, len) }.to_vec()));
+    unsafe { self::io(io) }.aof.push(command);
+}
+
+extern "C" fn get_context_from_io(io: *mut RedisModuleIO) -> *mut RedisModuleCtx {
+    let io = unsafe { self::io(io) };
+    let server = io.server;
+    io.ctx.get_or_insert_with(|| Box::new(Ctx::new(unsafe { &mut *server }))).as_raw()
+}
+
+extern "C" fn save_unsigned(io: *mut RedisModuleIO, value: u64) {
+    unsafe { self::io(io) }.rdb.push_back(RdbValue::Unsigned(value));
+}
+
+extern "C" fn save_signed(io: *mut RedisModuleIO, value: i64) {
+    unsafe { self::io(io) }.rdb.push_back(RdbValue::Signed(value));
+}
+
+extern "C" fn save_double(io: *mut RedisModuleIO, value: c_double) {
+    unsafe { self::io(io) }.rdb.push_back(RdbValue::Double(value));
+}
+
+extern "C" fn save_string_buffer(io: *mut RedisModuleIO, buf: *const u8, len: size_t) {
+    let bytes = unsafe { slice::from_raw_parts(buf, len) }.to_vec();
+    unsafe { self::io(io) }.rdb.push_back(RdbValue::Buffer(bytes));
+}
+
+// Loading something that isn't next in the RDB counts as an error, which makes
+// `Server::restore` fail, rather than bringing the test process down like a
+// bad RDB file would bring Redis down.
+extern "C" fn load_unsigned(io: *mut RedisModuleIO) -> u64 {
+    let io = unsafe { self::io(io) };
+    match io.rdb.pop_front() {
+        Some(RdbValue::Unsigned(v)) => v,
+        _ => { io.errors += 1; 0 }
+    }
+}
+
+extern "C" fn load_signed(io: *mut RedisModuleIO) -> i64 {
+    let io = unsafe { self::io(io) };
+    match io.rdb.pop_front() {
+        Some(RdbValue::Signed(v)) => v,
+        _ => { io.errors += 1; 0 }
+    }
+}
+
+extern "C" fn load_double(io: *mut RedisModuleIO) -> c_double {
+    let io = unsafe { self::io(io) };
+    match io.rdb.pop_front() {
+        Some(RdbValue::Double(v)) => v,
+        _ => { io.errors += 1; 0.0 }
+    }
+}
+
+// Like Redis, hands out a copy allocated for the module to free.
+extern "C" fn load_string_buffer(io: *mut RedisModuleIO, len: *mut size_t) -> *mut u8 {
+    let io = unsafe { self::io(io) };
+    let bytes = match io.rdb.pop_front() {
+        Some(RdbValue::Buffer(bytes)) => bytes,
+        _ => { io.errors += 1; Vec::new() }
+    };
+    unsafe {
+        let buf = libc::malloc(cmp::max(bytes.len(), 1)) as *mut u8;
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+        *len = bytes.len();
+        buf
+    }
+}
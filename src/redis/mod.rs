@@ -1,17 +1,77 @@
+pub mod allocator;
+#[cfg(test)]
+pub mod mock;
 pub mod raw;
+mod reply;
+pub mod stats;
+pub use self::reply::ArrayReply;
 use error::ColorError;
-use libc::{c_char, c_void, c_int, c_long, c_longlong, size_t};
-use std::error::Error;
-use std::iter;
+use libc::{c_void, c_int, c_longlong, size_t};
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::panic;
+use std::process;
 use std::ptr;
-use std::string;
-use std::ffi::{CString};
-use time;
+use std::slice;
+use std::str;
+use std::str::FromStr;
 
-static mut COLOR_TYPE: *mut raw::RedisModuleType = 0 as *mut raw::RedisModuleType;
+pub static mut COLOR_TYPE: *mut raw::RedisModuleType = ptr::null_mut();
+pub static COLOR_STATS: stats::TypeStats = stats::TypeStats::new();
+pub static mut THEME_TYPE: *mut raw::RedisModuleType = ptr::null_mut();
+pub static THEME_STATS: stats::TypeStats = stats::TypeStats::new();
+
+// Whether the running command wrote to a key, see `harness`.
+thread_local!(static WROTE: Cell<bool> = const { Cell::new(false) });
+
+fn mark_written() {
+    WROTE.with(|w| w.set(true))
+}
+
+/// A value held by keys of one of our module types.
+///
+/// Redis keeps a pointer for each value, which it hands back to the type's
+/// callbacks. By default the pointer is to a box holding the value, but a type
+/// can keep small values in the pointer itself, see `into_raw`.
+pub trait ModuleValue: Clone {
+    /// The module type, as created when the module was loaded.
+    fn module_type() -> *mut raw::RedisModuleType;
+
+    /// Where values of the type are counted, see `stats`.
+    fn stats() -> &'static stats::TypeStats;
+
+    /// Bytes the value takes up in memory. A value kept in its pointer takes
+    /// up none.
+    fn memory(&self) -> usize;
+
+    /// Turns the value into the pointer Redis keeps for it.
+    fn into_raw(self) -> *mut c_void {
+        Box::into_raw(Box::new(self)) as *mut c_void
+    }
+
+    /// Takes back the value behind a pointer made by `into_raw`, e.g. to
+    /// free it.
+    unsafe fn from_raw(raw: *mut c_void) -> Self {
+        *Box::from_raw(raw as *mut Self)
+    }
+
+    /// The value behind a pointer made by `into_raw`, borrowed when it's
+    /// boxed and rebuilt when it's kept in the pointer.
+    unsafe fn get<'a>(raw: *mut c_void) -> Cow<'a, Self> {
+        Cow::Borrowed(&*(raw as *const Self))
+    }
+
+    /// The value behind a pointer made by `into_raw` for changing in place,
+    /// or `None` when it's kept in the pointer.
+    unsafe fn get_mut<'a>(raw: *mut c_void) -> Option<&'a mut Self> {
+        Some(&mut *(raw as *mut Self))
+    }
+}
 
 /// `LogLevel` is a level of logging to be specified with a Redis log directive.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LogLevel {
     Debug,
     Notice,
@@ -19,55 +79,328 @@ pub enum LogLevel {
     Warning,
 }
 
+impl LogLevel {
+    // Redis' order of levels, from the chattiest up.
+    fn severity(self) -> u8 {
+        match self {
+            LogLevel::Debug => 0,
+            LogLevel::Verbose => 1,
+            LogLevel::Notice => 2,
+            LogLevel::Warning => 3,
+        }
+    }
+}
+
+/// The least severe level the module logs at. Messages below it are dropped
+/// before they reach Redis, which then applies its own `loglevel`.
+pub static mut LOG_LEVEL: LogLevel = LogLevel::Debug;
+
+fn log(ctx: *mut raw::RedisModuleCtx, level: LogLevel, message: &str) {
+    if level.severity() < unsafe { LOG_LEVEL }.severity() {
+        return
+    }
+    raw::log(ctx, format!("{:?}\0", level).to_lowercase().as_ptr(), format!("{}\0", message).as_ptr());
+}
+
+// Note that we log our debug messages as notice level in Redis. This is so
+// that they'll show up with default configuration. Our debug logging will get
+// compiled out in a release build so this won't result in undue noise in
+// production. LOG_LEVEL still treats them as debug messages.
+fn debug_enabled() -> bool {
+    unsafe { LOG_LEVEL == LogLevel::Debug }
+}
+
 /// Reply represents the various types of a replies that we can receive after
 /// executing a Redis command.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Reply {
-    Array,
-    Error,
+    Array(Vec<Reply>),
+    Error(String),
     Integer(i64),
     Nil,
-    String(String),
+    /// A bulk or status string, as the bytes Redis holds: key names and
+    /// values needn't be UTF-8.
+    String(Vec<u8>),
     Unknown,
 }
+bitflags! {
+    /// Flags a command is registered with. See the Redis module API
+    /// documentation for what each of them means.
+    pub struct CommandFlags: u32 {
+        const WRITE = 1;
+        const READONLY = (1 << 1);
+        const ADMIN = (1 << 2);
+        const DENY_OOM = (1 << 3);
+        const DENY_SCRIPT = (1 << 4);
+        const ALLOW_LOADING = (1 << 5);
+        const PUBSUB = (1 << 6);
+        const RANDOM = (1 << 7);
+        const ALLOW_STALE = (1 << 8);
+        const NO_MONITOR = (1 << 9);
+        const FAST = (1 << 10);
+        /// The command tells where its keys are itself, see `Command::keys`.
+        const GETKEYS_API = (1 << 11);
+    }
+}
+
+impl CommandFlags {
+    /// Renders the flags as the space separated string `CreateCommand` wants.
+    pub fn to_str_flags(self) -> String {
+        let names = [
+            (CommandFlags::WRITE, "write"),
+            (CommandFlags::READONLY, "readonly"),
+            (CommandFlags::ADMIN, "admin"),
+            (CommandFlags::DENY_OOM, "deny-oom"),
+            (CommandFlags::DENY_SCRIPT, "deny-script"),
+            (CommandFlags::ALLOW_LOADING, "allow-loading"),
+            (CommandFlags::PUBSUB, "pubsub"),
+            (CommandFlags::RANDOM, "random"),
+            (CommandFlags::ALLOW_STALE, "allow-stale"),
+            (CommandFlags::NO_MONITOR, "no-monitor"),
+            (CommandFlags::FAST, "fast"),
+            (CommandFlags::GETKEYS_API, "getkeys-api"),
+        ];
+        names.iter()
+            .filter(|&&(flag, _)| self.contains(flag))
+            .map(|&(_, name)| name)
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+/// `KeySpec` tells Redis where a command's key arguments are: the first and
+/// last argument positions holding a key and the step between them. Cluster
+/// mode and the COMMAND command use it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySpec {
+    pub first: i32,
+    pub last: i32,
+    pub step: i32,
+}
+
+impl KeySpec {
+    /// For commands that don't take key arguments.
+    pub const NONE: KeySpec = KeySpec { first: 0, last: 0, step: 0 };
+
+    /// For commands whose only key is their first argument.
+    pub const FIRST: KeySpec = KeySpec { first: 1, last: 1, step: 1 };
+}
+
+/// How the effect of a command reaches replicas and the AOF.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replication {
+    /// Not at all, for commands that don't write.
+    None,
+
+    /// As the command was called, once it succeeded or as soon as it wrote
+    /// anything. Right for deterministic commands, which do the same wherever
+    /// they run, failures included.
+    Verbatim,
+
+    /// Through what the command itself propagates with `Redis::replicate`.
+    /// Commands whose effect depends on the clock or on chance use this to
+    /// propagate the values they settled on, so that replicas and the AOF
+    /// end up holding the same. They replicate right after writing, before
+    /// anything else that could fail.
+    Explicit,
+}
+
 /// Command is a basic trait for a new command to be registered with a Redis
 /// module.
 pub trait Command {
     // Should return the name of the command to be registered.
     fn name(&self) -> &'static str;
 
-    // Run the command.
-    fn run(&self, r: Redis, args: &[&str]) -> Result<(), ColorError>;
+    // Number of arguments including the command name, following the Redis
+    // convention: a positive arity is exact, a negative one is a minimum.
+    fn arity(&self) -> i32;
 
-    // Should return any flags to be registered with the name as a string
-    // separated list. See the Redis module API documentation for a complete
-    // list of the ones that are available.
-    fn str_flags(&self) -> &'static str;
+    // Should return the flags to register the command with.
+    fn flags(&self) -> CommandFlags;
+
+    // Where the command's keys are in its arguments.
+    fn key_spec(&self) -> KeySpec { KeySpec::FIRST }
+
+    // For commands flagged GETKEYS_API, whose keys can't all be described by
+    // a `KeySpec`: the positions of the keys in `args`, the command name
+    // being at 0. Must not fail, arguments that don't parse are left out.
+    fn keys(&self, _args: &[RedisStr]) -> Vec<usize> { Vec::new() }
+
+    // How the command is replicated. Write commands are replicated verbatim
+    // unless they say otherwise.
+    fn replication(&self) -> Replication {
+        if self.flags().contains(CommandFlags::WRITE) { Replication::Verbatim } else { Replication::None }
+    }
+
+    // Run the command. `args` are the arguments as Redis passed them,
+    // starting with the command name; see `RedisStr` for how to read them.
+    fn run(&self, r: Redis, args: &[RedisStr]) -> Result<(), ColorError>;
 }
 
-impl Command {
-    /// Provides a basic wrapper for a command's implementation that parses
-    /// arguments to Rust data types and handles the OK/ERR reply back to Redis.
+impl dyn Command {
+    /// Provides a basic wrapper for a command's implementation that checks
+    /// its arity and handles the OK/ERR reply back to Redis.
     pub fn harness(
-        command: &Command,
+        command: &dyn Command,
+        ctx: *mut raw::RedisModuleCtx,
+        argv: *mut *mut raw::RedisModuleString,
+        argc: c_int,
+    ) -> raw::Status {
+        <dyn Command>::run_in_harness(command, ctx, argv, argc, true)
+    }
+
+    /// Like `harness`, for the reply and timeout callbacks of a client the
+    /// command blocked. The call isn't counted again in the command's stats:
+    /// it already was when the client blocked.
+    pub fn callback_harness(
+        command: &dyn Command,
+        ctx: *mut raw::RedisModuleCtx,
+        argv: *mut *mut raw::RedisModuleString,
+        argc: c_int,
+    ) -> raw::Status {
+        <dyn Command>::run_in_harness(command, ctx, argv, argc, false)
+    }
+
+    fn run_in_harness(
+        command: &dyn Command,
         ctx: *mut raw::RedisModuleCtx,
         argv: *mut *mut raw::RedisModuleString,
         argc: c_int,
+        counted: bool,
     ) -> raw::Status {
         let r = Redis { ctx };
-        let args = parse_args(argv, argc).unwrap();
-        let str_args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
-        match command.run(r, str_args.as_slice()) {
+        let args = parse_args(argv, argc);
+        if command.flags().contains(CommandFlags::GETKEYS_API) && raw::is_keys_position_request(ctx) {
+            let what = format!("keys of command {}", command.name());
+            return match catch_panic(&what, PanicLog::Ctx(ctx), || command.keys(&args)) {
+                Some(keys) => {
+                    for pos in keys {
+                        raw::key_at_pos(ctx, pos as c_int);
+                    }
+                    raw::Status::Ok
+                }
+                None => raw::Status::Err,
+            }
+        }
+        reply::reset();
+        WROTE.with(|w| w.set(false));
+        let arity = command.arity();
+        let arity_ok = if arity >= 0 { argc == arity } else { argc >= -arity };
+        let res = if arity_ok {
+            let what = format!("command {}", command.name());
+            catch_panic(&what, PanicLog::Ctx(ctx), || command.run(r, args.as_slice()))
+                .unwrap_or_else(|| Err(error!("{} crashed, see the Redis log", command.name())))
+        } else {
+            Err(ColorError::WrongArity(command.name().to_string()))
+        };
+        if counted {
+            stats::record(command.name(), &res);
+        }
+        // A command that fails after writing still changed the dataset, and
+        // replicas and the AOF have to see that change as well.
+        if command.replication() == Replication::Verbatim && (res.is_ok() || WROTE.with(|w| w.get())) {
+            raw::replicate_verbatim(ctx);
+        }
+        match res {
             Ok(_) => raw::Status::Ok,
+            // A command that fails halfway through its reply can't send an
+            // error on top of it, so the error only goes to the log.
+            Err(e) if reply::replied() => {
+                PanicLog::Ctx(ctx).log(&format!("{} failed after replying: {}", command.name(), e));
+                raw::Status::Err
+            }
             Err(e) => {
-                raw::reply_with_error(
-                    ctx,
-                    format!("Color error: {}\0", e.description()).as_ptr(),
-                );
+                raw::reply_with_error(ctx, format!("{}\0", e.reply()).as_ptr());
                 raw::Status::Err
             }
         }
     }
+
+    /// Registers `command` with Redis under `name`, to be served by
+    /// `handler`, which is expected to call `harness` with it. See
+    /// `commands!`.
+    pub fn register(
+        command: &dyn Command,
+        name: &str,
+        ctx: *mut raw::RedisModuleCtx,
+        handler: raw::RedisModuleCmdFunc,
+    ) -> raw::Status {
+        let keys = command.key_spec();
+        raw::create_command(
+            ctx,
+            format!("{}\0", name).as_ptr(),
+            Some(handler),
+            format!("{}\0", command.flags().to_str_flags()).as_ptr(),
+            keys.first,
+            keys.last,
+            keys.step,
+        )
+    }
+}
+
+/// What to do when a command or one of our type's callbacks panics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PanicPolicy {
+    /// Log the panic and carry on: commands reply with an error, callbacks
+    /// return what Redis takes as a failure.
+    Contain,
+
+    /// Log the panic and abort the server, for those who would rather
+    /// restart than keep running after a bug was hit.
+    Abort,
+}
+
+pub static mut PANIC_POLICY: PanicPolicy = PanicPolicy::Contain;
+
+/// Where to log a panic, depending on what the panicking code was given by
+/// Redis.
+#[derive(Clone, Copy, Debug)]
+pub enum PanicLog {
+    Ctx(*mut raw::RedisModuleCtx),
+    Io(*mut raw::RedisModuleIO),
+
+    // Some callbacks (free, mem_usage) get no handle to log through at all.
+    Stderr,
+}
+
+impl PanicLog {
+    fn log(self, message: &str) {
+        let level = format!("{:?}\0", LogLevel::Warning).to_lowercase();
+        let message = format!("{}\0", message.replace('\0', ""));
+        match self {
+            PanicLog::Ctx(ctx) => raw::log(ctx, level.as_ptr(), message.as_ptr()),
+            PanicLog::Io(io) => raw::log_io_error(io, level.as_ptr(), message.as_ptr()),
+            PanicLog::Stderr => eprintln!("{}", message.trim_end_matches('\0')),
+        }
+    }
+}
+
+/// Runs `f`, making sure a panic never unwinds into Redis, which is C and
+/// can't deal with it. Returns `None` if `f` panicked. Depending on
+/// `PANIC_POLICY` the process may be aborted instead, after logging `what`
+/// panicked and why.
+pub fn catch_panic<T, F: FnOnce() -> T>(what: &str, log: PanicLog, f: F) -> Option<T> {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(v) => Some(v),
+        Err(payload) => {
+            log.log(&format!("{} panicked: {}", what, panic_message(&payload)));
+            if unsafe { PANIC_POLICY } == PanicPolicy::Abort {
+                log.log("Aborting as configured by the panic policy");
+                process::abort();
+            }
+            None
+        }
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "unknown cause"
+    }
 }
 
 /// Redis is a structure that's designed to give us a high-level interface to
@@ -77,69 +410,54 @@ pub struct Redis {
 }
 
 impl Redis {
-    pub fn call(&self, command: &str, args: &[&str]) -> Result<Reply, ColorError> {
-        log_debug!(self, "{} [began] args = {:?}", command, args);
-
-        // We use a "format" string to tell redis what types we're passing in.
-        // Currently we just pass everything as a string so this is just the
-        // character "s" repeated as many times as we have arguments.
-        //
-        // It would be nice to start passing some parameters as their actual
-        // type (for example, i64s as long longs), but Redis stringifies these
-        // on the other end anyway so the practical benefit will be minimal.
-        let format: String = iter::repeat("s").take(args.len()).collect();
-
-        let terminated_args: Vec<RedisString> =
-            args.iter().map(|s| self.create_string(s)).collect();
-
-        // One would hope that there's a better way to handle a va_list than
-        // this, but I can't find it for the life of me.
-        let raw_reply = match args.len() {
-            1 => {
-                // WARNING: This is downright hazardous, but I've noticed that
-                // if I remove this format! from the line of invocation, the
-                // right memory layout doesn't make it into Redis (and it will
-                // reply with a -1 "unknown" to all calls). This is still
-                // unexplained and I need to do more legwork in understanding
-                // this.
-                //
-                // Still, this works fine and will continue to work as long as
-                // it's left unchanged.
-                raw::call1::call(
-                    self.ctx,
-                    format!("{}\0", command).as_ptr(),
-                    format!("{}\0", format).as_ptr(),
-                    terminated_args[0].str_inner,
-                )
-            }
-            2 => raw::call2::call(
-                self.ctx,
-                format!("{}\0", command).as_ptr(),
-                format!("{}\0", format).as_ptr(),
-                terminated_args[0].str_inner,
-                terminated_args[1].str_inner,
-            ),
-            3 => raw::call3::call(
-                self.ctx,
-                format!("{}\0", command).as_ptr(),
-                format!("{}\0", format).as_ptr(),
-                terminated_args[0].str_inner,
-                terminated_args[1].str_inner,
-                terminated_args[2].str_inner,
-            ),
-            _ => return Err(error!("Can't support that many CALL arguments")),
-        };
-
-        let reply_res = manifest_redis_reply(raw_reply);
-        raw::free_call_reply(raw_reply);
-
+    /// Runs a Redis command and decodes its reply. Arguments can be anything
+    /// that can be viewed as bytes, so binary data makes it through intact.
+    pub fn call<A: AsRef<[u8]>>(&self, command: &str, args: &[A]) -> Result<Reply, ColorError> {
+        let reply = self.call_reply(command, args)?;
+        let reply_res = reply.decode();
         if let Ok(ref reply) = reply_res {
             log_debug!(self, "{} [ended] result = {:?}", command, reply);
         }
-
         reply_res
     }
 
+    /// Runs a Redis command and hands back its undecoded reply, e.g. to
+    /// forward it to our own client with `reply_call_reply`.
+    pub fn call_reply<A: AsRef<[u8]>>(&self, command: &str, args: &[A]) -> Result<CallReply, ColorError> {
+        log_debug!(self, "{} [began] args = {}", command, args.len());
+
+        let redis_args: Vec<RedisString> =
+            args.iter().map(|a| RedisString::create_from_bytes(self.ctx, a.as_ref())).collect();
+        let raw_args: Vec<*mut raw::RedisModuleString> =
+            redis_args.iter().map(|a| a.str_inner).collect();
+
+        let reply_inner = raw::call(
+            self.ctx,
+            format!("{}\0", command).as_ptr(),
+            raw_args.as_ptr(),
+            raw_args.len(),
+        );
+        if reply_inner.is_null() {
+            return Err(error!("Unknown command or wrong number of arguments for {}", command))
+        }
+        Ok(CallReply { reply_inner })
+    }
+
+    /// Propagates `command` with `args` to replicas and the AOF in place of
+    /// the running command, which should use `Replication::Explicit`. When
+    /// called several times the commands are propagated together, wrapped
+    /// in MULTI/EXEC.
+    pub fn replicate<A: AsRef<[u8]>>(&self, command: &str, args: &[A]) -> Result<(), ColorError> {
+        let redis_args: Vec<RedisString> =
+            args.iter().map(|a| RedisString::create_from_bytes(self.ctx, a.as_ref())).collect();
+        let raw_args: Vec<*mut raw::RedisModuleString> =
+            redis_args.iter().map(|a| a.str_inner).collect();
+        handle_status(
+            raw::replicate(self.ctx, format!("{}\0", command).as_ptr(), raw_args.as_ptr(), raw_args.len()),
+            &format!("Could not replicate {}", command),
+        )
+    }
+
     /// Coerces a Redis string as an integer.
     ///
     /// Redis is pretty dumb about data types. It nominally supports strings
@@ -155,73 +473,112 @@ impl Redis {
         reply_res: Result<Reply, ColorError>,
     ) -> Result<Reply, ColorError> {
         match reply_res {
-            Ok(Reply::String(s)) => match s.parse::<i64>() {
-                Ok(n) => Ok(Reply::Integer(n)),
-                _ => Ok(Reply::String(s)),
+            Ok(Reply::String(s)) => match str::from_utf8(&s).ok().and_then(|s| s.parse::<i64>().ok()) {
+                Some(n) => Ok(Reply::Integer(n)),
+                None => Ok(Reply::String(s)),
             },
             _ => reply_res,
         }
     }
 
+    /// Blocks the calling client until it is unblocked with
+    /// `raw::unblock_client` (which leads to `reply_callback` being invoked)
+    /// or `timeout_ms` passes (`timeout_callback`). A timeout of 0 blocks
+    /// forever.
+    pub fn block_client(
+        &self,
+        reply_callback: raw::RedisModuleCmdFunc,
+        timeout_callback: raw::RedisModuleCmdFunc,
+        timeout_ms: i64,
+    ) -> Result<*mut raw::RedisModuleBlockedClient, ColorError> {
+        let bc = raw::block_client(
+            self.ctx,
+            Some(reply_callback),
+            Some(timeout_callback),
+            None,
+            timeout_ms as c_longlong,
+        );
+        if bc.is_null() {
+            return Err(error!("Could not block client"))
+        }
+        Ok(bc)
+    }
+
+    /// Returns the ID of the client that issued the current command.
+    pub fn client_id(&self) -> u64 {
+        raw::get_client_id(self.ctx)
+    }
+
+    /// Whether the current command is replayed by the server rather than sent
+    /// by a client: it comes from the master over the replication link, or
+    /// from the AOF while loading. Servers too old to tell are taken to run
+    /// client commands only.
+    pub fn is_replayed(&self) -> bool {
+        raw::get_context_flags(self.ctx)
+            .is_some_and(|flags| flags.intersects(raw::ContextFlags::REPLICATED | raw::ContextFlags::LOADING))
+    }
+
     pub fn create_string(&self, s: &str) -> RedisString {
         RedisString::create(self.ctx, s)
     }
 
     pub fn log(&self, level: LogLevel, message: &str) {
-        raw::log(
-            self.ctx,
-            format!("{:?}\0", level).to_lowercase().as_ptr(),
-            format!("{}\0", message).as_ptr(),
-        );
+        log(self.ctx, level, message)
     }
 
     pub fn log_debug(&self, message: &str) {
-        // Note that we log our debug messages as notice level in Redis. This
-        // is so that they'll show up with default configuration. Our debug
-        // logging will get compiled out in a release build so this won't
-        // result in undue noise in production.
-        self.log(LogLevel::Notice, message);
+        if debug_enabled() {
+            self.log(LogLevel::Notice, message)
+        }
     }
 
     /// Opens a Redis key for read access.
-    pub fn open_key(&self, key: &str) -> RedisKey {
-        RedisKey::open(self.ctx, key)
+    pub fn open_key<K: AsRef<[u8]>>(&self, key: K) -> RedisKey {
+        RedisKey::open(self.ctx, key.as_ref())
     }
 
     /// Opens a Redis key for read and write access.
-    pub fn open_key_writable(&self, key: &str) -> RedisKeyWritable {
-        RedisKeyWritable::open(self.ctx, key)
+    pub fn open_key_writable<K: AsRef<[u8]>>(&self, key: K) -> RedisKeyWritable {
+        RedisKeyWritable::open(self.ctx, key.as_ref())
     }
 
-    /// Tells Redis that we're about to reply with an (Redis) array.
-    ///
-    /// Used by invoking once with the expected length and then calling any
-    /// combination of the other reply_* methods exactly that number of times.
-    pub fn reply_array(&self, len: i64) -> Result<(), ColorError> {
-        handle_status(
-            raw::reply_with_array(self.ctx, len as c_long),
-            "Could not reply with long",
-        )
+    /// Starts replying with an array of `len` elements, to be written through
+    /// the returned `ArrayReply`.
+    pub fn reply_array(&self, len: usize) -> Result<ArrayReply<'_>, ColorError> {
+        reply::array(self.ctx, Some(len))
     }
-    
+
+    /// Starts replying with an array whose length isn't known yet. It is set
+    /// from the number of elements written when the array is finished.
+    pub fn reply_postponed_array(&self) -> Result<ArrayReply<'_>, ColorError> {
+        reply::array(self.ctx, None)
+    }
+
+    /// Replies with the reply of a command we called, exactly as Redis sent it
+    /// to us.
+    pub fn reply_call_reply(&self, reply: &CallReply) -> Result<(), ColorError> {
+        reply::call_reply(self.ctx, reply)
+    }
+
+    pub fn reply_null(&self) -> Result<(), ColorError> {
+        reply::null(self.ctx)
+    }
+
     pub fn reply_integer(&self, integer: i64) -> Result<(), ColorError> {
-        handle_status(raw::reply_with_long_long(self.ctx, integer as c_longlong), "Could not reply with longlong")
+        reply::integer(self.ctx, integer)
     }
 
-    pub fn reply_string(&self, message: &str) -> Result<(), ColorError> {
-        let redis_str = self.create_string(message);
-        handle_status(
-            raw::reply_with_string(self.ctx, redis_str.str_inner),
-            "Could not reply with string",
-        )
+    pub fn reply_double(&self, d: f64) -> Result<(), ColorError> {
+        reply::double(self.ctx, d)
+    }
+
+    /// Replies with a bulk string, which may hold any bytes.
+    pub fn reply_string<S: AsRef<[u8]>>(&self, message: S) -> Result<(), ColorError> {
+        reply::string(self.ctx, message.as_ref())
     }
 
     pub fn reply_simple_string(&self, message: &str) -> Result<(), ColorError> {
-        let cstr = CString::new(message).unwrap();
-        handle_status(
-            raw::reply_with_simple_string(self.ctx, cstr.as_ptr()),
-            "Could not reply with simple string",
-        )
+        reply::simple_string(self.ctx, message)
     }
 
 }
@@ -244,20 +601,25 @@ pub enum KeyMode {
 pub struct RedisKey {
     ctx:       *mut raw::RedisModuleCtx,
     key_inner: *mut raw::RedisModuleKey,
+
+    // Only kept for its Drop implementation, like in `RedisKeyWritable`.
+    #[allow(dead_code)]
     key_str:   RedisString,
 }
 
 impl RedisKey {
     pub fn log(&self, level: LogLevel, message: &str) {
-        raw::log(self.ctx, format!("{:?}\0", level).to_lowercase().as_ptr(), format!("{}\0", message).as_ptr());
+        log(self.ctx, level, message)
     }
 
     pub fn log_debug(&self, message: &str) {
-        self.log(LogLevel::Notice, message)
+        if debug_enabled() {
+            self.log(LogLevel::Notice, message)
+        }
     }
     
-    fn open(ctx: *mut raw::RedisModuleCtx, key: &str) -> RedisKey {
-        let key_str = RedisString::create(ctx, key);
+    fn open(ctx: *mut raw::RedisModuleCtx, key: &[u8]) -> RedisKey {
+        let key_str = RedisString::create_from_bytes(ctx, key);
         let key_inner = raw::open_key(ctx, key_str.str_inner, to_raw_mode(KeyMode::Read));
         RedisKey {
             ctx,
@@ -272,29 +634,96 @@ impl RedisKey {
         self.key_inner == null_key
     }
 
-    pub fn read(&self) -> Result<*mut super::Color, ColorError> {
+    /// Redis hands out a null key when a key opened for reading doesn't exist.
+    pub fn is_empty(&self) -> bool {
+        self.is_null() || self.key_type() == raw::KeyType::Empty
+    }
+
+    /// Whether the key holds a value of the module type of `T`.
+    pub fn holds<T: ModuleValue>(&self) -> bool {
+        !self.is_null()
+            && self.key_type() == raw::KeyType::Module
+            && self.module_key_type() == T::module_type()
+    }
+
+    /// The value held by the key, or `None` if the key is empty. Fails if the
+    /// key holds anything else than a `T`.
+    pub fn value<T: ModuleValue>(&self) -> Result<Option<Cow<'_, T>>, ColorError> {
+        if self.is_empty() {
+            return Ok(None)
+        }
+        if !self.holds::<T>() {
+            return Err(ColorError::WrongType)
+        }
+        Ok(Some(unsafe { T::get(raw::module_type_get_value(self.key_inner)) }))
+    }
+
+    pub fn read(&self) -> Result<Cow<'_, super::ColorValue>, ColorError> {
         if self.is_null() {
             return Err(error!("null"))
         }
-        let color = read_color_key(self.key_inner)?;
-        Ok(color)
+        self.value()?.ok_or_else(|| error!("Key holds no color"))
+    }
+
+    /// Returns the members of a sorted set key whose scores fall inside
+    /// [min, max], in ascending score order. An empty or missing key yields no
+    /// members.
+    pub fn zset_score_range(&self, min: f64, max: f64) -> Result<Vec<(Vec<u8>, f64)>, ColorError> {
+        let mut members = Vec::new();
+        if self.is_null() || self.key_type() == raw::KeyType::Empty {
+            return Ok(members)
+        }
+        if self.key_type() != raw::KeyType::Zset {
+            return Err(ColorError::WrongType)
+        }
+        if raw::zset_first_in_score_range(self.key_inner, min, max, false, false) == raw::Status::Err {
+            return Err(error!("Could not iterate sorted set"))
+        }
+        while !raw::zset_range_end_reached(self.key_inner) {
+            let mut score: f64 = 0.0;
+            let member = RedisString {
+                ctx: self.ctx,
+                str_inner: raw::zset_range_current_element(self.key_inner, &mut score),
+            };
+            members.push((redis_string_bytes(member.str_inner).to_vec(), score));
+            raw::zset_range_next(self.key_inner);
+        }
+        raw::zset_range_stop(self.key_inner);
+        Ok(members)
     }
 
-    // pub fn read_str(&self) -> Result<Option<String>, ColorError> {
-    //     let val = if self.is_null() {
-    //         None
-    //     } else {
-    //         Some(read_key(self.key_inner)?)
-    //     };
-    //     Ok(val)
-    // }
+    pub fn is_zset(&self) -> bool {
+        self.key_type() == raw::KeyType::Zset
+    }
+
+    pub fn is_hash(&self) -> bool {
+        self.key_type() == raw::KeyType::Hash
+    }
+
+    /// Returns the value of `field` in the hash stored at this key, or `None`
+    /// if either doesn't exist.
+    pub fn hash_get(&self, field: &[u8]) -> Result<Option<Vec<u8>>, ColorError> {
+        if self.is_empty() {
+            return Ok(None)
+        }
+        if !self.is_hash() {
+            return Err(ColorError::WrongType)
+        }
+        let field = RedisString::create_from_bytes(self.ctx, field);
+        let value = raw::hash_get(self.key_inner, field.str_inner);
+        if value.is_null() {
+            return Ok(None)
+        }
+        let value = RedisString { ctx: self.ctx, str_inner: value };
+        Ok(Some(redis_string_bytes(value.str_inner).to_vec()))
+    }
 
     pub fn valid_key_type(&self) -> bool {
         if self.key_type() != raw::KeyType::Module {
             self.log_debug("Key type is not Module");
             return false
         }
-        if self.module_key_type() != unsafe{COLOR_TYPE} {
+        if !self.holds::<super::ColorValue>() {
             self.log_debug("Key type is Module but not COLOR_TYPE");
             return false
         }
@@ -335,14 +764,16 @@ pub struct RedisKeyWritable {
 
 impl RedisKeyWritable {
     pub fn log(&self, level: LogLevel, message: &str) {
-        raw::log(self.ctx, format!("{:?}\0", level).to_lowercase().as_ptr(), format!("{}\0", message).as_ptr());
+        log(self.ctx, level, message)
     }
 
     pub fn log_debug(&self, message: &str) {
-        self.log(LogLevel::Notice, message)
+        if debug_enabled() {
+            self.log(LogLevel::Notice, message)
+        }
     }
-    fn open(ctx: *mut raw::RedisModuleCtx, key: &str) -> RedisKeyWritable {
-        let key_str = RedisString::create(ctx, key);
+    fn open(ctx: *mut raw::RedisModuleCtx, key: &[u8]) -> RedisKeyWritable {
+        let key_str = RedisString::create_from_bytes(ctx, key);
         let key_inner =
             raw::open_key(ctx, key_str.str_inner, to_raw_mode(KeyMode::ReadWrite));
         RedisKeyWritable {
@@ -356,25 +787,144 @@ impl RedisKeyWritable {
         self.key_type() == raw::KeyType::Empty
     }
 
-    // pub fn read(&self) -> Result<Option<String>, ColorError> {
-    //     Ok(Some(read_key(self.key_inner)?))
-    // }
+    /// Reads the color held by the key, or `None` if the key is empty. Callers
+    /// are expected to have checked the key type.
+    pub fn read(&self) -> Result<Option<super::ColorValue>, ColorError> {
+        Ok(self.value::<super::ColorValue>()?.map(Cow::into_owned))
+    }
+
+    /// Adds `member` to the sorted set stored at this key, or updates its score
+    /// if it is already there. The key is created if it doesn't exist.
+    pub fn zset_add(&self, score: f64, member: &[u8]) -> Result<(), ColorError> {
+        let member = RedisString::create_from_bytes(self.ctx, member);
+        mark_written();
+        handle_status(
+            raw::zset_add(self.key_inner, score, member.str_inner, ptr::null_mut()),
+            "Could not add to sorted set",
+        )
+    }
+
+    /// Removes `member` from the sorted set stored at this key. Returns
+    /// whether it was there.
+    pub fn zset_rem(&self, member: &[u8]) -> Result<bool, ColorError> {
+        if self.is_empty() {
+            return Ok(false)
+        }
+        let member = RedisString::create_from_bytes(self.ctx, member);
+        let mut deleted: c_int = 0;
+        mark_written();
+        handle_status(
+            raw::zset_rem(self.key_inner, member.str_inner, &mut deleted),
+            "Could not remove from sorted set",
+        )?;
+        Ok(deleted != 0)
+    }
+
+    pub fn is_zset(&self) -> bool {
+        self.key_type() == raw::KeyType::Zset
+    }
+
+    pub fn is_hash(&self) -> bool {
+        self.key_type() == raw::KeyType::Hash
+    }
+
+    /// Sets `field` to `value` in the hash stored at this key, creating the
+    /// hash if the key is empty. Returns whether the field is new.
+    pub fn hash_set(&self, field: &[u8], value: &[u8]) -> Result<bool, ColorError> {
+        if !self.is_empty() && !self.is_hash() {
+            return Err(ColorError::WrongType)
+        }
+        let field = RedisString::create_from_bytes(self.ctx, field);
+        let value = RedisString::create_from_bytes(self.ctx, value);
+        // What HashSet returns differs between Redis versions, so whether the
+        // field is new is asked beforehand.
+        let new = self.is_empty() || !raw::hash_exists(self.key_inner, field.str_inner);
+        mark_written();
+        raw::hash_set(self.key_inner, raw::HashFlags::NONE, field.str_inner, value.str_inner);
+        Ok(new)
+    }
+
+    /// Deletes whatever the key holds, leaving it empty.
+    pub fn delete(&self) -> Result<(), ColorError> {
+        mark_written();
+        handle_status(raw::delete_key(self.key_inner), "Could not delete key")
+    }
+
+    /// Writes a color over whatever color the key held, recording the write
+    /// by the calling client in the key's history (see
+    /// `ColorValue::recorded`). Returns the value as stored.
+    pub fn write(&self, value: super::ColorValue) -> Result<super::ColorValue, ColorError> {
+        self.write_as(value, milliseconds(), raw::get_client_id(self.ctx))
+    }
+
+    /// Like `write`, recording the write as made at `ms` by `client`, for
+    /// writes replayed from a master or the AOF.
+    pub fn write_as(&self, value: super::ColorValue, ms: i64, client: u64) -> Result<super::ColorValue, ColorError> {
+        let value = value.recorded(self.value()?.as_deref(), ms, client);
+        self.set_value(value.clone())?;
+        Ok(value)
+    }
+
+    /// Whether the key holds a value of the module type of `T`.
+    pub fn holds<T: ModuleValue>(&self) -> bool {
+        self.key_type() == raw::KeyType::Module && self.module_key_type() == T::module_type()
+    }
 
-    // pub fn set_expire(&self, expire: time::Duration) -> Result<(), ColorError> {
-    //     match raw::set_expire(self.key_inner, expire.num_milliseconds()) {
-    //         raw::Status::Ok => Ok(()),
+    /// The value held by the key, or `None` if the key is empty. Fails if the
+    /// key holds anything else than a `T`.
+    pub fn value<T: ModuleValue>(&self) -> Result<Option<Cow<'_, T>>, ColorError> {
+        if self.is_empty() {
+            return Ok(None)
+        }
+        if !self.holds::<T>() {
+            return Err(ColorError::WrongType)
+        }
+        Ok(Some(unsafe { T::get(raw::module_type_get_value(self.key_inner)) }))
+    }
 
-    //         // Error may occur if the key wasn't open for writing or is an
-    //         // empty key.
-    //         raw::Status::Err => Err(error!("Error while setting key expire")),
-    //     }
-    // }
+    /// Stores `value` at the key, replacing whatever it held. Redis frees the
+    /// previous value.
+    pub fn set_value<T: ModuleValue>(&self, value: T) -> Result<(), ColorError> {
+        let memory = value.memory();
+        let value_pt = value.into_raw();
+        mark_written();
+        match raw::module_type_set_value(self.key_inner, T::module_type(), value_pt) {
+            raw::Status::Ok => {
+                T::stats().created(memory);
+                Ok(())
+            }
+            raw::Status::Err => {
+                drop(unsafe { T::from_raw(value_pt) });
+                Err(error!("Error setting module type value"))
+            }
+        }
+    }
 
-    pub fn write(&self, color: &super::Color) -> Result<(), ColorError> {
-        let color_pt = Box::into_raw(Box::new(color));
-        match raw::module_type_set_value(self.key_inner, color_pt as *mut c_void) {
-            raw::Status::Ok => Ok(()),
-            raw::Status::Err => Err(error!("Error setting module type value")),
+    /// Changes the value held by the key in place with `f`. Fails if the key
+    /// doesn't hold a `T`.
+    ///
+    /// A value kept in its pointer can't be changed in place, so it's replaced
+    /// with the changed value instead, which like any `set_value` clears the
+    /// key's expiry.
+    pub fn update_value<T: ModuleValue, R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Result<R, ColorError> {
+        if !self.holds::<T>() {
+            return Err(ColorError::WrongType)
+        }
+        let value_pt = raw::module_type_get_value(self.key_inner);
+        match unsafe { T::get_mut(value_pt) } {
+            Some(value) => {
+                let before = value.memory();
+                mark_written();
+                let res = f(value);
+                T::stats().resized(before, value.memory());
+                Ok(res)
+            }
+            None => {
+                let mut value = unsafe { T::get(value_pt) }.into_owned();
+                let res = f(&mut value);
+                self.set_value(value)?;
+                Ok(res)
+            }
         }
     }
 
@@ -387,7 +937,7 @@ impl RedisKeyWritable {
             self.log_debug("Key type is not Module");
             return false
         }
-        if self.module_key_type() != unsafe{COLOR_TYPE} {
+        if !self.holds::<super::ColorValue>() {
             self.log_debug("Key type is Module but not COLOR_TYPE");
             return false
         }
@@ -409,6 +959,100 @@ impl Drop for RedisKeyWritable {
     }
 }
 
+/// `RedisStr` is a Redis string we borrow from Redis, like the arguments of
+/// a command. It isn't freed when it goes out of scope and can't outlive the
+/// call it was handed to us in.
+///
+/// Arguments are binary safe: nothing is assumed about their contents until
+/// one of the typed accessors is used, and those return errors rather than
+/// panicking when the contents don't fit.
+#[derive(Clone, Copy, Debug)]
+pub struct RedisStr<'a> {
+    str_inner: *mut raw::RedisModuleString,
+
+    // Index of the argument in the command line, for error messages.
+    position: usize,
+
+    _marker: PhantomData<&'a raw::RedisModuleString>,
+}
+
+impl<'a> RedisStr<'a> {
+    fn from_raw(str_inner: *mut raw::RedisModuleString, position: usize) -> RedisStr<'a> {
+        RedisStr { str_inner, position, _marker: PhantomData }
+    }
+
+    /// The raw contents of the string.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        redis_string_bytes(self.str_inner)
+    }
+
+    /// The contents as text, failing if they aren't valid UTF-8.
+    pub fn to_str(self) -> Result<&'a str, ColorError> {
+        str::from_utf8(self.as_bytes()).map_err(|_| self.parse_error("a UTF-8 string"))
+    }
+
+    /// Interprets the contents as an integer, the way Redis does.
+    pub fn to_i64(self) -> Result<i64, ColorError> {
+        let mut ll: c_longlong = 0;
+        match raw::string_to_long_long(self.str_inner, &mut ll) {
+            raw::Status::Ok => Ok(ll as i64),
+            raw::Status::Err => Err(self.parse_error("an integer")),
+        }
+    }
+
+    /// Interprets the contents as a floating point number, the way Redis does.
+    pub fn to_f64(self) -> Result<f64, ColorError> {
+        let mut d: f64 = 0.0;
+        match raw::string_to_double(self.str_inner, &mut d) {
+            raw::Status::Ok => Ok(d),
+            raw::Status::Err => Err(self.parse_error("a number")),
+        }
+    }
+
+    /// Parses the contents as text into a `T`. `expected` describes what was
+    /// expected for the error message.
+    pub fn parse<T: FromStr>(&self, expected: &str) -> Result<T, ColorError> {
+        self.to_str()?.parse::<T>().map_err(|_| self.parse_error(expected))
+    }
+
+    /// Compares the contents to `s`, ignoring ASCII case. Handy for options.
+    pub fn eq_ignore_case(&self, s: &str) -> bool {
+        self.as_bytes().eq_ignore_ascii_case(s.as_bytes())
+    }
+
+    /// Returns a parse error for this argument.
+    pub fn parse_error(&self, expected: &str) -> ColorError {
+        ColorError::parse(self.position, &String::from_utf8_lossy(self.as_bytes()), expected)
+    }
+}
+
+impl<'a> AsRef<[u8]> for RedisStr<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// `CallReply` is the reply to a command run through `Redis::call_reply`. The
+/// reply is freed when it goes out of scope.
+#[derive(Debug)]
+pub struct CallReply {
+    reply_inner: *mut raw::RedisModuleCallReply,
+}
+
+impl CallReply {
+    /// Decodes the reply, recursing into arrays.
+    pub fn decode(&self) -> Result<Reply, ColorError> {
+        manifest_redis_reply(self.reply_inner)
+    }
+}
+
+impl Drop for CallReply {
+    // Frees the reply along with all of its array elements.
+    fn drop(&mut self) {
+        raw::free_call_reply(self.reply_inner);
+    }
+}
+
 /// `RedisString` is an abstraction over a Redis string.
 ///
 /// Its primary function is to ensure the proper deallocation of resources when
@@ -427,6 +1071,11 @@ impl RedisString {
         let str_inner = raw::create_string(ctx, format!("{}\0", s).as_ptr(), s.len());
         RedisString { ctx, str_inner }
     }
+
+    fn create_from_bytes(ctx: *mut raw::RedisModuleCtx, b: &[u8]) -> RedisString {
+        let str_inner = raw::create_string(ctx, b.as_ptr(), b.len());
+        RedisString { ctx, str_inner }
+    }
 }
 
 impl Drop for RedisString {
@@ -436,6 +1085,19 @@ impl Drop for RedisString {
     }
 }
 
+/// Current Unix time in milliseconds according to the Redis clock. Everything
+/// time based should use this so that all clients agree on what "now" is.
+pub fn milliseconds() -> i64 {
+    raw::milliseconds() as i64
+}
+
+/// Emits `command key args...` into an AOF that is being rewritten. Meant to
+/// be called from a type's `aof_rewrite` callback.
+pub fn emit_aof<A: AsRef<[u8]>>(io: *mut raw::RedisModuleIO, command: &str, key: *mut raw::RedisModuleString, args: &[A]) {
+    let args: Vec<&[u8]> = args.iter().map(|a| a.as_ref()).collect();
+    raw::emit_aof(io, format!("{}\0", command).as_ptr(), key, &args);
+}
+
 fn handle_status(status: raw::Status, message: &str) -> Result<(), ColorError> {
     match status {
         raw::Status::Ok => Ok(()),
@@ -443,66 +1105,64 @@ fn handle_status(status: raw::Status, message: &str) -> Result<(), ColorError> {
     }
 }
 
-fn manifest_redis_string(redis_str: *mut raw::RedisModuleString) -> Result<String, string::FromUtf8Error> {
-  let mut length: size_t = 0;
-  let bytes = raw::string_ptr_len(redis_str, &mut length);
-  from_byte_string(bytes, length)
+// Borrows the bytes of a Redis string. The slice is only valid as long as the
+// string is.
+fn redis_string_bytes<'a>(redis_str: *mut raw::RedisModuleString) -> &'a [u8] {
+    let mut length: size_t = 0;
+    let bytes = raw::string_ptr_len(redis_str, &mut length);
+    if bytes.is_null() {
+        return &[]
+    }
+    unsafe { slice::from_raw_parts(bytes, length) }
 }
 
 fn manifest_redis_reply(reply: *mut raw::RedisModuleCallReply) -> Result<Reply, ColorError> {
     match raw::call_reply_type(reply) {
         raw::ReplyType::Integer => Ok(Reply::Integer(raw::call_reply_integer(reply))),
         raw::ReplyType::Nil => Ok(Reply::Nil),
-        raw::ReplyType::String => {
-            let mut length: size_t = 0;
-            let bytes = raw::call_reply_string_ptr(reply, &mut length);
-            from_byte_string(bytes, length)
-                .map(Reply::String)
-                .map_err(ColorError::from)
-        }
+        raw::ReplyType::String => Ok(Reply::String(call_reply_bytes(reply))),
         raw::ReplyType::Unknown => Ok(Reply::Unknown),
+        raw::ReplyType::Array => {
+            let len = raw::call_reply_length(reply);
+            let mut elements = Vec::with_capacity(len);
+            for i in 0..len {
+                let element = raw::call_reply_array_element(reply, i);
+                // Errors nested in an array (e.g. from EXEC) are data, not a
+                // failure of the call itself.
+                elements.push(match raw::call_reply_type(element) {
+                    raw::ReplyType::Error => Reply::Error(call_reply_error(element)),
+                    _ => manifest_redis_reply(element)?,
+                });
+            }
+            Ok(Reply::Array(elements))
+        }
 
-        // TODO: I need to actually extract the error from Redis here. Also, it
-        // should probably be its own non-generic variety of ColorError.
-        raw::ReplyType::Error => Err(error!("Redis replied with an error.")),
-
-        other => Err(error!("Don't yet handle Redis type: {:?}", other)),
+        raw::ReplyType::Error => Err(ColorError::Redis(call_reply_error(reply))),
     }
 }
 
-fn parse_args(argv: *mut *mut raw::RedisModuleString, argc: c_int) -> Result<Vec<String>, string::FromUtf8Error> {
-  let mut args: Vec<String> = Vec::with_capacity(argc as usize);
-  for i in 0..argc {
-    let redis_str = unsafe { *argv.offset(i as isize) };
-    args.push(manifest_redis_string(redis_str)?);
-  }
-  Ok(args)
+// Reads the payload of a string or error reply.
+fn call_reply_bytes(reply: *mut raw::RedisModuleCallReply) -> Vec<u8> {
+    let mut length: size_t = 0;
+    let bytes = raw::call_reply_string_ptr(reply, &mut length);
+    if bytes.is_null() {
+        return Vec::new()
+    }
+    unsafe { slice::from_raw_parts(bytes, length) }.to_vec()
 }
 
-fn from_byte_string(byte_str: *const u8, length: size_t) -> Result<String, string::FromUtf8Error> {
-  let mut vec_str: Vec<u8> = Vec::with_capacity(length as usize);
-  for j in 0..length {
-    let byte: u8 = unsafe { *byte_str.offset(j as isize) };
-    vec_str.insert(j, byte);
-  }
-  String::from_utf8(vec_str)
+// Reads the message of an error reply, which Redis words in plain text.
+fn call_reply_error(reply: *mut raw::RedisModuleCallReply) -> String {
+    String::from_utf8_lossy(&call_reply_bytes(reply)).into_owned()
 }
 
-// fn read_key(key: *mut raw::RedisModuleKey) -> Result<String, string::FromUtf8Error> {
-
-//     let mut length: size_t = 0;
-//     from_byte_string(
-//         raw::string_dma(key, &mut length, raw::KeyMode::READ),
-//         length,
-//     )
-// }
-
-// fn read_color_key(key: *mut raw::RedisModuleKey) -> Result<*mut super::Color, ColorError> {
-//     let color_pt = raw::module_type_get_value(key);
-//     // TODO: check null?
-//     Ok(color_pt as *mut super::Color)
-
-// }
+/// Wraps the arguments Redis passed to a command, or to the module when
+/// loading it.
+pub fn parse_args<'a>(argv: *mut *mut raw::RedisModuleString, argc: c_int) -> Vec<RedisStr<'a>> {
+    (0..argc as usize)
+        .map(|i| RedisStr::from_raw(unsafe { *argv.add(i) }, i))
+        .collect()
+}
 
 fn to_raw_mode(mode: KeyMode) -> raw::KeyMode {
     match mode {
@@ -510,3 +1170,42 @@ fn to_raw_mode(mode: KeyMode) -> raw::KeyMode {
         KeyMode::ReadWrite => raw::KeyMode::READ | raw::KeyMode::WRITE,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+
+    #[test]
+    fn deterministic_writes_replicate_verbatim() {
+        let mut server = Server::new();
+        server.run(&["THEME.SET", "light", "text", "#f5e"]);
+        server.run(&["THEME.SET", "light", "text", "nope"]);
+        server.run(&["THEME.RESOLVE", "light", "text"]);
+        server.run(&["COLOR.INFO"]);
+        assert_eq!(server.replicated, vec![vec!["THEME.SET", "light", "text", "#f5e"]]);
+    }
+
+    #[test]
+    fn failed_writes_still_replicate() {
+        let mut server = Server::new();
+        server.fail_replies = true;
+        // Verbatim.
+        assert!(server.run(&["THEME.SET", "light", "text", "#000000"]).is_error("ERR"));
+        // Explicit.
+        server.fail_replies = false;
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        server.fail_replies = true;
+        assert!(server.run(&["COLOR.SETCHANNEL", "pink", "r", "0"]).is_error("ERR"));
+        // Nothing written.
+        assert!(server.run(&["THEME.SET", "light", "text", "nope"]).is_error("ERR"));
+        assert_eq!(server.replicated, vec![
+            vec!["THEME.SET", "light", "text", "#000000"],
+            vec!["color.set", "pink", "#ff55efff"],
+            vec!["color.set", "pink", "#0055efff"],
+        ]);
+
+        server.fail_replies = false;
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#0055ef"));
+        assert_eq!(server.run(&["THEME.RESOLVE", "light", "text"]), Reply::bulks(&["#000000"]));
+    }
+}
@@ -4,22 +4,51 @@
 
 extern crate libc;
 
-use libc::{c_void, c_int, c_long, c_longlong, size_t, c_char};
+use libc::{c_void, c_int, c_long, c_longlong, size_t, c_char, c_double};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // Rust can't link against C macros (#define) so we just redefine them here.
 // There's a ~0 chance that any of these will ever change so it's pretty safe.
 pub const REDISMODULE_APIVER_1: c_int = 1;
 pub const ERRORMSG_WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
 pub const SIMPLE_OK: &str = "OK";
+pub const POSTPONED_ARRAY_LEN: c_long = -1; // REDISMODULE_POSTPONED_ARRAY_LEN
 
 bitflags! {
+    #[repr(transparent)]
     pub struct KeyMode: c_int {
         const READ = 1;
         const WRITE = (1 << 1);
     }
 }
 
+bitflags! {
+    /// Flags for `hash_set`. Redis also has CFIELDS, for fields given as C
+    /// strings, and EXISTS, which `hash_exists` uses; the wrappers below
+    /// always pass fields as RedisModuleStrings.
+    #[repr(transparent)]
+    pub struct HashFlags: c_int {
+        const NONE = 0;
+        const NX = 1;
+        const XX = (1 << 1);
+    }
+}
+
+const REDISMODULE_HASH_EXISTS: c_int = 1 << 3;
+
+bitflags! {
+    /// What `get_context_flags` tells about where a command comes from. Only
+    /// the flags the module looks at are listed.
+    #[repr(transparent)]
+    pub struct ContextFlags: c_int {
+        const REPLICATED = (1 << 12); // REDISMODULE_CTX_FLAGS_REPLICATED
+        const LOADING = (1 << 13);    // REDISMODULE_CTX_FLAGS_LOADING
+    }
+}
+
 #[derive(Debug, PartialEq)]
+#[repr(C)]
 pub enum ReplyType {
     Unknown = -1,
     String = 0,
@@ -30,6 +59,7 @@ pub enum ReplyType {
 }
 
 #[derive(Debug, PartialEq)]
+#[repr(C)]
 pub enum KeyType {
     Empty = 0,  // REDISMODULE_KEYTYPE_EMPTY
     String = 1, // REDISMODULE_KEYTYPE_STRING
@@ -41,6 +71,7 @@ pub enum KeyType {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
 pub enum Status {
     Ok = 0,
     Err = 1,
@@ -48,31 +79,35 @@ pub enum Status {
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct RedisModuleCallReply;
+pub struct RedisModuleCallReply { _private: [u8; 0] }
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct RedisModuleCtx;
+pub struct RedisModuleCtx { _private: [u8; 0] }
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct RedisModuleKey;
+pub struct RedisModuleKey { _private: [u8; 0] }
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct RedisModuleString;
+pub struct RedisModuleString { _private: [u8; 0] }
 
 #[derive(Debug,Copy,Clone)] 
 #[repr(C)] 
-pub struct RedisModuleDigest;
+pub struct RedisModuleDigest { _private: [u8; 0] }
 
 #[derive(Debug,Copy,Clone)] 
 #[repr(C)] 
-pub struct RedisModuleIO;
+pub struct RedisModuleIO { _private: [u8; 0] }
 
 #[derive(Debug,Copy,Clone)] 
 #[repr(C)] 
-pub struct RedisModuleType;
+pub struct RedisModuleType { _private: [u8; 0] }
+
+#[derive(Debug,Copy,Clone)]
+#[repr(C)]
+pub struct RedisModuleBlockedClient { _private: [u8; 0] }
 
 pub type RedisModuleTypeLoadFunc = Option <unsafe extern "C" fn(rdb: *mut RedisModuleIO, encver: c_int) -> *mut c_void>;
 pub type RedisModuleTypeSaveFunc = Option <unsafe extern "C" fn(rdb: *mut RedisModuleIO, value: *mut c_void)>;
@@ -105,7 +140,103 @@ pub fn init(
     module_version: c_int,
     api_version: c_int,
 ) -> Status {
-    unsafe { Export_RedisModule_Init(ctx, modulename, module_version, api_version) }
+    let status = unsafe { Export_RedisModule_Init(ctx, modulename, module_version, api_version) };
+    // GetContextFlags came after the Redis 4.0 redismodule.h we build
+    // against, so it's looked up by hand. Servers that don't have it leave
+    // the pointer null.
+    let mut f: *mut c_void = ptr::null_mut();
+    if status == Status::Ok {
+        unsafe { RedisModule_GetApi("RedisModule_GetContextFlags\0".as_ptr(), &mut f as *mut *mut c_void as *mut c_void) };
+    }
+    GET_CONTEXT_FLAGS.store(f as usize, Ordering::SeqCst);
+    status
+}
+
+static GET_CONTEXT_FLAGS: AtomicUsize = AtomicUsize::new(0);
+
+// What the server tells about the context of the current command, or None if
+// the server is too old to tell.
+pub fn get_context_flags(ctx: *mut RedisModuleCtx) -> Option<ContextFlags> {
+    let f = GET_CONTEXT_FLAGS.load(Ordering::SeqCst);
+    if f == 0 {
+        return None
+    }
+    let f: extern "C" fn(*mut RedisModuleCtx) -> c_int = unsafe { ::std::mem::transmute(f) };
+    Some(ContextFlags::from_bits_truncate(f(ctx)))
+}
+
+// Blocks the client that issued the current command. `reply_callback` is
+// invoked once the client is unblocked and `timeout_callback` if `timeout_ms`
+// (0 means no timeout) expires first.
+pub fn block_client(
+    ctx: *mut RedisModuleCtx,
+    reply_callback: Option<RedisModuleCmdFunc>,
+    timeout_callback: Option<RedisModuleCmdFunc>,
+    free_privdata: Option<extern "C" fn(*mut c_void)>,
+    timeout_ms: c_longlong,
+) -> *mut RedisModuleBlockedClient {
+    unsafe { RedisModule_BlockClient(ctx, reply_callback, timeout_callback, free_privdata, timeout_ms) }
+}
+
+pub fn unblock_client(bc: *mut RedisModuleBlockedClient, privdata: *mut c_void) -> Status {
+    unsafe { RedisModule_UnblockClient(bc, privdata) }
+}
+
+pub fn get_client_id(ctx: *mut RedisModuleCtx) -> u64 {
+    unsafe { RedisModule_GetClientId(ctx) }
+}
+
+// Whether a command registered with the "getkeys-api" flag is called to tell
+// where its keys are rather than to run, see `key_at_pos`.
+pub fn is_keys_position_request(ctx: *mut RedisModuleCtx) -> bool {
+    unsafe { RedisModule_IsKeysPositionRequest(ctx) != 0 }
+}
+
+// Declares the argument at `pos` a key, in answer to a keys position request.
+pub fn key_at_pos(ctx: *mut RedisModuleCtx, pos: c_int) {
+    unsafe { RedisModule_KeyAtPos(ctx, pos) }
+}
+
+// Runs a Redis command. The arguments are passed as a vector of `argc` Redis
+// strings (the "v" format), which lets us call commands with any number of
+// arguments. Returns null if the command doesn't exist or the arity is wrong.
+pub fn call(
+    ctx: *mut RedisModuleCtx,
+    cmdname: *const u8,
+    args: *const *mut RedisModuleString,
+    argc: size_t,
+) -> *mut RedisModuleCallReply {
+    unsafe { RedisModule_Call(ctx, cmdname, "v\0".as_ptr(), args, argc) }
+}
+
+// Propagates `cmdname` with `args` to replicas and the AOF, once the calling
+// command returns.
+pub fn replicate(
+    ctx: *mut RedisModuleCtx,
+    cmdname: *const u8,
+    args: *const *mut RedisModuleString,
+    argc: size_t,
+) -> Status {
+    unsafe { RedisModule_Replicate(ctx, cmdname, "v\0".as_ptr(), args, argc) }
+}
+
+// Propagates the calling command as it was called.
+pub fn replicate_verbatim(ctx: *mut RedisModuleCtx) -> Status {
+    unsafe { RedisModule_ReplicateVerbatim(ctx) }
+}
+
+pub fn call_reply_length(reply: *mut RedisModuleCallReply) -> size_t {
+    unsafe { RedisModule_CallReplyLength(reply) }
+}
+
+// Returns the element at `idx` of an array reply. The element belongs to the
+// array and must not be freed on its own.
+pub fn call_reply_array_element(reply: *mut RedisModuleCallReply, idx: size_t) -> *mut RedisModuleCallReply {
+    unsafe { RedisModule_CallReplyArrayElement(reply, idx) }
+}
+
+pub fn reply_with_call_reply(ctx: *mut RedisModuleCtx, reply: *mut RedisModuleCallReply) -> Status {
+    unsafe { RedisModule_ReplyWithCallReply(ctx, reply) }
 }
 
 pub fn call_reply_type(reply: *mut RedisModuleCallReply) -> ReplyType {
@@ -116,8 +247,12 @@ pub fn key_type(key: *mut RedisModuleKey) -> KeyType {
     unsafe { RedisModule_KeyType(key) }
 }
 
-pub fn module_type_set_value(key: *mut RedisModuleKey, value: *mut c_void) -> Status {
-    unsafe { RedisModule_ModuleTypeSetValue(key, super::COLOR_TYPE, value) }
+pub fn delete_key(key: *mut RedisModuleKey) -> Status {
+    unsafe { RedisModule_DeleteKey(key) }
+}
+
+pub fn module_type_set_value(key: *mut RedisModuleKey, mt: *mut RedisModuleType, value: *mut c_void) -> Status {
+    unsafe { RedisModule_ModuleTypeSetValue(key, mt, value) }
 }
 
 pub fn module_key_type(key: *mut RedisModuleKey) -> *mut RedisModuleType {
@@ -176,7 +311,7 @@ pub fn create_type(
     name: *const u8,
     encver: c_int,
     typemethods: *mut RedisModuleTypeMethods
-) -> Status {
+) -> *mut RedisModuleType {
     unsafe {
         RedisModule_CreateDataType(ctx, name, encver, typemethods)
     }
@@ -190,6 +325,23 @@ pub fn create_string(
     unsafe { RedisModule_CreateString(ctx, ptr, len) }
 }
 
+// Emits `cmdname key args...` into the AOF being rewritten. EmitAOF is
+// variadic, so the arguments go as a single vector of Redis strings ("v"),
+// which takes any number of them. They're created in the context Redis keeps
+// for the rewrite.
+pub fn emit_aof(io: *mut RedisModuleIO, cmdname: *const u8, key: *mut RedisModuleString, args: &[&[u8]]) {
+    let ctx = get_context_from_io(io);
+    let argv: Vec<*mut RedisModuleString> = args.iter().map(|a| create_string(ctx, a.as_ptr(), a.len())).collect();
+    unsafe { RedisModule_EmitAOF(io, cmdname, "sv\0".as_ptr(), key, argv.as_ptr(), argv.len() as size_t) };
+    for s in argv {
+        free_string(ctx, s);
+    }
+}
+
+pub fn get_context_from_io(io: *mut RedisModuleIO) -> *mut RedisModuleCtx {
+    unsafe { RedisModule_GetContextFromIO(io) }
+}
+
 pub fn free_string(ctx: *mut RedisModuleCtx, str: *mut RedisModuleString) {
     unsafe { RedisModule_FreeString(ctx, str) }
 }
@@ -198,8 +350,44 @@ pub fn get_selected_db(ctx: *mut RedisModuleCtx) -> c_int {
     unsafe { RedisModule_GetSelectedDb(ctx) }
 }
 
-pub fn log(ctx: *mut RedisModuleCtx, level: *const u8, fmt: *const u8) {
-    unsafe { RedisModule_Log(ctx, level, fmt) }
+pub fn save_string_buffer(io: *mut RedisModuleIO, buf: &[u8]) {
+    unsafe { RedisModule_SaveStringBuffer(io, buf.as_ptr(), buf.len()) }
+}
+
+// Loads a string saved with `save_string_buffer`. Redis allocates the buffer
+// for us to free, so it is copied out and freed here.
+pub fn load_string_buffer(io: *mut RedisModuleIO) -> Vec<u8> {
+    let mut len: size_t = 0;
+    let buf = unsafe { RedisModule_LoadStringBuffer(io, &mut len) };
+    if buf.is_null() {
+        return Vec::new()
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(buf, len) }.to_vec();
+    free(buf as *mut c_void);
+    bytes
+}
+
+pub fn load_unsigned(io: *mut RedisModuleIO) -> u64 {
+    unsafe { RedisModule_LoadUnsigned(io) }
+}
+
+pub fn load_signed(io: *mut RedisModuleIO) -> i64 {
+    unsafe { RedisModule_LoadSigned(io) }
+}
+
+pub fn load_double(io: *mut RedisModuleIO) -> c_double {
+    unsafe { RedisModule_LoadDouble(io) }
+}
+
+// Logs `msg` verbatim. It's passed through a "%s" format so that a stray % in
+// the message can't make Redis read arguments that aren't there.
+pub fn log(ctx: *mut RedisModuleCtx, level: *const u8, msg: *const u8) {
+    unsafe { RedisModule_Log(ctx, level, "%s\0".as_ptr(), msg) }
+}
+
+// Like `log`, for callbacks that only have an IO handle (RDB and AOF).
+pub fn log_io_error(io: *mut RedisModuleIO, level: *const u8, msg: *const u8) {
+    unsafe { RedisModule_LogIOError(io, level, "%s\0".as_ptr(), msg) }
 }
 
 pub fn open_key(
@@ -214,6 +402,40 @@ pub fn reply_with_array(ctx: *mut RedisModuleCtx, len: c_long) -> Status {
     unsafe { RedisModule_ReplyWithArray(ctx, len) }
 }
 
+// Redis' allocator. Its functions are only there once the module API was
+// initialised, so unlike the other bindings these say whether they could run.
+pub fn alloc(bytes: size_t) -> Option<*mut c_void> {
+    unsafe { RedisModule_Alloc.map(|f| f(bytes)) }
+}
+
+pub fn calloc(nmemb: size_t, size: size_t) -> Option<*mut c_void> {
+    unsafe { RedisModule_Calloc.map(|f| f(nmemb, size)) }
+}
+
+pub fn realloc(ptr: *mut c_void, bytes: size_t) -> Option<*mut c_void> {
+    unsafe { RedisModule_Realloc.map(|f| f(ptr, bytes)) }
+}
+
+pub fn free(ptr: *mut c_void) -> bool {
+    unsafe { RedisModule_Free.map(|f| f(ptr)).is_some() }
+}
+
+pub fn reply_set_array_length(ctx: *mut RedisModuleCtx, len: c_long) {
+    unsafe { RedisModule_ReplySetArrayLength(ctx, len) }
+}
+
+pub fn reply_with_null(ctx: *mut RedisModuleCtx) -> Status {
+    unsafe { RedisModule_ReplyWithNull(ctx) }
+}
+
+pub fn reply_with_double(ctx: *mut RedisModuleCtx, d: c_double) -> Status {
+    unsafe { RedisModule_ReplyWithDouble(ctx, d) }
+}
+
+pub fn reply_with_string_buffer(ctx: *mut RedisModuleCtx, buf: *const u8, len: size_t) -> Status {
+    unsafe { RedisModule_ReplyWithStringBuffer(ctx, buf, len) }
+}
+
 pub fn reply_with_error(ctx: *mut RedisModuleCtx, err: *const u8) {
     unsafe { RedisModule_ReplyWithError(ctx, err) }
 }
@@ -233,6 +455,23 @@ pub fn reply_with_simple_string(ctx: *mut RedisModuleCtx, str: *const c_char) ->
     unsafe { RedisModule_ReplyWithSimpleString(ctx, str) }
 }
 
+pub fn save_unsigned(io: *mut RedisModuleIO, value: u64) {
+    unsafe { RedisModule_SaveUnsigned(io, value) }
+}
+
+pub fn save_signed(io: *mut RedisModuleIO, value: i64) {
+    unsafe { RedisModule_SaveSigned(io, value) }
+}
+
+pub fn save_double(io: *mut RedisModuleIO, value: c_double) {
+    unsafe { RedisModule_SaveDouble(io, value) }
+}
+
+// Current Unix time in milliseconds according to Redis.
+pub fn milliseconds() -> c_longlong {
+    unsafe { RedisModule_Milliseconds() }
+}
+
 // Sets the expiry on a key.
 //
 // Expire is in milliseconds.
@@ -252,10 +491,103 @@ pub fn string_ptr_len(str: *mut RedisModuleString, len: *mut size_t) -> *const u
     unsafe { RedisModule_StringPtrLen(str, len) }
 }
 
+pub fn string_to_long_long(str: *mut RedisModuleString, ll: *mut c_longlong) -> Status {
+    unsafe { RedisModule_StringToLongLong(str, ll) }
+}
+
+pub fn string_to_double(str: *mut RedisModuleString, d: *mut c_double) -> Status {
+    unsafe { RedisModule_StringToDouble(str, d) }
+}
+
 pub fn string_set(key: *mut RedisModuleKey, str: *mut RedisModuleString) -> Status {
     unsafe { RedisModule_StringSet(key, str) }
 }
 
+// RedisModule_HashSet and RedisModule_HashGet are variadic: they take any
+// number of fields, each followed by where its value is, and a NULL to end the
+// list. Leaving out the NULL makes Redis read past the arguments, so the
+// wrappers below take a single field each and terminate the list themselves.
+
+// Sets `field` to `value` in the hash at `key`, creating the hash if the key
+// is empty. Returns how many fields were updated, which depending on the
+// version of Redis counts only fields that already existed.
+pub fn hash_set(
+    key: *mut RedisModuleKey,
+    flags: HashFlags,
+    field: *mut RedisModuleString,
+    value: *mut RedisModuleString,
+) -> c_int {
+    unsafe { RedisModule_HashSet(key, flags.bits(), field, value, ptr::null_mut::<c_void>()) }
+}
+
+// The value of `field` in the hash at `key`, or null if there's no such
+// field. The string is ours to free.
+pub fn hash_get(key: *mut RedisModuleKey, field: *mut RedisModuleString) -> *mut RedisModuleString {
+    let mut value: *mut RedisModuleString = ptr::null_mut();
+    unsafe {
+        RedisModule_HashGet(key, HashFlags::NONE.bits(), field, &mut value as *mut *mut RedisModuleString, ptr::null_mut::<c_void>());
+    }
+    value
+}
+
+pub fn hash_exists(key: *mut RedisModuleKey, field: *mut RedisModuleString) -> bool {
+    let mut exists: c_int = 0;
+    unsafe {
+        RedisModule_HashGet(key, REDISMODULE_HASH_EXISTS, field, &mut exists as *mut c_int, ptr::null_mut::<c_void>());
+    }
+    exists != 0
+}
+
+pub fn zset_add(
+    key: *mut RedisModuleKey,
+    score: c_double,
+    ele: *mut RedisModuleString,
+    flags: *mut c_int,
+) -> Status {
+    unsafe { RedisModule_ZsetAdd(key, score, ele, flags) }
+}
+
+pub fn zset_rem(
+    key: *mut RedisModuleKey,
+    ele: *mut RedisModuleString,
+    deleted: *mut c_int,
+) -> Status {
+    unsafe { RedisModule_ZsetRem(key, ele, deleted) }
+}
+
+// Positions the key's range iterator on the first element with a score inside
+// [min, max]. The `*ex` flags make the respective bound exclusive.
+pub fn zset_first_in_score_range(
+    key: *mut RedisModuleKey,
+    min: c_double,
+    max: c_double,
+    minex: bool,
+    maxex: bool,
+) -> Status {
+    unsafe { RedisModule_ZsetFirstInScoreRange(key, min, max, minex as c_int, maxex as c_int) }
+}
+
+// Returns the element under the range iterator. The returned string is owned
+// by the caller and must be freed.
+pub fn zset_range_current_element(
+    key: *mut RedisModuleKey,
+    score: *mut c_double,
+) -> *mut RedisModuleString {
+    unsafe { RedisModule_ZsetRangeCurrentElement(key, score) }
+}
+
+pub fn zset_range_next(key: *mut RedisModuleKey) -> bool {
+    unsafe { RedisModule_ZsetRangeNext(key) != 0 }
+}
+
+pub fn zset_range_end_reached(key: *mut RedisModuleKey) -> bool {
+    unsafe { RedisModule_ZsetRangeEndReached(key) != 0 }
+}
+
+pub fn zset_range_stop(key: *mut RedisModuleKey) {
+    unsafe { RedisModule_ZsetRangeStop(key) }
+}
+
 // Redis doesn't make this easy for us by exporting a library, so instead what
 // we do is bake redismodule.h's symbols into a library of our construction
 // during build and link against that. See build.rs for details.
@@ -269,6 +601,24 @@ extern "C" {
         api_version: c_int,
     ) -> Status;
 
+    static RedisModule_BlockClient:
+        extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        reply_callback: Option<RedisModuleCmdFunc>,
+        timeout_callback: Option<RedisModuleCmdFunc>,
+        free_privdata: Option<extern "C" fn(*mut c_void)>,
+        timeout_ms: c_longlong,
+    ) -> *mut RedisModuleBlockedClient;
+
+    static RedisModule_UnblockClient:
+        extern "C" fn(bc: *mut RedisModuleBlockedClient, privdata: *mut c_void) -> Status;
+
+    static RedisModule_GetClientId: extern "C" fn(ctx: *mut RedisModuleCtx) -> u64;
+
+    static RedisModule_IsKeysPositionRequest: extern "C" fn(ctx: *mut RedisModuleCtx) -> c_int;
+
+    static RedisModule_KeyAtPos: extern "C" fn(ctx: *mut RedisModuleCtx, pos: c_int);
+
     static RedisModule_CallReplyType:
         extern "C" fn(reply: *mut RedisModuleCallReply) -> ReplyType;
 
@@ -287,10 +637,33 @@ extern "C" {
         name: *const u8, 
         encver: c_int, 
         typemethods: *mut RedisModuleTypeMethods,
-    ) -> Status;
+    ) -> *mut RedisModuleType;
+
+    static RedisModule_EmitAOF: unsafe extern "C" fn(
+        io: *mut RedisModuleIO,
+        cmdname: *const u8,
+        fmt: *const u8,
+        ...
+    );
+
+    static RedisModule_GetContextFromIO: extern "C" fn(io: *mut RedisModuleIO) -> *mut RedisModuleCtx;
+
+    static RedisModule_SaveStringBuffer: extern "C" fn(io: *mut RedisModuleIO, str: *const u8, len: size_t);
+    static RedisModule_LoadStringBuffer: extern "C" fn(io: *mut RedisModuleIO, lenptr: *mut size_t) -> *mut u8;
+    static RedisModule_SaveUnsigned: extern "C" fn(io: *mut RedisModuleIO, value: u64);
+    static RedisModule_LoadUnsigned: extern "C" fn(io: *mut RedisModuleIO) -> u64;
+    static RedisModule_SaveSigned: extern "C" fn(io: *mut RedisModuleIO, value: i64);
+    static RedisModule_LoadSigned: extern "C" fn(io: *mut RedisModuleIO) -> i64;
+    static RedisModule_SaveDouble: extern "C" fn(io: *mut RedisModuleIO, value: c_double);
+    static RedisModule_LoadDouble: extern "C" fn(io: *mut RedisModuleIO) -> c_double;
+
+    static RedisModule_Milliseconds: extern "C" fn() -> c_longlong;
+
+    static RedisModule_GetApi: extern "C" fn(name: *const u8, target: *mut c_void) -> c_int;
 
     // TODO: Does kp has to be mut?
     static RedisModule_KeyType: extern "C" fn(kp: *mut RedisModuleKey) -> KeyType;
+    static RedisModule_DeleteKey: extern "C" fn(key: *mut RedisModuleKey) -> Status;
     static RedisModule_ModuleTypeSetValue: extern "C" fn(key: *mut RedisModuleKey, mt: *mut RedisModuleType, value: *mut c_void) -> Status;
     static RedisModule_ModuleTypeGetType: extern "C" fn(key: *mut RedisModuleKey) -> *mut RedisModuleType;
     static RedisModule_ModuleTypeGetValue: extern "C" fn(key: *mut RedisModuleKey) -> *mut c_void;
@@ -316,7 +689,10 @@ extern "C" {
     static RedisModule_GetSelectedDb: extern "C" fn(ctx: *mut RedisModuleCtx) -> c_int;
 
     static RedisModule_Log:
-        extern "C" fn(ctx: *mut RedisModuleCtx, level: *const u8, fmt: *const u8);
+        unsafe extern "C" fn(ctx: *mut RedisModuleCtx, level: *const u8, fmt: *const u8, ...);
+
+    static RedisModule_LogIOError:
+        unsafe extern "C" fn(io: *mut RedisModuleIO, level: *const u8, fmt: *const u8, ...);
 
     pub static RedisModule_OpenKey:
         extern "C" fn(
@@ -325,9 +701,27 @@ extern "C" {
         mode: KeyMode,
     ) -> *mut RedisModuleKey;
 
+    // Null until `init` ran, hence the Options.
+    static RedisModule_Alloc: Option<extern "C" fn(bytes: size_t) -> *mut c_void>;
+    static RedisModule_Calloc: Option<extern "C" fn(nmemb: size_t, size: size_t) -> *mut c_void>;
+    static RedisModule_Realloc: Option<extern "C" fn(ptr: *mut c_void, bytes: size_t) -> *mut c_void>;
+    static RedisModule_Free: Option<extern "C" fn(ptr: *mut c_void)>;
+
     static RedisModule_ReplyWithArray:
         extern "C" fn(ctx: *mut RedisModuleCtx, len: c_long) -> Status;
 
+    static RedisModule_ReplySetArrayLength:
+        extern "C" fn(ctx: *mut RedisModuleCtx, len: c_long);
+
+    static RedisModule_ReplyWithNull:
+        extern "C" fn(ctx: *mut RedisModuleCtx) -> Status;
+
+    static RedisModule_ReplyWithDouble:
+        extern "C" fn(ctx: *mut RedisModuleCtx, d: c_double) -> Status;
+
+    static RedisModule_ReplyWithStringBuffer:
+        extern "C" fn(ctx: *mut RedisModuleCtx, buf: *const u8, len: size_t) -> Status;
+
     static RedisModule_ReplyWithError:
         extern "C" fn(ctx: *mut RedisModuleCtx, err: *const u8);
 
@@ -348,92 +742,76 @@ extern "C" {
     static RedisModule_StringPtrLen:
         extern "C" fn(str: *mut RedisModuleString, len: *mut size_t) -> *const u8;
 
+    static RedisModule_StringToLongLong:
+        extern "C" fn(str: *mut RedisModuleString, ll: *mut c_longlong) -> Status;
+
+    static RedisModule_StringToDouble:
+        extern "C" fn(str: *mut RedisModuleString, d: *mut c_double) -> Status;
+
     static RedisModule_StringSet:
         extern "C" fn(key: *mut RedisModuleKey, str: *mut RedisModuleString) -> Status;
 
-    static RedisModule_Call:
+    static RedisModule_HashSet:
+        unsafe extern "C" fn(key: *mut RedisModuleKey, flags: c_int, ...) -> c_int;
+
+    static RedisModule_HashGet:
+        unsafe extern "C" fn(key: *mut RedisModuleKey, flags: c_int, ...) -> Status;
+
+    static RedisModule_ZsetAdd:
         extern "C" fn(
-        ctx: *mut RedisModuleCtx,
-        cmdname: *const u8,
-        fmt: *const u8,
-        args: *const *mut RedisModuleString,
-    ) -> *mut RedisModuleCallReply;
-}
+        key: *mut RedisModuleKey,
+        score: c_double,
+        ele: *mut RedisModuleString,
+        flagsptr: *mut c_int,
+    ) -> Status;
+
+    static RedisModule_ZsetRem:
+        extern "C" fn(
+        key: *mut RedisModuleKey,
+        ele: *mut RedisModuleString,
+        deleted: *mut c_int,
+    ) -> Status;
 
-pub mod call1 {
-    use redis::raw;
+    static RedisModule_ZsetFirstInScoreRange:
+        extern "C" fn(
+        key: *mut RedisModuleKey,
+        min: c_double,
+        max: c_double,
+        minex: c_int,
+        maxex: c_int,
+    ) -> Status;
 
-    pub fn call(
-        ctx: *mut raw::RedisModuleCtx,
-        cmdname: *const u8,
-        fmt: *const u8,
-        arg0: *mut raw::RedisModuleString,
-    ) -> *mut raw::RedisModuleCallReply {
-        unsafe { RedisModule_Call(ctx, cmdname, fmt, arg0) }
-    }
+    static RedisModule_ZsetRangeCurrentElement:
+        extern "C" fn(key: *mut RedisModuleKey, score: *mut c_double) -> *mut RedisModuleString;
 
-    #[allow(improper_ctypes)]
-    extern "C" {
-        pub static RedisModule_Call:
-            extern "C" fn(
-            ctx: *mut raw::RedisModuleCtx,
-            cmdname: *const u8,
-            fmt: *const u8,
-            arg0: *mut raw::RedisModuleString,
-        ) -> *mut raw::RedisModuleCallReply;
-    }
-}
+    static RedisModule_ZsetRangeNext: extern "C" fn(key: *mut RedisModuleKey) -> c_int;
+    static RedisModule_ZsetRangeEndReached: extern "C" fn(key: *mut RedisModuleKey) -> c_int;
+    static RedisModule_ZsetRangeStop: extern "C" fn(key: *mut RedisModuleKey);
 
-pub mod call2 {
-    use redis::raw;
+    static RedisModule_Call:
+        unsafe extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        cmdname: *const u8,
+        fmt: *const u8,
+        ...
+    ) -> *mut RedisModuleCallReply;
 
-    pub fn call(
-        ctx: *mut raw::RedisModuleCtx,
+    static RedisModule_Replicate:
+        unsafe extern "C" fn(
+        ctx: *mut RedisModuleCtx,
         cmdname: *const u8,
         fmt: *const u8,
-        arg0: *mut raw::RedisModuleString,
-        arg1: *mut raw::RedisModuleString,
-    ) -> *mut raw::RedisModuleCallReply {
-        unsafe { RedisModule_Call(ctx, cmdname, fmt, arg0, arg1) }
-    }
+        ...
+    ) -> Status;
 
-    #[allow(improper_ctypes)]
-    extern "C" {
-        pub static RedisModule_Call:
-            extern "C" fn(
-            ctx: *mut raw::RedisModuleCtx,
-            cmdname: *const u8,
-            fmt: *const u8,
-            arg0: *mut raw::RedisModuleString,
-            arg1: *mut raw::RedisModuleString,
-        ) -> *mut raw::RedisModuleCallReply;
-    }
-}
+    static RedisModule_ReplicateVerbatim: extern "C" fn(ctx: *mut RedisModuleCtx) -> Status;
 
-pub mod call3 {
-    use redis::raw;
+    static RedisModule_CallReplyLength:
+        extern "C" fn(reply: *mut RedisModuleCallReply) -> size_t;
 
-    pub fn call(
-        ctx: *mut raw::RedisModuleCtx,
-        cmdname: *const u8,
-        fmt: *const u8,
-        arg0: *mut raw::RedisModuleString,
-        arg1: *mut raw::RedisModuleString,
-        arg2: *mut raw::RedisModuleString,
-    ) -> *mut raw::RedisModuleCallReply {
-        unsafe { RedisModule_Call(ctx, cmdname, fmt, arg0, arg1, arg2) }
-    }
+    static RedisModule_CallReplyArrayElement:
+        extern "C" fn(reply: *mut RedisModuleCallReply, idx: size_t) -> *mut RedisModuleCallReply;
 
-    #[allow(improper_ctypes)]
-    extern "C" {
-        pub static RedisModule_Call:
-            extern "C" fn(
-            ctx: *mut raw::RedisModuleCtx,
-            cmdname: *const u8,
-            fmt: *const u8,
-            arg0: *mut raw::RedisModuleString,
-            arg1: *mut raw::RedisModuleString,
-            arg2: *mut raw::RedisModuleString,
-        ) -> *mut raw::RedisModuleCallReply;
-    }
+    static RedisModule_ReplyWithCallReply:
+        extern "C" fn(ctx: *mut RedisModuleCtx, reply: *mut RedisModuleCallReply) -> Status;
 }
@@ -0,0 +1,202 @@
+// Typed replies to our own clients.
+//
+// Scalar replies are written straight through `Redis`. Arrays go through
+// `ArrayReply`, which counts the elements written so that a command can't send
+// a malformed reply by writing more or fewer elements than it announced. An
+// array whose length isn't known up front is postponed: Redis reserves room
+// for the length and `finish` fills it in once every element was written.
+//
+// Once a command has started replying it can't reply with an error anymore
+// without the client reading one reply too many, so the harness asks
+// `replied` before turning a failure into an error reply.
+//
+// The few kinds of array elements no command writes yet are allowed to be dead
+// code, one by one.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::marker::PhantomData;
+
+use error::ColorError;
+use libc::{c_double, c_long, c_longlong, size_t};
+use redis::{handle_status, raw, CallReply, LogLevel};
+
+thread_local!(static REPLIED: Cell<bool> = const { Cell::new(false) });
+
+// Forgets about earlier replies, before a command starts running.
+pub fn reset() {
+    REPLIED.with(|r| r.set(false))
+}
+
+// Whether the running command wrote any reply yet.
+pub fn replied() -> bool {
+    REPLIED.with(|r| r.get())
+}
+
+fn mark(status: raw::Status, message: &str) -> Result<(), ColorError> {
+    REPLIED.with(|r| r.set(true));
+    handle_status(status, message)
+}
+
+pub fn null(ctx: *mut raw::RedisModuleCtx) -> Result<(), ColorError> {
+    mark(raw::reply_with_null(ctx), "Could not reply with null")
+}
+
+pub fn integer(ctx: *mut raw::RedisModuleCtx, n: i64) -> Result<(), ColorError> {
+    mark(raw::reply_with_long_long(ctx, n as c_longlong), "Could not reply with longlong")
+}
+
+pub fn double(ctx: *mut raw::RedisModuleCtx, d: f64) -> Result<(), ColorError> {
+    mark(raw::reply_with_double(ctx, d as c_double), "Could not reply with double")
+}
+
+pub fn string(ctx: *mut raw::RedisModuleCtx, bytes: &[u8]) -> Result<(), ColorError> {
+    mark(
+        raw::reply_with_string_buffer(ctx, bytes.as_ptr(), bytes.len() as size_t),
+        "Could not reply with string",
+    )
+}
+
+pub fn simple_string(ctx: *mut raw::RedisModuleCtx, message: &str) -> Result<(), ColorError> {
+    let cstr = match CString::new(message) {
+        Ok(cstr) => cstr,
+        Err(_) => return Err(error!("Simple strings can't contain NUL bytes")),
+    };
+    mark(
+        raw::reply_with_simple_string(ctx, cstr.as_ptr()),
+        "Could not reply with simple string",
+    )
+}
+
+pub fn call_reply(ctx: *mut raw::RedisModuleCtx, reply: &CallReply) -> Result<(), ColorError> {
+    mark(
+        raw::reply_with_call_reply(ctx, reply.reply_inner),
+        "Could not reply with call reply",
+    )
+}
+
+// Starts an array of `len` elements, or a postponed one when `len` is None.
+pub fn array<'a>(ctx: *mut raw::RedisModuleCtx, len: Option<usize>) -> Result<ArrayReply<'a>, ColorError> {
+    let raw_len = match len {
+        Some(len) => len as c_long,
+        None => raw::POSTPONED_ARRAY_LEN,
+    };
+    mark(raw::reply_with_array(ctx, raw_len), "Could not reply with array")?;
+    Ok(ArrayReply { ctx, len, written: 0, finished: false, _parent: PhantomData })
+}
+
+/// An array reply being written. Elements are added with the methods below,
+/// nested arrays included, and `finish` closes the array.
+///
+/// Writing more elements than the array was started with fails without
+/// writing anything. If the array is dropped short of elements, e.g. because
+/// the command bailed out with `?`, it is padded with nulls and the mistake is
+/// logged, so the client still gets a well formed reply.
+pub struct ArrayReply<'a> {
+    ctx: *mut raw::RedisModuleCtx,
+    len: Option<usize>,
+    written: usize,
+    finished: bool,
+    // A nested array borrows its parent so that the parent can't be written
+    // to until the nested one is done.
+    _parent: PhantomData<&'a mut ()>,
+}
+
+impl<'a> ArrayReply<'a> {
+    /// The number of elements written so far.
+    #[allow(dead_code)]
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Makes room for one more element.
+    fn push(&mut self) -> Result<(), ColorError> {
+        if let Some(len) = self.len {
+            if self.written >= len {
+                return Err(error!("Array reply already has its {} elements", len))
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn null(&mut self) -> Result<(), ColorError> {
+        self.push()?;
+        null(self.ctx)
+    }
+
+    pub fn integer(&mut self, n: i64) -> Result<(), ColorError> {
+        self.push()?;
+        integer(self.ctx, n)
+    }
+
+    #[allow(dead_code)]
+    pub fn double(&mut self, d: f64) -> Result<(), ColorError> {
+        self.push()?;
+        double(self.ctx, d)
+    }
+
+    pub fn string<S: AsRef<[u8]>>(&mut self, s: S) -> Result<(), ColorError> {
+        self.push()?;
+        string(self.ctx, s.as_ref())
+    }
+
+    #[allow(dead_code)]
+    pub fn simple_string(&mut self, message: &str) -> Result<(), ColorError> {
+        self.push()?;
+        simple_string(self.ctx, message)
+    }
+
+    pub fn call_reply(&mut self, reply: &CallReply) -> Result<(), ColorError> {
+        self.push()?;
+        call_reply(self.ctx, reply)
+    }
+
+    /// Starts a nested array of `len` elements.
+    pub fn array(&mut self, len: usize) -> Result<ArrayReply<'_>, ColorError> {
+        self.push()?;
+        array(self.ctx, Some(len))
+    }
+
+    /// Starts a nested array whose length is set when it's finished.
+    pub fn postponed_array(&mut self) -> Result<ArrayReply<'_>, ColorError> {
+        self.push()?;
+        array(self.ctx, None)
+    }
+
+    /// Closes the array. Fails if fewer elements were written than the array
+    /// was started with, after padding it so the reply stays well formed.
+    pub fn finish(mut self) -> Result<(), ColorError> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<(), ColorError> {
+        if self.finished {
+            return Ok(())
+        }
+        self.finished = true;
+        match self.len {
+            None => {
+                raw::reply_set_array_length(self.ctx, self.written as c_long);
+                Ok(())
+            }
+            Some(len) if self.written < len => {
+                let missing = len - self.written;
+                for _ in 0..missing {
+                    null(self.ctx)?;
+                }
+                Err(error!("Array reply of {} elements was {} short", len, missing))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl<'a> Drop for ArrayReply<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            let level = format!("{:?}\0", LogLevel::Warning).to_lowercase();
+            raw::log(self.ctx, level.as_ptr(), format!("{}\0", e).as_ptr());
+        }
+    }
+}
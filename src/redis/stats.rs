@@ -0,0 +1,102 @@
+// Counters behind COLOR.INFO, so that usage can be looked at without scanning
+// the keyspace.
+//
+// Key counts are kept by the type callbacks: a value counts from the moment
+// it's handed to Redis (by a command or an RDB load) until Redis frees it.
+// Freeing may happen on a background thread when keys are deleted lazily,
+// hence the atomics. Command counters are only touched by the harness, which
+// always runs on Redis' main thread.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error::ColorError;
+
+/// Live values of one of our data types and the memory they use.
+pub struct TypeStats {
+    keys: AtomicUsize,
+    memory: AtomicUsize,
+}
+
+impl TypeStats {
+    pub const fn new() -> TypeStats {
+        TypeStats { keys: AtomicUsize::new(0), memory: AtomicUsize::new(0) }
+    }
+
+    /// Counts a value of `memory` bytes handed to Redis.
+    pub fn created(&self, memory: usize) {
+        self.keys.fetch_add(1, Ordering::Relaxed);
+        self.memory.fetch_add(memory, Ordering::Relaxed);
+    }
+
+    /// Counts a value of `memory` bytes freed by Redis.
+    pub fn freed(&self, memory: usize) {
+        self.keys.fetch_sub(1, Ordering::Relaxed);
+        self.memory.fetch_sub(memory, Ordering::Relaxed);
+    }
+
+    /// Counts a value that grew or shrank from `before` to `after` bytes.
+    pub fn resized(&self, before: usize, after: usize) {
+        if after >= before {
+            self.memory.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.memory.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+
+    pub fn keys(&self) -> usize {
+        self.keys.load(Ordering::Relaxed)
+    }
+
+    pub fn memory(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.keys.store(0, Ordering::Relaxed);
+        self.memory.store(0, Ordering::Relaxed);
+    }
+}
+
+/// What happened to the calls of one command.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    pub calls: u64,
+
+    /// Calls that failed, whatever the reason.
+    pub errors: u64,
+
+    /// Failed calls whose arguments didn't parse, a subset of `errors`. These
+    /// usually point at a client using the module wrongly.
+    pub parse_failures: u64,
+}
+
+thread_local!(static COMMANDS: RefCell<BTreeMap<&'static str, CommandStats>> = const { RefCell::new(BTreeMap::new()) });
+
+/// Counts a call of `command` that ended with `res`.
+pub fn record(command: &'static str, res: &Result<(), ColorError>) {
+    COMMANDS.with(|commands| {
+        let mut commands = commands.borrow_mut();
+        let stats = commands.entry(command).or_default();
+        stats.calls += 1;
+        if let Err(ref e) = *res {
+            stats.errors += 1;
+            if e.is_parse_failure() {
+                stats.parse_failures += 1;
+            }
+        }
+    })
+}
+
+/// The counters of every command called so far, by name.
+pub fn commands() -> Vec<(&'static str, CommandStats)> {
+    COMMANDS.with(|commands| commands.borrow().iter().map(|(&name, &stats)| (name, stats)).collect())
+}
+
+/// Starts counting from scratch, when the module is loaded.
+pub fn reset() {
+    COMMANDS.with(|commands| commands.borrow_mut().clear());
+    super::COLOR_STATS.reset();
+    super::THEME_STATS.reset();
+}
@@ -0,0 +1,152 @@
+// Tonal scales: the tints and shades of a color, numbered like the 50 to 950
+// scales of Tailwind, written to a palette for stylesheets to use.
+
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use search;
+use {read_color, Color, Space};
+
+// The steps of COLOR.SCALE when STEPS is left out, those of Tailwind.
+const DEFAULT_STEPS: &[u32] = &[50, 100, 200, 300, 400, 500, 600, 700, 800, 900, 950];
+
+// Implements COLOR.SCALE base dest [STEPS s1,s2,...] [SPACE oklch|srgb]
+//
+// Replaces `dest` with a palette, a hash from step to color, holding the
+// tonal scale of the color at `base` (see `Color::tonal_scale`). STEPS lists
+// the steps, each from 0 (the lightest) to 1000 (the darkest), and SPACE how
+// the scale is made, oklch by default. Replies with the palette as
+// step/color pairs from the lightest step to the darkest.
+//
+// `base` is read as of now, which may be halfway through a transition, so the
+// palette is replicated as the fields written rather than verbatim.
+pub struct ScaleColorCommand {}
+impl Command for ScaleColorCommand {
+    fn name(&self) -> &'static str { "color.scale" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn key_spec(&self) -> redis::KeySpec { redis::KeySpec { first: 1, last: 2, step: 1 } }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let mut steps = DEFAULT_STEPS.to_vec();
+        let mut space = Space::Oklch;
+        let mut i = 3;
+        while i < args.len() {
+            if i + 1 >= args.len() {
+                return Err(ColorError::Syntax(format!("{} needs a value", args[i].to_str()?)))
+            }
+            match args[i].to_str()?.to_uppercase().as_str() {
+                "STEPS" => steps = parse_steps(args[i + 1])?,
+                "SPACE" => space = args[i + 1].parse("oklch or srgb")?,
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+            i += 2;
+        }
+
+        let base = read_color(&r, args[1], redis::milliseconds())?;
+        let scale: Vec<(String, Color)> = steps.iter()
+            .map(|step| step.to_string())
+            .zip(base.tonal_scale(&steps, space))
+            .collect();
+
+        let dest = r.open_key_writable(args[2]);
+        dest.delete()?;
+        let unindexed = search::remove(&r, args[2].as_bytes());
+        let mut fields: Vec<Vec<u8>> = vec![args[2].as_bytes().to_vec()];
+        for (step, color) in &scale {
+            let hex = color.to_canonical_hex();
+            dest.hash_set(step.as_bytes(), hex.as_bytes())?;
+            fields.push(step.as_bytes().to_vec());
+            fields.push(hex.into_bytes());
+        }
+        r.replicate("DEL", &[args[2].as_bytes()])?;
+        r.replicate("HMSET", &fields)?;
+        if unindexed {
+            r.replicate("ZREM", &[search::INDEX_KEY.as_bytes(), args[2].as_bytes()])?;
+        }
+
+        let format = config::get().default_format;
+        let mut reply = r.reply_array(scale.len() * 2)?;
+        for (step, color) in scale {
+            reply.string(step)?;
+            reply.string(color.format(format))?;
+        }
+        reply.finish()
+    }
+}
+
+// Reads a comma separated list of steps, which comes out sorted and without
+// duplicates.
+fn parse_steps(arg: redis::RedisStr) -> Result<Vec<u32>, ColorError> {
+    let mut steps = arg.to_str()?.split(',')
+        .map(|s| match s.trim().parse::<u32>() {
+            Ok(step) if step <= 1000 => Ok(step),
+            _ => Err(arg.parse_error("steps from 0 to 1000 separated by commas")),
+        })
+        .collect::<Result<Vec<u32>, ColorError>>()?;
+    steps.sort_unstable();
+    steps.dedup();
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+    use {Color, Oklch};
+
+    // The step/color pairs of a COLOR.SCALE reply.
+    fn scale(reply: Reply) -> Vec<(String, Color)> {
+        let items: Vec<String> = match reply {
+            Reply::Array(items) => items.into_iter().map(|i| match i {
+                Reply::Bulk(s) => String::from_utf8(s).unwrap(),
+                other => panic!("{:?}", other),
+            }).collect(),
+            other => panic!("{:?}", other),
+        };
+        items.chunks(2).map(|pair| (pair[0].clone(), pair[1].parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn scale_is_anchored_and_even() {
+        let mut server = Server::with_colors(&[("brand", "#3b82f6")]);
+        let blue = scale(server.run(&["COLOR.SCALE", "brand", "blue"]));
+        let steps: Vec<&str> = blue.iter().map(|(step, _)| step.as_str()).collect();
+        assert_eq!(steps, ["50", "100", "200", "300", "400", "500", "600", "700", "800", "900", "950"]);
+        assert_eq!(server.hget("blue", "500"), Some("#3b82f6".to_string()));
+
+        let base = "#3b82f6".parse::<Color>().unwrap().to_oklch();
+        let lch: Vec<Oklch> = blue.iter().map(|(_, c)| c.to_oklch()).collect();
+        for pair in lch.windows(2) {
+            assert!(pair[0].l > pair[1].l, "{:?}", lch);
+        }
+        for (step, c) in lch.iter().enumerate() {
+            assert!(c.c <= base.c + 0.005, "{} {:?}", step, c);
+            if c.c > 0.03 {
+                assert!((c.h - base.h).abs() < 3.0, "{} {:?}", step, c);
+            }
+        }
+        assert!(lch[0].l > 0.95 && lch[10].l < 0.3, "{:?}", lch);
+        assert!(lch[0].c < base.c / 2.0);
+    }
+
+    #[test]
+    fn scale_options_and_replication() {
+        let mut server = Server::with_colors(&[("brand", "#3b82f680"), ("out", "#000")]);
+        server.replicated.clear();
+        let out = scale(server.run(&["COLOR.SCALE", "brand", "out", "STEPS", "900, 100,100", "SPACE", "srgb"]));
+        assert_eq!(out.iter().map(|(step, _)| step.as_str()).collect::<Vec<&str>>(), ["100", "900"]);
+        assert!(out.iter().all(|(_, c)| c.to_full_hex().ends_with("80")));
+        assert_eq!(server.replicated, vec![
+            vec!["DEL".to_string(), "out".to_string()],
+            vec!["HMSET".to_string(), "out".to_string(),
+                "100".to_string(), server.hget("out", "100").unwrap(),
+                "900".to_string(), server.hget("out", "900").unwrap()],
+        ]);
+
+        assert!(server.run(&["COLOR.SCALE", "brand", "out", "STEPS", "50,1001"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SCALE", "brand", "out", "SPACE", "hsl"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SCALE", "brand", "out", "STEPS"]).is_error("ERR syntax"));
+        assert!(server.run(&["COLOR.SCALE", "nope", "out"]).is_error("NOTFOUND"));
+    }
+}
@@ -0,0 +1,247 @@
+// Iterating over the keys holding our values, like SCAN but filtered by what
+// they hold. Keys are walked with SCAN itself, so the same guarantees hold: a
+// key there for the whole iteration is returned at least once, and a call can
+// return fewer matches than COUNT, or none, before the cursor gets back to 0.
+
+use error::ColorError;
+use hash::read_palette;
+use redis;
+use redis::{Command, CommandFlags, KeySpec};
+use search::Range;
+use theme::ThemeValue;
+use {Channel, ColorValue};
+
+// What keys COLOR.SCAN looks for with TYPE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyKind {
+    Color,
+    Theme,
+    // A hash holding nothing but colors.
+    Palette,
+}
+
+// A WHERE predicate: a channel of the color within a range.
+struct Predicate {
+    channel: Channel,
+    range: Range,
+}
+
+impl Predicate {
+    // Hues wrap around 360 when the range's min is greater than its max.
+    fn matches(&self, v: f64) -> bool {
+        if self.channel == Channel::Hue && self.range.min > self.range.max {
+            v >= self.range.min || v <= self.range.max
+        } else {
+            self.range.contains(v)
+        }
+    }
+}
+
+// Reads a `min..max` range.
+fn parse_range(arg: redis::RedisStr) -> Result<Range, ColorError> {
+    let s = arg.to_str()?;
+    let bounds = s.find("..").and_then(|i| {
+        let (min, max) = (s[..i].trim().parse::<f64>().ok()?, s[i + 2..].trim().parse::<f64>().ok()?);
+        Some(Range { min, max })
+    });
+    bounds.ok_or_else(|| arg.parse_error("a range such as 200..240"))
+}
+
+// Implements COLOR.SCAN cursor [MATCH pattern] [COUNT n] [TYPE color|theme|palette]
+//     [WHERE channel min..max ...]
+//
+// Runs one SCAN iteration from `cursor`, passing it MATCH and COUNT, and
+// replies like SCAN with the next cursor and the keys found. Only keys
+// holding one of our types are kept, color or theme, or with TYPE only one
+// kind: colors, themes or palettes, hashes whose values are all colors.
+// There's no TYPE gradient: the module has no gradient values, and the closest
+// things, a color mid-transition and the hash COLOR.SCALE writes, are found
+// with TYPE color and TYPE palette. TYPE theme scans the module's other type
+// instead.
+//
+// Telling a palette from any other hash takes reading it whole, so with TYPE
+// palette every hash SCAN returns costs an HGETALL, O(fields). COUNT bounds
+// the number of hashes looked at per call, not their size.
+//
+// WHERE keeps the colors with a channel (see `Channel`, e.g. `h` or `hue`)
+// within a range, both ends included, a hue range whose min is greater than
+// its max wrapping around 360. Colors are read as of now. It can be given
+// several times, and only colors can match it.
+pub struct ScanColorCommand {}
+impl Command for ScanColorCommand {
+    fn name(&self) -> &'static str { "color.scan" }
+    fn arity(&self) -> i32 { -2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::RANDOM }
+    fn key_spec(&self) -> KeySpec { KeySpec::NONE }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let cursor = args[1].parse::<u64>("a cursor")?;
+        let mut scan_args: Vec<Vec<u8>> = vec![cursor.to_string().into_bytes()];
+        let mut kind: Option<KeyKind> = None;
+        let mut predicates: Vec<Predicate> = Vec::new();
+
+        let mut i = 2;
+        while i < args.len() {
+            let option = args[i].to_str()?.to_uppercase();
+            let needs = if option == "WHERE" { 2 } else { 1 };
+            if i + needs >= args.len() {
+                return Err(ColorError::Syntax(format!("{} needs {} values", option, needs)))
+            }
+            match option.as_str() {
+                "MATCH" => scan_args.extend(vec![b"MATCH".to_vec(), args[i + 1].as_bytes().to_vec()]),
+                "COUNT" => {
+                    let count = args[i + 1].parse::<u64>("a count")?;
+                    scan_args.extend(vec![b"COUNT".to_vec(), count.to_string().into_bytes()]);
+                }
+                "TYPE" => {
+                    kind = Some(match args[i + 1].to_str()?.to_lowercase().as_str() {
+                        "color" => KeyKind::Color,
+                        "theme" => KeyKind::Theme,
+                        "palette" => KeyKind::Palette,
+                        _ => return Err(args[i + 1].parse_error("color, theme or palette")),
+                    });
+                }
+                "WHERE" => predicates.push(Predicate {
+                    channel: args[i + 1].to_str()?.parse()?,
+                    range: parse_range(args[i + 2])?,
+                }),
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+            i += needs + 1;
+        }
+
+        let (next, keys) = match r.call("SCAN", &scan_args)? {
+            redis::Reply::Array(ref reply) if reply.len() == 2 => match (&reply[0], &reply[1]) {
+                (redis::Reply::String(next), redis::Reply::Array(keys)) => (next.clone(), keys.iter()
+                    .filter_map(|k| match *k {
+                        redis::Reply::String(ref key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<Vec<u8>>>()),
+                _ => return Err(error!("Unexpected reply to SCAN: {:?}", reply)),
+            },
+            other => return Err(error!("Unexpected reply to SCAN: {:?}", other)),
+        };
+
+        let now = redis::milliseconds();
+        let mut matches: Vec<Vec<u8>> = Vec::new();
+        for name in keys {
+            let key = r.open_key(&name);
+            let found = if key.holds::<ColorValue>() {
+                KeyKind::Color
+            } else if key.holds::<ThemeValue>() {
+                KeyKind::Theme
+            } else if kind == Some(KeyKind::Palette) && key.is_hash() {
+                match read_palette(&r, &name) {
+                    Ok(ref colors) if !colors.is_empty() => KeyKind::Palette,
+                    _ => continue,
+                }
+            } else {
+                continue
+            };
+            if kind.is_some_and(|k| k != found) {
+                continue
+            }
+            if !predicates.is_empty() {
+                let color = match key.value::<ColorValue>() {
+                    Ok(Some(value)) => value.at(now),
+                    _ => continue,
+                };
+                if !predicates.iter().all(|p| p.matches(color.channel(p.channel))) {
+                    continue
+                }
+            }
+            matches.push(name);
+        }
+
+        let mut reply = r.reply_array(2)?;
+        reply.string(next)?;
+        let mut found = reply.array(matches.len())?;
+        for name in matches {
+            found.string(name)?;
+        }
+        found.finish()?;
+        reply.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server};
+
+    // Runs COLOR.SCAN from cursor 0 until it's back to 0, collecting the keys.
+    fn scan_all(server: &mut Server, options: &[&str]) -> Vec<String> {
+        let (mut cursor, mut keys) = ("0".to_string(), Vec::new());
+        loop {
+            let mut args = vec!["COLOR.SCAN", &cursor];
+            args.extend(options);
+            let reply = match server.run(&args) {
+                Reply::Array(reply) => reply,
+                other => panic!("{:?}", other),
+            };
+            match (&reply[0], &reply[1]) {
+                (Reply::Bulk(next), Reply::Array(found)) => {
+                    keys.extend(found.iter().map(|k| match k {
+                        Reply::Bulk(k) => String::from_utf8_lossy(k).into_owned(),
+                        other => panic!("{:?}", other),
+                    }));
+                    cursor = String::from_utf8(next.clone()).unwrap();
+                }
+                other => panic!("{:?}", other),
+            }
+            if cursor == "0" {
+                keys.sort();
+                return keys
+            }
+        }
+    }
+
+    #[test]
+    fn scan_filters_by_type_and_channel() {
+        let mut server = Server::with_colors(&[
+            ("sky", "#3399ff"),
+            ("navy", "#000080"),
+            ("red", "#ff0000"),
+            ("rose", "#ff0040"),
+        ]);
+        server.run(&["THEME.SET", "theme", "brand", "#3366ff"]);
+        server.hset("palette", "a", "#ffffff");
+        server.hset("notes", "a", "not a color");
+
+        assert_eq!(scan_all(&mut server, &["COUNT", "3"]), vec!["navy", "red", "rose", "sky", "theme"]);
+        assert_eq!(scan_all(&mut server, &["MATCH", "r*"]), vec!["red", "rose"]);
+        assert_eq!(scan_all(&mut server, &["TYPE", "theme"]), vec!["theme"]);
+        assert_eq!(scan_all(&mut server, &["TYPE", "palette"]), vec!["palette"]);
+        assert_eq!(scan_all(&mut server, &["WHERE", "hue", "200..250"]), vec!["navy", "sky"]);
+        assert_eq!(scan_all(&mut server, &["WHERE", "h", "200..250", "WHERE", "l", "0..0.3"]), vec!["navy"]);
+        assert_eq!(scan_all(&mut server, &["WHERE", "hue", "340..10"]), vec!["red", "rose"]);
+
+        assert!(server.run(&["COLOR.SCAN", "0", "TYPE", "gradient"]).is_error("ERR invalid argument \"gradient\""));
+        assert!(server.run(&["COLOR.SCAN", "0", "WHERE", "hue", "200"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SCAN", "0", "WHERE", "x", "1..2"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.SCAN", "x"]).is_error("ERR invalid argument"));
+    }
+
+    #[test]
+    fn scan_handles_binary_names() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        server.rename("pink", b"pink\xff");
+        server.run(&["COLOR.SET", "red", "#ff0000"]);
+        server.hset(&b"palette\xfe"[..], &b"\xffbrand"[..], "#3366ff");
+        assert_eq!(scan_all(&mut server, &[]), vec!["pink\u{fffd}", "red"]);
+        assert_eq!(scan_all(&mut server, &["TYPE", "palette"]), vec!["palette\u{fffd}"]);
+        match server.run(&["COLOR.SCAN", "0", "WHERE", "hue", "300..360"]) {
+            Reply::Array(reply) => assert_eq!(reply[1], Reply::Array(vec![Reply::Bulk(b"pink\xff".to_vec())])),
+            other => panic!("{:?}", other),
+        }
+
+        // A binary field is fine in a palette, but can't become a token.
+        server.hset("palette", &b"\xffbrand"[..], "#3366ff");
+        server.hset("palette", "text", "#000000");
+        assert_eq!(scan_all(&mut server, &["TYPE", "palette"]), vec!["palette", "palette\u{fffd}"]);
+        match server.run(&["COLOR.RANDOM", "c", "SEED", "1", "DISTINCTFROM", "palette", "10"]) {
+            Reply::Simple(_) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(server.run(&["THEME.DERIVEDARK", "palette", "dark"]).is_error("ERR field"));
+    }
+}
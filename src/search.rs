@@ -0,0 +1,264 @@
+// A secondary index over color keys that allows range queries on perceptual
+// hue, lightness and chroma.
+//
+// The index is a plain Redis sorted set whose members are the names of color
+// keys and whose scores are their OKLCH hues. Lightness and chroma are checked
+// by reading the candidate keys themselves, so the index stays a single sorted
+// set and a query can never return a stale lightness or chroma.
+//
+// The index is off unless the module is loaded with `INDEX yes`. It then
+// lives at `INDEX_KEY`, in the user keyspace, under a name unlikely to clash
+// with anybody's keys. Keeping it up to date is a side effect of a write that
+// already happened, so when that key holds something else the index is left
+// alone and a warning is logged instead of failing the write. COLOR.SEARCH
+// fails instead, rather than reply as if nothing matched.
+//
+// The module's own commands drop a key from the index when they overwrite its
+// color with another type. Keys deleted or overwritten by anything else are
+// not removed (we get no notification when that happens); they are skipped at
+// query time instead. Being a regular key, the index is also gone after a
+// FLUSHDB, and follows a RENAME, until the next writes rebuild it.
+//
+// Neither the index key nor the keys COLOR.SEARCH reads are declared as keys
+// of the commands: the candidates are only known once the index is read, and
+// Redis wants keys to be arguments. So the index can't be used in a cluster.
+
+use config;
+use error::ColorError;
+use redis;
+use redis::{Command, CommandFlags, KeySpec, LogLevel};
+use Color;
+
+/// Name of the sorted set that holds the hue index. The hash tag keeps it in
+/// one slot, whatever follows it.
+pub const INDEX_KEY: &str = "{dvd-color}:index:hue";
+
+/// Adds or moves `key` in the hue index. Does nothing when the index is
+/// disabled with the INDEX option.
+pub fn update(r: &redis::Redis, key: &[u8], color: &Color) {
+    if let Some(index) = open_index(r) {
+        if let Err(err) = index.zset_add(color.to_oklch().h, key) {
+            r.log(LogLevel::Warning, &format!("Can't index {}: {}", String::from_utf8_lossy(key), err));
+        }
+    }
+}
+
+/// Drops `key` from the hue index, for commands that overwrite a color with
+/// another type. Returns whether the key was indexed, in which case commands
+/// with explicit replication have to replicate the removal.
+pub fn remove(r: &redis::Redis, key: &[u8]) -> bool {
+    match open_index(r) {
+        Some(index) => index.zset_rem(key).unwrap_or_else(|err| {
+            r.log(LogLevel::Warning, &format!("Can't unindex {}: {}", String::from_utf8_lossy(key), err));
+            false
+        }),
+        None => false,
+    }
+}
+
+// Opens the index for writing, unless it's disabled or its key holds another
+// type.
+fn open_index(r: &redis::Redis) -> Option<redis::RedisKeyWritable> {
+    if !config::get().index {
+        return None
+    }
+    let index = r.open_key_writable(INDEX_KEY);
+    if !index.is_empty() && !index.is_zset() {
+        r.log(LogLevel::Warning, &format!("{} isn't a sorted set, the hue index isn't updated", INDEX_KEY));
+        return None
+    }
+    Some(index)
+}
+
+/// An inclusive range over one of the OKLCH components.
+#[derive(Clone, Copy, Debug)]
+pub struct Range { pub min: f64, pub max: f64 }
+
+impl Range {
+    pub fn contains(&self, v: f64) -> bool {
+        v >= self.min && v <= self.max
+    }
+}
+
+/// Parses the min and max following the option at `args[at]`, as in
+/// `HUE min max`.
+pub fn parse_range(args: &[redis::RedisStr], at: usize) -> Result<Range, ColorError> {
+    if at + 2 >= args.len() {
+        return Err(ColorError::Syntax(format!("{} needs a min and a max", args[at].to_str()?)))
+    }
+    let min = args[at + 1].to_f64()?;
+    let max = args[at + 2].to_f64()?;
+    Ok(Range { min, max })
+}
+
+// Implements COLOR.SEARCH HUE min max [LIGHTNESS min max] [CHROMA min max] [LIMIT n]
+//
+// Replies with the names of the color keys whose OKLCH values fall inside all
+// the given ranges, ordered by hue. Hue is in degrees; a range whose min is
+// greater than its max wraps around 360, so `HUE 330 30` finds reds. LIMIT
+// can't go beyond the MAX-SEARCH-RESULTS option.
+pub struct SearchColorCommand {}
+impl Command for SearchColorCommand {
+    fn name(&self) -> &'static str { "color.search" }
+    fn arity(&self) -> i32 { -1 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY }
+    // The keys searched are found through the index, not in the arguments,
+    // which is why the index doesn't work in a cluster.
+    fn key_spec(&self) -> KeySpec { KeySpec::NONE }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let mut hue = Range { min: 0.0, max: 360.0 };
+        let mut lightness = Range { min: 0.0, max: 1.0 };
+        let mut chroma = Range { min: 0.0, max: f64::INFINITY };
+        let mut limit: Option<usize> = None;
+
+        let mut i = 1;
+        while i < args.len() {
+            match args[i].to_str()?.to_uppercase().as_str() {
+                "HUE" => { hue = parse_range(args, i)?; i += 3; }
+                "LIGHTNESS" => { lightness = parse_range(args, i)?; i += 3; }
+                "CHROMA" => { chroma = parse_range(args, i)?; i += 3; }
+                "LIMIT" => {
+                    if i + 1 >= args.len() {
+                        return Err(ColorError::Syntax("LIMIT needs a count".to_string()))
+                    }
+                    limit = Some(args[i + 1].parse("a count")?);
+                    i += 2;
+                }
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+        }
+        let max = config::get().max_search_results;
+        if max > 0 {
+            limit = Some(limit.map_or(max, |l| l.min(max)));
+        }
+        if hue.min < 0.0 || hue.max > 360.0 {
+            return Err(ColorError::OutOfRange("HUE must be within 0 and 360".to_string()))
+        }
+
+        let index = r.open_key(INDEX_KEY);
+        if !index.is_empty() && !index.is_zset() {
+            return Err(error!("{} isn't a sorted set, the hue index is unusable", INDEX_KEY))
+        }
+        let candidates = if hue.min <= hue.max {
+            index.zset_score_range(hue.min, hue.max)?
+        } else {
+            let mut c = index.zset_score_range(hue.min, 360.0)?;
+            c.extend(index.zset_score_range(0.0, hue.max)?);
+            c
+        };
+
+        let mut matches: Vec<Vec<u8>> = Vec::new();
+        for (name, indexed_hue) in candidates {
+            if let Some(l) = limit {
+                if matches.len() >= l {
+                    break
+                }
+            }
+            let key = r.open_key(&name);
+            if !key.valid_key_type() {
+                log_debug!(r, "Skipping stale index entry {:?}", name);
+                continue
+            }
+            // A key in the middle of a transition is indexed by its target.
+            let color: Color = key.read()?.color;
+            let lch = color.to_oklch();
+            // The key may have been rewritten since it was indexed (e.g. by a
+            // RESTORE), so we trust the stored value over the indexed score.
+            if (lch.h - indexed_hue).abs() > 1e-6 {
+                continue
+            }
+            if lightness.contains(lch.l) && chroma.contains(lch.c) {
+                matches.push(name);
+            }
+        }
+
+        let mut reply = r.reply_array(matches.len())?;
+        for m in matches {
+            reply.string(&m)?;
+        }
+        reply.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::INDEX_KEY;
+    use redis::mock::{Reply, Server};
+
+    #[test]
+    fn max_search_results_caps_replies() {
+        let mut server = Server::with_args(&["INDEX", "yes", "MAX-SEARCH-RESULTS", "2"]).unwrap();
+        server.set_colors(&[("a", "#ff0000"), ("b", "#ff0000"), ("c", "#ff0000")]);
+        match server.run(&["COLOR.SEARCH", "HUE", "0", "360"]) {
+            Reply::Array(keys) => assert_eq!(keys.len(), 2),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn search_finds_hue_ranges() {
+        let mut server = Server::with_args(&["INDEX", "yes"]).unwrap();
+        server.set_colors(&[
+            ("red", "#ff0000"),      // hue 29.2
+            ("yellow", "#ffff00"),   // hue 109.8
+            ("green", "#00ff00"),    // hue 142.5
+            ("blue", "#0000ff"),     // hue 264.1
+            ("magenta", "#ff00ff"),  // hue 328.4
+            ("pink", "#ff55ef"),     // hue 331.9
+            ("rose", "#ff0080"),     // hue 2.5
+        ]);
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "100", "150"]), Reply::bulks(&["yellow", "green"]));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "330", "30"]), Reply::bulks(&["pink", "rose", "red"]));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "150", "200"]), Reply::bulks(&[]));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "360", "LIMIT", "2"]), Reply::bulks(&["rose", "red"]));
+        assert!(server.run(&["COLOR.SEARCH", "HUE", "-10", "30"]).is_error("ERR"));
+
+        // Moved keys are found at their new hue only.
+        server.run(&["COLOR.SET", "red", "#0000ff"]);
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "330", "30"]), Reply::bulks(&["pink", "rose"]));
+    }
+
+    #[test]
+    fn search_filters_lightness_and_chroma() {
+        let mut server = Server::with_args(&["INDEX", "yes"]).unwrap();
+        server.set_colors(&[
+            ("red", "#ff0000"),     // l 0.628, c 0.258
+            ("maroon", "#800000"),  // l 0.377, c 0.155
+            ("blush", "#ffcccc"),   // l 0.890, c 0.058
+        ]);
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "30", "LIGHTNESS", "0", "0.5"]), Reply::bulks(&["maroon"]));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "30", "CHROMA", "0.2", "1"]), Reply::bulks(&["red"]));
+        assert_eq!(
+            server.run(&["COLOR.SEARCH", "HUE", "0", "30", "LIGHTNESS", "0.5", "1", "CHROMA", "0", "0.1"]),
+            Reply::bulks(&["blush"]));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "30", "CHROMA", "0.1", "1", "LIMIT", "1"]), Reply::bulks(&["maroon"]));
+    }
+
+    #[test]
+    fn search_index_is_opt_in() {
+        let mut server = Server::with_colors(&[("red", "#ff0000")]);
+        assert!(!server.exists(INDEX_KEY));
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "360"]), Reply::bulks(&[]));
+    }
+
+    #[test]
+    fn search_index_never_fails_writes() {
+        let mut server = Server::with_args(&["INDEX", "yes"]).unwrap();
+        server.hset(INDEX_KEY, "red", "#ff0000");
+        assert_eq!(server.run(&["COLOR.SET", "red", "#ff0000"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "red"]), Reply::simple("#ff0000"));
+        assert!(server.log.iter().any(|(level, m)| level == "warning" && m.contains(INDEX_KEY)));
+        // Searches can't be answered though.
+        assert!(server.run(&["COLOR.SEARCH", "HUE", "0", "360"])
+            .is_error("ERR {dvd-color}:index:hue isn't a sorted set"));
+    }
+
+    #[test]
+    fn search_unindexes_overwritten_colors() {
+        let mut server = Server::with_args(&["INDEX", "yes"]).unwrap();
+        server.set_colors(&[("red", "#ff0000"), ("brand", "#ff0000")]);
+        server.run(&["COLOR.SCALE", "red", "brand", "STEPS", "500"]);
+        assert_eq!(server.run(&["COLOR.SEARCH", "HUE", "0", "360"]), Reply::bulks(&["red"]));
+        assert_eq!(server.replicated.last(), Some(&vec!["ZREM".to_string(), INDEX_KEY.to_string(), "brand".to_string()]));
+    }
+}
@@ -0,0 +1,945 @@
+// Themes: sets of design tokens such as `button.bg` or `text.muted`, each
+// holding either a color or an alias of another token. A theme can inherit
+// from a parent theme, stored at another key, the tokens it doesn't define
+// itself.
+//
+// Aliases are resolved against the theme being resolved, not the one that
+// declares them: if a base theme aliases `button.bg` to `brand.primary`, a
+// theme inheriting from it only has to override `brand.primary` to get its
+// own buttons. Aliases and parents can form cycles; these are reported when
+// resolving rather than rejected when written, since a cycle may go through
+// several keys that are written independently.
+//
+// A theme can also declare pairs of tokens meant to be shown one on the
+// other, such as `text` on `surface`. They're inherited like tokens, and
+// THEME.DERIVEDARK keeps them readable.
+//
+// Parent keys aren't declared as keys of the commands that follow them, so in
+// a cluster a theme and its ancestors need to share a hash slot.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ptr;
+
+use config;
+use error::ColorError;
+use hash::read_palette;
+use libc::{c_int, c_void};
+use redis;
+use redis::{Command, CommandFlags, ModuleValue};
+use redis::raw;
+use search;
+use Color;
+
+/// Version of the RDB encoding of dvd-theme values. Version 1 added the
+/// foreground/background pairs.
+pub const ENCODING_VERSION: c_int = 1;
+
+// Tokens per command in AOF rewrites, which split large themes over several
+// commands as Redis does for its own collections (AOF_REWRITE_ITEMS_PER_CMD).
+const AOF_TOKENS_PER_COMMAND: usize = 64;
+
+// Contrast THEME.DERIVEDARK asks for by default: WCAG AA for body text.
+const DEFAULT_MIN_CONTRAST: f64 = 4.5;
+
+// How far THEME.DERIVEDARK moves a color's lightness at a time.
+const NUDGE_STEP: f64 = 0.01;
+
+// Passes THEME.DERIVEDARK makes over the pairs, since nudging a color for one
+// pair can break another pair sharing it.
+const MAX_NUDGE_PASSES: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Color(Color),
+    /// The name of the token this one stands for.
+    Alias(String),
+}
+
+/// The value held by a dvd-theme key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThemeValue {
+    /// The key of the theme the tokens not defined here are taken from.
+    pub parent: Option<Vec<u8>>,
+    pub tokens: BTreeMap<String, Token>,
+
+    /// Foreground/background pairs of tokens that need to contrast.
+    pub pairs: BTreeSet<(String, String)>,
+}
+
+impl ModuleValue for ThemeValue {
+    fn module_type() -> *mut raw::RedisModuleType {
+        unsafe { redis::THEME_TYPE }
+    }
+
+    fn stats() -> &'static redis::stats::TypeStats {
+        &redis::THEME_STATS
+    }
+
+    // An estimate: the tree's nodes can't be seen from here, so each token is
+    // counted as an entry of the map plus the strings it owns.
+    fn memory(&self) -> usize {
+        let tokens: usize = self.tokens.iter()
+            .map(|(name, token)| {
+                let alias = match *token {
+                    Token::Alias(ref target) => target.capacity(),
+                    Token::Color(_) => 0,
+                };
+                mem::size_of::<(String, Token)>() + name.capacity() + alias
+            })
+            .sum();
+        let pairs: usize = self.pairs.iter()
+            .map(|(fg, bg)| mem::size_of::<(String, String)>() + fg.capacity() + bg.capacity())
+            .sum();
+        mem::size_of::<ThemeValue>()
+            + self.parent.as_ref().map_or(0, |p| p.capacity())
+            + tokens + pairs
+    }
+}
+
+// A theme followed by its ancestors, as open keys. The keys stay open for as
+// long as the values borrowed from them are in use.
+struct Chain {
+    keys: Vec<redis::RedisKey>,
+}
+
+impl Chain {
+    // Opens the theme at `key` and its ancestors. A missing theme has no
+    // tokens, but a missing parent is an error.
+    fn open(r: &redis::Redis, key: &[u8]) -> Result<Chain, ColorError> {
+        let mut keys: Vec<redis::RedisKey> = Vec::new();
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        let mut next = Some(key.to_vec());
+        while let Some(name) = next {
+            if names.contains(&name) {
+                names.push(name);
+                let names: Vec<String> = names.iter().map(|n| String::from_utf8_lossy(n).into_owned()).collect();
+                return Err(error!("inheritance cycle: {}", names.join(" -> ")))
+            }
+            let k = r.open_key(&name);
+            next = match k.value::<ThemeValue>()? {
+                Some(theme) => theme.parent.clone(),
+                None if keys.is_empty() => return Ok(Chain { keys }),
+                None => return Err(ColorError::NotFound(String::from_utf8_lossy(&name).into_owned())),
+            };
+            names.push(name);
+            keys.push(k);
+        }
+        Ok(Chain { keys })
+    }
+
+    // Themes are kept boxed (see `redis::ModuleValue`), so they're always
+    // borrowed from their keys.
+    fn themes(&self) -> Vec<&ThemeValue> {
+        self.keys.iter()
+            .filter_map(|k| match k.value::<ThemeValue>() {
+                Ok(Some(Cow::Borrowed(theme))) => Some(theme),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The theme with everything it inherits copied in, and no parent.
+    fn standalone(&self) -> ThemeValue {
+        let themes = self.themes();
+        let names: BTreeSet<&String> = themes.iter().flat_map(|t| t.tokens.keys()).collect();
+        ThemeValue {
+            parent: None,
+            tokens: names.into_iter()
+                .filter_map(|name| lookup(&themes, name).map(|token| (name.clone(), token.clone())))
+                .collect(),
+            pairs: themes.iter().flat_map(|t| t.pairs.iter().cloned()).collect(),
+        }
+    }
+}
+
+// The definition of `token` nearest to the start of `themes`.
+fn lookup<'a>(themes: &[&'a ThemeValue], token: &str) -> Option<&'a Token> {
+    themes.iter().filter_map(|t| t.tokens.get(token)).next()
+}
+
+// The color `token` stands for in the first theme of `themes`, following
+// aliases. `None` if the token isn't defined at all.
+fn resolve(themes: &[&ThemeValue], token: &str) -> Result<Option<Color>, ColorError> {
+    Ok(resolve_origin(themes, token)?.map(|(_, c)| c))
+}
+
+// Like `resolve`, but also tells which token the color is defined by.
+fn resolve_origin<'a>(themes: &[&'a ThemeValue], token: &'a str) -> Result<Option<(&'a str, Color)>, ColorError> {
+    let mut followed: Vec<&str> = Vec::new();
+    let mut name = token;
+    loop {
+        match lookup(themes, name) {
+            Some(&Token::Color(c)) => return Ok(Some((name, c))),
+            Some(Token::Alias(target)) => {
+                followed.push(name);
+                if followed.contains(&target.as_str()) {
+                    followed.push(target);
+                    return Err(error!("alias cycle: {}", followed.join(" -> ")))
+                }
+                name = target;
+            }
+            None if followed.is_empty() => return Ok(None),
+            None => return Err(error!("{} is an alias of {}, which isn't defined", followed[followed.len() - 1], name)),
+        }
+    }
+}
+
+// Every token visible from the first theme of `themes`, resolved.
+fn flatten(themes: &[&ThemeValue]) -> Result<BTreeMap<String, Color>, ColorError> {
+    let names: BTreeSet<&String> = themes.iter().flat_map(|t| t.tokens.keys()).collect();
+    let mut flat = BTreeMap::new();
+    for name in names {
+        if let Some(c) = resolve(themes, name)? {
+            flat.insert(name.clone(), c);
+        }
+    }
+    Ok(flat)
+}
+
+/// The tokens of the theme at `key`, resolved. Empty if there's no theme.
+pub fn flatten_key(r: &redis::Redis, key: &[u8]) -> Result<BTreeMap<String, Color>, ColorError> {
+    let chain = Chain::open(r, key)?;
+    flatten(&chain.themes())
+}
+
+// Type callbacks for dvd-theme. A theme is saved as its parent, if any, then
+// the number of tokens followed by each token's name, kind and color or
+// alias, then the number of pairs followed by each pair's tokens.
+//
+// As for colors, the callbacks only contain panics and defer to the functions
+// below them.
+pub unsafe extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
+    redis::catch_panic("dvd-theme rdb_load", redis::PanicLog::Io(rdb), || {
+        let value = load_theme_value(rdb, encver);
+        ThemeValue::stats().created(value.memory());
+        value.into_raw()
+    }).unwrap_or(ptr::null_mut())
+}
+
+pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+    redis::catch_panic("dvd-theme rdb_save", redis::PanicLog::Io(rdb), || {
+        save_theme_value(rdb, &ThemeValue::get(value))
+    });
+}
+
+pub unsafe extern "C" fn aof_rewrite(
+    aof: *mut raw::RedisModuleIO,
+    key: *mut raw::RedisModuleString,
+    value: *mut c_void,
+) {
+    redis::catch_panic("dvd-theme aof_rewrite", redis::PanicLog::Io(aof), || {
+        rewrite_theme_value(aof, key, &ThemeValue::get(value))
+    });
+}
+
+pub unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+    redis::catch_panic("dvd-theme mem_usage", redis::PanicLog::Stderr, || {
+        ThemeValue::get(value as *mut c_void).memory()
+    }).unwrap_or(0)
+}
+
+pub unsafe extern "C" fn free(value: *mut c_void) {
+    redis::catch_panic("dvd-theme free", redis::PanicLog::Stderr, || {
+        let value = ThemeValue::from_raw(value);
+        ThemeValue::stats().freed(value.memory());
+    });
+}
+
+fn load_theme_value(rdb: *mut raw::RedisModuleIO, encver: c_int) -> ThemeValue {
+    let mut value = ThemeValue::default();
+    if raw::load_unsigned(rdb) == 1 {
+        value.parent = Some(raw::load_string_buffer(rdb));
+    }
+    for _ in 0..raw::load_unsigned(rdb) {
+        let name = String::from_utf8_lossy(&raw::load_string_buffer(rdb)).into_owned();
+        let token = match raw::load_unsigned(rdb) {
+            1 => Token::Alias(String::from_utf8_lossy(&raw::load_string_buffer(rdb)).into_owned()),
+            _ => Token::Color(Color::unpack(raw::load_unsigned(rdb) as u32)),
+        };
+        value.tokens.insert(name, token);
+    }
+    if encver >= 1 {
+        for _ in 0..raw::load_unsigned(rdb) {
+            let fg = String::from_utf8_lossy(&raw::load_string_buffer(rdb)).into_owned();
+            let bg = String::from_utf8_lossy(&raw::load_string_buffer(rdb)).into_owned();
+            value.pairs.insert((fg, bg));
+        }
+    }
+    value
+}
+
+fn save_theme_value(rdb: *mut raw::RedisModuleIO, v: &ThemeValue) {
+    match v.parent {
+        Some(ref parent) => {
+            raw::save_unsigned(rdb, 1);
+            raw::save_string_buffer(rdb, parent);
+        }
+        None => raw::save_unsigned(rdb, 0),
+    }
+    raw::save_unsigned(rdb, v.tokens.len() as u64);
+    for (name, token) in &v.tokens {
+        raw::save_string_buffer(rdb, name.as_bytes());
+        match *token {
+            Token::Color(c) => {
+                raw::save_unsigned(rdb, 0);
+                raw::save_unsigned(rdb, c.pack() as u64);
+            }
+            Token::Alias(ref target) => {
+                raw::save_unsigned(rdb, 1);
+                raw::save_string_buffer(rdb, target.as_bytes());
+            }
+        }
+    }
+    raw::save_unsigned(rdb, v.pairs.len() as u64);
+    for (fg, bg) in &v.pairs {
+        raw::save_string_buffer(rdb, fg.as_bytes());
+        raw::save_string_buffer(rdb, bg.as_bytes());
+    }
+}
+
+fn rewrite_theme_value(aof: *mut raw::RedisModuleIO, key: *mut raw::RedisModuleString, v: &ThemeValue) {
+    if let Some(ref parent) = v.parent {
+        redis::emit_aof(aof, "theme.inherit", key, &[parent]);
+    }
+    let colors: Vec<(&String, String)> = v.tokens.iter()
+        .filter_map(|(name, token)| match *token {
+            Token::Color(c) => Some((name, c.to_full_hex())),
+            Token::Alias(_) => None,
+        })
+        .collect();
+    for chunk in colors.chunks(AOF_TOKENS_PER_COMMAND) {
+        let args: Vec<&str> = chunk.iter().flat_map(|&(name, ref hex)| vec![name.as_str(), hex.as_str()]).collect();
+        redis::emit_aof(aof, "theme.set", key, &args);
+    }
+    let aliases: Vec<(&String, &String)> = v.tokens.iter()
+        .filter_map(|(name, token)| match *token {
+            Token::Alias(ref target) => Some((name, target)),
+            Token::Color(_) => None,
+        })
+        .collect();
+    for chunk in aliases.chunks(AOF_TOKENS_PER_COMMAND) {
+        let args: Vec<&str> = chunk.iter().flat_map(|&(name, target)| vec![name.as_str(), target.as_str()]).collect();
+        redis::emit_aof(aof, "theme.alias", key, &args);
+    }
+    let pairs: Vec<&(String, String)> = v.pairs.iter().collect();
+    for chunk in pairs.chunks(AOF_TOKENS_PER_COMMAND) {
+        let args: Vec<&str> = chunk.iter().flat_map(|&(fg, bg)| vec![fg.as_str(), bg.as_str()]).collect();
+        redis::emit_aof(aof, "theme.pair", key, &args);
+    }
+}
+
+// Stores `tokens` in the theme at `key`, which is created if it doesn't
+// exist. Returns how many tokens are new.
+fn set_tokens(r: &redis::Redis, key: redis::RedisStr, tokens: Vec<(String, Token)>) -> Result<i64, ColorError> {
+    let key = r.open_key_writable(key);
+    if key.is_empty() {
+        key.set_value(ThemeValue::default())?;
+    }
+    key.update_value(|theme: &mut ThemeValue| {
+        tokens.into_iter().filter(|(name, token)| theme.tokens.insert(name.clone(), token.clone()).is_none()).count() as i64
+    })
+}
+
+// Reads the name of a token, which can be any non-empty UTF-8 string.
+fn token_name(arg: &redis::RedisStr) -> Result<String, ColorError> {
+    let name = arg.to_str()?;
+    if name.is_empty() {
+        return Err(arg.parse_error("a token name"))
+    }
+    Ok(name.to_string())
+}
+
+// Implements THEME.SET key token color [token color ...]
+//
+// Sets each token of the theme at `key` to its color, replacing any alias.
+// Replies with the number of tokens that were added.
+pub struct SetCommand {}
+impl Command for SetCommand {
+    fn name(&self) -> &'static str { "theme.set" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        if !args.len().is_multiple_of(2) {
+            return Err(ColorError::WrongArity(self.name().to_string()))
+        }
+        let tokens = args[2..].chunks(2)
+            .map(|pair| Ok((token_name(&pair[0])?, Token::Color(pair[1].parse("a hex color")?))))
+            .collect::<Result<Vec<(String, Token)>, ColorError>>()?;
+        let added = set_tokens(&r, args[1], tokens)?;
+        r.reply_integer(added)
+    }
+}
+
+// Implements THEME.ALIAS key token target [token target ...]
+//
+// Makes each token an alias of its target token, replacing any color. The
+// target doesn't need to exist yet. Replies with the number of tokens that
+// were added.
+pub struct AliasCommand {}
+impl Command for AliasCommand {
+    fn name(&self) -> &'static str { "theme.alias" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        if !args.len().is_multiple_of(2) {
+            return Err(ColorError::WrongArity(self.name().to_string()))
+        }
+        let tokens = args[2..].chunks(2)
+            .map(|pair| {
+                let (name, target) = (token_name(&pair[0])?, token_name(&pair[1])?);
+                if name == target {
+                    return Err(ColorError::OutOfRange(format!("{} can't be an alias of itself", name)))
+                }
+                Ok((name, Token::Alias(target)))
+            })
+            .collect::<Result<Vec<(String, Token)>, ColorError>>()?;
+        let added = set_tokens(&r, args[1], tokens)?;
+        r.reply_integer(added)
+    }
+}
+
+// Implements THEME.PAIR key fg bg [fg bg ...]
+//
+// Declares that each token `fg` is shown on token `bg` in the theme at `key`,
+// created if needed, so that THEME.DERIVEDARK keeps them apart. Neither token
+// needs to exist yet. Replies with the number of pairs that were added.
+pub struct PairCommand {}
+impl Command for PairCommand {
+    fn name(&self) -> &'static str { "theme.pair" }
+    fn arity(&self) -> i32 { -4 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let pairs = parse_pairs(args, 2, args.len())?;
+        let key = r.open_key_writable(args[1]);
+        if key.is_empty() {
+            key.set_value(ThemeValue::default())?;
+        }
+        let added = key.update_value(|theme: &mut ThemeValue| {
+            pairs.into_iter().filter(|pair| theme.pairs.insert(pair.clone())).count() as i64
+        })?;
+        r.reply_integer(added)
+    }
+}
+
+// Reads the fg/bg pairs in `args[from..to]`.
+fn parse_pairs(args: &[redis::RedisStr], from: usize, to: usize) -> Result<Vec<(String, String)>, ColorError> {
+    if !(to - from).is_multiple_of(2) {
+        return Err(ColorError::Syntax("pairs need a foreground and a background".to_string()))
+    }
+    args[from..to].chunks(2)
+        .map(|pair| {
+            let (fg, bg) = (token_name(&pair[0])?, token_name(&pair[1])?);
+            if fg == bg {
+                return Err(ColorError::OutOfRange(format!("{} can't be paired with itself", fg)))
+            }
+            Ok((fg, bg))
+        })
+        .collect()
+}
+
+// Implements THEME.INHERIT key [parent]
+//
+// Makes the theme at `key`, created if needed, inherit from the theme at
+// `parent`, or from nothing when `parent` is left out.
+pub struct InheritCommand {}
+impl Command for InheritCommand {
+    fn name(&self) -> &'static str { "theme.inherit" }
+    fn arity(&self) -> i32 { -2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let parent = match args.len() {
+            2 => None,
+            3 if args[2].as_bytes() == args[1].as_bytes() => {
+                return Err(ColorError::OutOfRange("a theme can't inherit from itself".to_string()))
+            }
+            3 => Some(args[2].as_bytes().to_vec()),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+        let key = r.open_key_writable(args[1]);
+        if key.is_empty() {
+            key.set_value(ThemeValue::default())?;
+        }
+        key.update_value(|theme: &mut ThemeValue| theme.parent = parent)?;
+        r.reply_simple_string(raw::SIMPLE_OK)
+    }
+}
+
+// Implements THEME.RESOLVE key token [token ...]
+//
+// Replies with the color each token stands for in the theme at `key`, after
+// following aliases and parents, or nil for tokens that aren't defined. Fails
+// on cycles and on aliases of undefined tokens.
+pub struct ResolveCommand {}
+impl Command for ResolveCommand {
+    fn name(&self) -> &'static str { "theme.resolve" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let chain = Chain::open(&r, args[1].as_bytes())?;
+        let themes = chain.themes();
+        let colors = args[2..].iter()
+            .map(|token| resolve(&themes, token.to_str()?))
+            .collect::<Result<Vec<Option<Color>>, ColorError>>()?;
+        let format = config::get().default_format;
+        let mut reply = r.reply_array(colors.len())?;
+        for color in colors {
+            match color {
+                Some(c) => reply.string(c.format(format))?,
+                None => reply.null()?,
+            }
+        }
+        reply.finish()
+    }
+}
+
+// Implements THEME.FLATTEN key
+//
+// Replies with every token visible from the theme at `key`, its own and
+// inherited, as token/color pairs ordered by token.
+pub struct FlattenCommand {}
+impl Command for FlattenCommand {
+    fn name(&self) -> &'static str { "theme.flatten" }
+    fn arity(&self) -> i32 { 2 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let flat = flatten_key(&r, args[1].as_bytes())?;
+        let format = config::get().default_format;
+        let mut reply = r.reply_array(flat.len() * 2)?;
+        for (name, color) in flat {
+            reply.string(name)?;
+            reply.string(color.format(format))?;
+        }
+        reply.finish()
+    }
+}
+
+// Implements THEME.DIFF key other
+//
+// Compares the resolved themes at `key` and `other`. Replies with a
+// [token, color in key, color in other] triple for each token that resolves
+// differently, ordered by token, nil standing for a token a theme lacks.
+pub struct DiffCommand {}
+impl Command for DiffCommand {
+    fn name(&self) -> &'static str { "theme.diff" }
+    fn arity(&self) -> i32 { 3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY }
+    fn key_spec(&self) -> redis::KeySpec { redis::KeySpec { first: 1, last: 2, step: 1 } }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let a = flatten_key(&r, args[1].as_bytes())?;
+        let b = flatten_key(&r, args[2].as_bytes())?;
+        let names: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+        let diff: Vec<(&String, Option<&Color>, Option<&Color>)> = names.into_iter()
+            .map(|name| (name, a.get(name), b.get(name)))
+            .filter(|&(_, x, y)| x != y)
+            .collect();
+
+        let format = config::get().default_format;
+        let mut reply = r.reply_array(diff.len())?;
+        for (name, x, y) in diff {
+            let mut triple = reply.array(3)?;
+            triple.string(name)?;
+            for color in &[x, y] {
+                match *color {
+                    Some(c) => triple.string(c.format(format))?,
+                    None => triple.null()?,
+                }
+            }
+            triple.finish()?;
+        }
+        reply.finish()
+    }
+}
+
+// Implements THEME.DERIVEDARK src dest [MINCONTRAST ratio] [PAIR fg bg ...]
+//
+// Stores at `dest` a dark variant of the theme or palette at `src`: every
+// color has its OKLCH lightness mirrored, keeping its hue and chroma, so that
+// light surfaces turn dark and dark text light while brand colors stay
+// recognizable. Then, for every foreground/background pair, those declared by
+// the theme and those given with PAIR, the foreground's lightness (or the
+// background's, once the foreground can't go any further) is moved away from
+// the other until the pair has a WCAG contrast ratio of at least MINCONTRAST,
+// 4.5 by default.
+//
+// A theme gives a theme holding all its tokens and pairs, inherited ones
+// included, with aliases kept. A palette gives a palette. Whatever `dest` held
+// is replaced. Replies with the number of colors that had to be nudged. Fails
+// if a paired token isn't defined or a pair can't be given the contrast.
+pub struct DeriveDarkCommand {}
+impl Command for DeriveDarkCommand {
+    fn name(&self) -> &'static str { "theme.derivedark" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn key_spec(&self) -> redis::KeySpec { redis::KeySpec { first: 1, last: 2, step: 1 } }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let mut min = DEFAULT_MIN_CONTRAST;
+        let mut extra: Vec<(String, String)> = Vec::new();
+        let mut i = 3;
+        while i < args.len() {
+            match args[i].to_str()?.to_uppercase().as_str() {
+                "MINCONTRAST" => {
+                    if i + 1 >= args.len() {
+                        return Err(ColorError::Syntax("MINCONTRAST needs a ratio".to_string()))
+                    }
+                    min = args[i + 1].to_f64()?;
+                    if !(1.0..=21.0).contains(&min) {
+                        return Err(ColorError::OutOfRange("MINCONTRAST must be within 1 and 21".to_string()))
+                    }
+                    i += 2;
+                }
+                "PAIR" => {
+                    if i + 2 >= args.len() {
+                        return Err(ColorError::Syntax("PAIR needs a foreground and a background".to_string()))
+                    }
+                    extra.extend(parse_pairs(args, i + 1, i + 3)?);
+                    i += 3;
+                }
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+        }
+
+        let (mut theme, palette) = {
+            let src = r.open_key(args[1]);
+            if src.is_empty() {
+                return Err(ColorError::NotFound(args[1].to_str()?.to_string()))
+            }
+            if src.is_hash() {
+                // Token names are UTF-8, palette fields needn't be.
+                let tokens = read_palette(&r, args[1].as_bytes())?.into_iter()
+                    .map(|(name, c)| match String::from_utf8(name) {
+                        Ok(name) => Ok((name, Token::Color(c))),
+                        Err(err) => Err(error!(
+                            "field {:?} of {} can't be a token name",
+                            String::from_utf8_lossy(err.as_bytes()), String::from_utf8_lossy(args[1].as_bytes()),
+                        )),
+                    })
+                    .collect::<Result<_, ColorError>>()?;
+                (ThemeValue { parent: None, tokens, pairs: BTreeSet::new() }, true)
+            } else if src.holds::<ThemeValue>() {
+                (Chain::open(&r, args[1].as_bytes())?.standalone(), false)
+            } else {
+                return Err(ColorError::WrongType)
+            }
+        };
+        let pairs: BTreeSet<(String, String)> = theme.pairs.iter().cloned().chain(extra).collect();
+        let nudged = derive_dark(&mut theme, &pairs, min)?;
+
+        let dest = r.open_key_writable(args[2]);
+        dest.delete()?;
+        search::remove(&r, args[2].as_bytes());
+        if palette {
+            for (name, token) in &theme.tokens {
+                if let Token::Color(c) = *token {
+                    dest.hash_set(name.as_bytes(), c.to_canonical_hex().as_bytes())?;
+                }
+            }
+        } else {
+            dest.set_value(theme)?;
+        }
+        r.reply_integer(nudged as i64)
+    }
+}
+
+// Turns `theme` dark as THEME.DERIVEDARK describes. Returns how many colors
+// were nudged after their lightness was mirrored.
+fn derive_dark(theme: &mut ThemeValue, pairs: &BTreeSet<(String, String)>, min: f64) -> Result<usize, ColorError> {
+    for token in theme.tokens.values_mut() {
+        if let Token::Color(ref mut c) = *token {
+            let mut lch = c.to_oklch();
+            lch.l = 1.0 - lch.l;
+            *c = lch.to_color(c.a);
+        }
+    }
+    let mirrored = theme.tokens.clone();
+    for _ in 0..MAX_NUDGE_PASSES {
+        let mut nudged = false;
+        for (fg, bg) in pairs {
+            nudged |= nudge_pair(theme, fg, bg, min)?;
+        }
+        if !nudged {
+            break
+        }
+    }
+    for (fg, bg) in pairs {
+        let (_, fg_color) = origin(theme, fg)?;
+        let (_, bg_color) = origin(theme, bg)?;
+        if fg_color.contrast(&bg_color) < min {
+            return Err(error!("can't give {} on {} a contrast of {}", fg, bg, min))
+        }
+    }
+    Ok(theme.tokens.iter().filter(|&(name, token)| mirrored.get(name) != Some(token)).count())
+}
+
+// Moves the lightness of the color behind `fg` away from that of `bg`, or the
+// other way round once `fg` is as light or dark as it gets, until they
+// contrast by `min`. Returns whether anything moved.
+fn nudge_pair(theme: &mut ThemeValue, fg: &str, bg: &str, min: f64) -> Result<bool, ColorError> {
+    let (fg_name, mut fg_color) = origin(theme, fg)?;
+    let (bg_name, mut bg_color) = origin(theme, bg)?;
+    if fg_name == bg_name {
+        return Err(error!("{} and {} are both {}, so they can't contrast", fg, bg, fg_name))
+    }
+    let mut nudged = false;
+    // Each step moves a color's lightness one NUDGE_STEP closer to 0 or 1.
+    for _ in 0..(2.0 / NUDGE_STEP) as usize {
+        if fg_color.contrast(&bg_color) >= min {
+            break
+        }
+        let lighter = fg_color.to_oklch().l >= bg_color.to_oklch().l;
+        let moved = nudge(fg_color, lighter);
+        if moved != fg_color {
+            fg_color = moved;
+        } else {
+            let moved = nudge(bg_color, !lighter);
+            if moved == bg_color {
+                break
+            }
+            bg_color = moved;
+        }
+        nudged = true;
+    }
+    theme.tokens.insert(fg_name, Token::Color(fg_color));
+    theme.tokens.insert(bg_name, Token::Color(bg_color));
+    Ok(nudged)
+}
+
+// `c` one NUDGE_STEP lighter, or darker.
+fn nudge(c: Color, lighter: bool) -> Color {
+    let mut lch = c.to_oklch();
+    lch.l = if lighter { lch.l + NUDGE_STEP } else { lch.l - NUDGE_STEP }.clamp(0.0, 1.0);
+    lch.to_color(c.a)
+}
+
+// The token defining the color `token` stands for in `theme`, and the color.
+fn origin(theme: &ThemeValue, token: &str) -> Result<(String, Color), ColorError> {
+    match resolve_origin(&[theme], token)? {
+        Some((name, c)) => Ok((name.to_string(), c)),
+        None => Err(error!("{} is paired but isn't defined", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{RdbValue, Reply, Server};
+    use Color;
+
+    fn bulks(values: &[Option<&str>]) -> Reply {
+        Reply::Array(values.iter().map(|v| match *v {
+            Some(s) => Reply::Bulk(s.as_bytes().to_vec()),
+            None => Reply::Null,
+        }).collect())
+    }
+
+    // A base theme, and a dark one that only overrides what it needs to.
+    fn themes(server: &mut Server) {
+        server.run(&["THEME.SET", "base", "brand", "#3366ff", "surface", "#ffffff", "ink", "#111111"]);
+        server.run(&["THEME.ALIAS", "base", "button.bg", "brand", "text", "ink"]);
+        server.run(&["THEME.SET", "dark", "surface", "#101010", "ink", "#eeeeee"]);
+        server.run(&["THEME.INHERIT", "dark", "base"]);
+    }
+
+    #[test]
+    fn theme_resolves_through_aliases_and_parents() {
+        let mut server = Server::new();
+        themes(&mut server);
+        assert_eq!(server.run(&["THEME.RESOLVE", "base", "button.bg", "text", "nope"]),
+            bulks(&[Some("#3366ff"), Some("#111111"), None]));
+        // Aliases declared by the parent follow the child's overrides.
+        assert_eq!(server.run(&["THEME.RESOLVE", "dark", "button.bg", "text", "surface"]),
+            bulks(&[Some("#3366ff"), Some("#eeeeee"), Some("#101010")]));
+        assert_eq!(server.run(&["THEME.RESOLVE", "missing", "text"]), bulks(&[None]));
+
+        assert_eq!(server.run(&["THEME.SET", "dark", "brand", "#99bbff", "ink", "#ffffff"]), Reply::Integer(1));
+        assert_eq!(server.run(&["THEME.RESOLVE", "dark", "button.bg"]), bulks(&[Some("#99bbff")]));
+        assert_eq!(server.run(&["THEME.INHERIT", "dark"]), Reply::simple("OK"));
+        assert_eq!(server.run(&["THEME.RESOLVE", "dark", "brand", "button.bg"]), bulks(&[Some("#99bbff"), None]));
+    }
+
+    #[test]
+    fn theme_reports_cycles_and_dangling_aliases() {
+        let mut server = Server::new();
+        server.run(&["THEME.ALIAS", "t", "a", "b", "b", "c", "c", "a"]);
+        assert!(server.run(&["THEME.RESOLVE", "t", "a"]).is_error("ERR alias cycle: a -> b -> c -> a"));
+        assert!(server.run(&["THEME.ALIAS", "t", "d", "d"]).is_error("ERR"));
+        server.run(&["THEME.ALIAS", "t", "e", "f"]);
+        assert!(server.run(&["THEME.RESOLVE", "t", "e"]).is_error("ERR e is an alias of f"));
+
+        server.run(&["THEME.INHERIT", "x", "y"]);
+        server.run(&["THEME.INHERIT", "y", "x"]);
+        assert!(server.run(&["THEME.FLATTEN", "x"]).is_error("ERR inheritance cycle: x -> y -> x"));
+        assert!(server.run(&["THEME.INHERIT", "x", "x"]).is_error("ERR"));
+        server.run(&["THEME.INHERIT", "x", "gone"]);
+        assert!(server.run(&["THEME.RESOLVE", "x", "a"]).is_error("NOTFOUND"));
+    }
+
+    #[test]
+    fn theme_flatten_and_diff() {
+        let mut server = Server::new();
+        themes(&mut server);
+        assert_eq!(server.run(&["THEME.FLATTEN", "dark"]), Reply::bulks(&[
+            "brand", "#3366ff", "button.bg", "#3366ff", "ink", "#eeeeee", "surface", "#101010", "text", "#eeeeee",
+        ]));
+        server.run(&["THEME.SET", "dark", "focus", "#ffcc00"]);
+        assert_eq!(server.run(&["THEME.DIFF", "base", "dark"]), Reply::Array(vec![
+            bulks(&[Some("focus"), None, Some("#ffcc00")]),
+            bulks(&[Some("ink"), Some("#111111"), Some("#eeeeee")]),
+            bulks(&[Some("surface"), Some("#ffffff"), Some("#101010")]),
+            bulks(&[Some("text"), Some("#111111"), Some("#eeeeee")]),
+        ]));
+        assert_eq!(server.run(&["THEME.DIFF", "dark", "dark"]), Reply::Array(vec![]));
+    }
+
+    #[test]
+    fn theme_rdb_and_aof_round_trip() {
+        let mut server = Server::new();
+        themes(&mut server);
+        let rdb = server.dump("dark");
+        server.restore("copy", &rdb).unwrap();
+        assert_eq!(server.run(&["THEME.FLATTEN", "copy"]), server.run(&["THEME.FLATTEN", "dark"]));
+
+        let aof = server.rewrite_aof("base");
+        assert_eq!(aof, vec![
+            vec!["theme.set", "base", "brand", "#3366ffff", "ink", "#111111ff", "surface", "#ffffffff"],
+            vec!["theme.alias", "base", "button.bg", "brand", "text", "ink"],
+        ]);
+        let aof: Vec<Vec<String>> = aof.into_iter().chain(server.rewrite_aof("dark")).collect();
+        let flat = server.run(&["THEME.FLATTEN", "dark"]);
+        drop(server);
+        let mut server = Server::new();
+        for command in &aof {
+            let args: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            assert!(!server.run(&args).is_error(""));
+        }
+        assert_eq!(server.run(&["THEME.FLATTEN", "dark"]), flat);
+    }
+
+    #[test]
+    fn theme_aof_splits_large_themes() {
+        let mut server = Server::new();
+        let names: Vec<String> = (0..70).map(|i| format!("t{:02}", i)).collect();
+        let mut args = vec!["THEME.SET", "big"];
+        for name in &names {
+            args.extend(&[name.as_str(), "#123456"]);
+        }
+        server.run(&args);
+        let aliases: Vec<String> = (0..66).map(|i| format!("a{:02}", i)).collect();
+        let mut args = vec!["THEME.ALIAS", "big"];
+        let mut pairs = vec!["THEME.PAIR", "big"];
+        for (name, alias) in names.iter().zip(&aliases) {
+            args.extend(&[alias.as_str(), name.as_str()]);
+            pairs.extend(&[name.as_str(), alias.as_str()]);
+        }
+        assert_eq!(server.run(&args), Reply::Integer(66));
+        assert_eq!(server.run(&pairs), Reply::Integer(66));
+        let aof = server.rewrite_aof("big");
+        assert_eq!(aof.iter().map(|c| (c[0].as_str(), c.len())).collect::<Vec<_>>(), vec![
+            ("theme.set", 2 + 128), ("theme.set", 2 + 12),
+            ("theme.alias", 2 + 128), ("theme.alias", 2 + 4),
+            ("theme.pair", 2 + 128), ("theme.pair", 2 + 4),
+        ]);
+        assert_eq!(&aof[1][..4], &["theme.set", "big", "t64", "#123456ff"]);
+
+        let flat = server.run(&["THEME.FLATTEN", "big"]);
+        drop(server);
+        let mut server = Server::new();
+        for command in &aof {
+            let args: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            assert!(!server.run_loading(&args).is_error(""), "{:?}", command);
+        }
+        assert_eq!(server.run(&["THEME.FLATTEN", "big"]), flat);
+    }
+
+    #[test]
+    fn theme_is_its_own_type() {
+        let mut server = Server::new();
+        themes(&mut server);
+        let usage = server.run(&["COLOR.INFO", "types"]).info_field("type_dvd-theme").unwrap();
+        let memory = server.mem_usage("base") + server.mem_usage("dark");
+        assert_eq!(usage, format!("keys=2,memory={},encver=1", memory));
+        server.run(&["THEME.SET", "dark", "a.much.longer.token.name", "#000"]);
+        let memory = server.mem_usage("base") + server.mem_usage("dark");
+        assert_eq!(server.run(&["COLOR.INFO", "types"]).info_field("type_dvd-theme"),
+            Some(format!("keys=2,memory={},encver=1", memory)));
+
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert!(server.run(&["THEME.SET", "pink", "a", "#000"]).is_error("WRONGTYPE"));
+        assert!(server.run(&["THEME.RESOLVE", "pink", "a"]).is_error("WRONGTYPE"));
+        assert!(server.run(&["COLOR.GET", "base"]).is_error("WRONGTYPE"));
+        assert!(server.run(&["THEME.SET", "base", "a"]).is_error("ERR wrong number"));
+    }
+
+    fn color(reply: Reply) -> Color {
+        match reply {
+            Reply::Array(ref colors) if colors.len() == 1 => match colors[0] {
+                Reply::Bulk(ref hex) => String::from_utf8(hex.clone()).unwrap().parse().unwrap(),
+                ref other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn theme_derivedark_mirrors_and_keeps_pairs_readable() {
+        let mut server = Server::new();
+        themes(&mut server);
+        server.run(&["THEME.SET", "base", "button.text", "#ffffff", "muted", "#8a8a8a"]);
+        server.run(&["THEME.PAIR", "base", "text", "surface", "button.text", "button.bg"]);
+        server.run(&["THEME.PAIR", "dark", "muted", "surface"]);
+        match server.run(&["THEME.DERIVEDARK", "dark", "night", "MINCONTRAST", "7"]) {
+            Reply::Integer(n) => assert!(n > 0),
+            other => panic!("{:?}", other),
+        }
+        let get = |server: &mut Server, token: &str| color(server.run(&["THEME.RESOLVE", "night", token]));
+
+        // The dark theme's dark surface turns light, its light ink dark.
+        assert!(get(&mut server, "surface").to_oklch().l > 0.8);
+        assert!(get(&mut server, "text").to_oklch().l < 0.3);
+        let (brand, night_brand) = ("#3366ff".parse::<Color>().unwrap().to_oklch(), get(&mut server, "brand").to_oklch());
+        assert!((brand.h - night_brand.h).abs() < 5.0, "{:?} {:?}", brand, night_brand);
+        for &(fg, bg) in &[("text", "surface"), ("button.text", "button.bg"), ("muted", "surface")] {
+            let contrast = get(&mut server, fg).contrast(&get(&mut server, bg));
+            assert!(contrast >= 7.0, "{} on {}: {}", fg, bg, contrast);
+        }
+        // Inherited tokens and pairs are copied, aliases kept.
+        assert_eq!(get(&mut server, "button.bg"), get(&mut server, "brand"));
+        server.run(&["THEME.SET", "night", "brand", "#ff0000"]);
+        assert_eq!(get(&mut server, "button.bg"), "#ff0000".parse().unwrap());
+        assert_eq!(server.rewrite_aof("night").last().unwrap(),
+            &vec!["theme.pair", "night", "button.text", "button.bg", "muted", "surface", "text", "surface"]);
+    }
+
+    #[test]
+    fn theme_derivedark_turns_palettes_into_palettes() {
+        let mut server = Server::new();
+        server.hset("light", "bg", "#ffffff");
+        server.hset("light", "fg", "#777777");
+        server.run(&["COLOR.SET", "dark", "#000000"]);
+        assert_eq!(server.run(&["THEME.DERIVEDARK", "light", "dark", "PAIR", "fg", "bg"]), Reply::Integer(1));
+        let bg: Color = server.hget("dark", "bg").unwrap().parse().unwrap();
+        let fg: Color = server.hget("dark", "fg").unwrap().parse().unwrap();
+        assert_eq!(bg, "#000000".parse().unwrap());
+        assert!(fg.contrast(&bg) >= 4.5);
+        assert_eq!(server.run(&["THEME.DERIVEDARK", "light", "dark"]), Reply::Integer(0));
+
+        assert!(server.run(&["THEME.DERIVEDARK", "nope", "dark"]).is_error("NOTFOUND"));
+        assert!(server.run(&["THEME.DERIVEDARK", "light", "dark", "PAIR", "fg", "link"]).is_error("ERR link is paired"));
+        assert!(server.run(&["THEME.DERIVEDARK", "light", "dark", "PAIR", "fg", "fg"]).is_error("ERR"));
+        assert!(server.run(&["THEME.DERIVEDARK", "light", "dark", "MINCONTRAST", "22"]).is_error("ERR"));
+        assert!(server.run(&["THEME.DERIVEDARK", "light", "dark", "PAIR", "fg"]).is_error("ERR syntax"));
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert!(server.run(&["THEME.DERIVEDARK", "pink", "dark"]).is_error("WRONGTYPE"));
+    }
+
+    #[test]
+    fn theme_rdb_loads_version_0() {
+        let mut server = Server::new();
+        themes(&mut server);
+        let mut rdb = server.dump("base");
+        assert_eq!(rdb.values.pop(), Some(RdbValue::Unsigned(0)));
+        rdb.encver = 0;
+        server.restore("old", &rdb).unwrap();
+        assert_eq!(server.run(&["THEME.FLATTEN", "old"]), server.run(&["THEME.FLATTEN", "base"]));
+    }
+}
@@ -0,0 +1,335 @@
+// Server side color transitions.
+//
+// A transition is stored alongside the color it is heading for and is
+// evaluated lazily: every read computes the color from the Redis clock, so all
+// clients see the same fade without anything being written while it runs. Once
+// the duration has passed, reads simply return the target.
+
+use std::fmt;
+use std::str::FromStr;
+
+use config;
+use error::ColorError;
+use history;
+use redis;
+use redis::{Command, CommandFlags, Replication};
+use redis::raw;
+use time::Duration;
+use {color_changed, replicate_color_value, Color, ColorValue, Space};
+
+/// An easing function mapping linear progress in [0, 1] to eased progress.
+/// These follow the CSS definitions of the functions with the same names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    CubicBezier(f64, f64, f64, f64),
+    Steps(u32),
+}
+
+pub const EASE_IN_OUT: Easing = Easing::CubicBezier(0.42, 0.0, 0.58, 1.0);
+
+impl Easing {
+    pub fn apply(&self, p: f64) -> f64 {
+        let p = p.clamp(0.0, 1.0);
+        match *self {
+            Easing::Linear => p,
+            Easing::Steps(n) => (p * n as f64).floor() / n as f64,
+            Easing::CubicBezier(x1, y1, x2, y2) => {
+                if p == 0.0 || p == 1.0 {
+                    return p
+                }
+                let t = solve_bezier_x(p, x1, x2);
+                bezier(t, y1, y2)
+            }
+        }
+    }
+}
+
+// One coordinate of a cubic bezier from (0, 0) to (1, 1) with control points
+// p1 and p2, at parameter t.
+fn bezier(t: f64, p1: f64, p2: f64) -> f64 {
+    let u = 1.0 - t;
+    3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t
+}
+
+// Finds the parameter t for which the curve's x coordinate equals x. The x
+// coordinate is monotonic because x1 and x2 are confined to [0, 1], so a
+// bisection always converges.
+fn solve_bezier_x(x: f64, x1: f64, x2: f64) -> f64 {
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..40 {
+        let mid = (lo + hi) / 2.0;
+        if bezier(mid, x1, x2) < x { lo = mid } else { hi = mid }
+    }
+    (lo + hi) / 2.0
+}
+
+impl FromStr for Easing {
+    type Err = ColorError;
+
+    fn from_str(s: &str) -> Result<Easing, ColorError> {
+        let lower = s.to_lowercase();
+        let lower = lower.trim();
+        if lower == "linear" {
+            return Ok(Easing::Linear)
+        }
+        if lower == "ease-in-out" {
+            return Ok(EASE_IN_OUT)
+        }
+        if let Some(params) = function_params(lower, "cubic-bezier") {
+            let p = params.iter()
+                .map(|v| v.parse::<f64>().map_err(|_| ColorError::parse(0, s, "cubic-bezier(x1,y1,x2,y2)")))
+                .collect::<Result<Vec<f64>, ColorError>>()?;
+            if p.len() != 4 {
+                return Err(ColorError::parse(0, s, "cubic-bezier(x1,y1,x2,y2)"))
+            }
+            if !(0.0..=1.0).contains(&p[0]) || !(0.0..=1.0).contains(&p[2]) {
+                return Err(ColorError::OutOfRange("cubic-bezier() x coordinates must be within 0 and 1".to_string()))
+            }
+            if !p[1].is_finite() || !p[3].is_finite() {
+                return Err(ColorError::OutOfRange("cubic-bezier() y coordinates must be finite".to_string()))
+            }
+            return Ok(Easing::CubicBezier(p[0], p[1], p[2], p[3]))
+        }
+        if let Some(params) = function_params(lower, "steps") {
+            let n = match params.as_slice() {
+                [n] => n.parse::<u32>().map_err(|_| ColorError::parse(0, s, "steps(n)"))?,
+                _ => return Err(ColorError::parse(0, s, "steps(n)")),
+            };
+            if n == 0 {
+                return Err(ColorError::OutOfRange("steps() needs at least 1 step".to_string()))
+            }
+            return Ok(Easing::Steps(n))
+        }
+        Err(ColorError::parse(0, s, "an easing function"))
+    }
+}
+
+// Splits "name(a, b, c)" into its comma separated parameters.
+fn function_params<'a>(s: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    if !s.starts_with(name) || !s.ends_with(')') {
+        return None
+    }
+    let inner = s[name.len()..s.len() - 1].trim().strip_prefix('(')?;
+    Some(inner.split(',').map(|p| p.trim()).collect())
+}
+
+impl fmt::Display for Easing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Easing::Linear => write!(f, "linear"),
+            Easing::CubicBezier(x1, y1, x2, y2) => write!(f, "cubic-bezier({},{},{},{})", x1, y1, x2, y2),
+            Easing::Steps(n) => write!(f, "steps({})", n),
+        }
+    }
+}
+
+/// A running transition towards the color of the `ColorValue` holding it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transition {
+    pub from: Color,
+    /// Start of the transition in Unix milliseconds, per the Redis clock.
+    pub start_ms: i64,
+    pub duration: Duration,
+    pub easing: Easing,
+    pub space: Space,
+}
+
+impl Transition {
+    /// The color on the way from `from` to `to` at `now_ms`.
+    pub fn at(&self, to: &Color, now_ms: i64) -> Color {
+        // AT and RDB files can put the start anywhere, so don't let the
+        // arithmetic overflow.
+        let elapsed = now_ms.saturating_sub(self.start_ms);
+        let total = self.duration.whole_milliseconds() as i64;
+        if elapsed <= 0 {
+            return self.from
+        }
+        if elapsed >= total {
+            return *to
+        }
+        let p = self.easing.apply(elapsed as f64 / total as f64);
+        self.from.mix(to, p, self.space)
+    }
+
+    pub fn is_finished(&self, now_ms: i64) -> bool {
+        now_ms >= self.start_ms.saturating_add(self.duration.whole_milliseconds() as i64)
+    }
+}
+
+// Implements COLOR.TRANSITION key target DURATION ms [EASING e] [SPACE srgb|oklch] [AT unix-ms]
+//     [FROM color] [REVISION ms client]
+//
+// Starts fading the color at `key` from its current value (which may itself
+// be mid-transition) to `target`. EASING is one of linear (the default),
+// ease-in-out, cubic-bezier(x1,y1,x2,y2) or steps(n), and SPACE is the color
+// space to interpolate in, srgb by default. AT pins the start time and FROM
+// the color to start from, in which case the key doesn't need to exist.
+// REVISION records the write as COLOR.SET does. Those three are how the
+// master and the AOF replay transitions, and clients can't send them.
+// DURATION can't exceed the MAX-TRANSITION-MS option, except in replays.
+//
+// Where the transition starts from depends on when the command runs, so it is
+// replicated as the COLOR.TRANSITION ... AT ... FROM it resolved to, which is
+// also how it's replayed from the AOF.
+pub struct TransitionColorCommand {}
+impl Command for TransitionColorCommand {
+    fn name(&self) -> &'static str { "color.transition" }
+    fn arity(&self) -> i32 { -5 }
+    fn flags(&self) -> CommandFlags { CommandFlags::WRITE | CommandFlags::DENY_OOM }
+    fn replication(&self) -> Replication { Replication::Explicit }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let target: Color = args[2].parse("a hex color")?;
+        let mut duration: Option<Duration> = None;
+        let mut easing = Easing::Linear;
+        let mut space = Space::Srgb;
+        let now = redis::milliseconds();
+        let mut start_ms = now;
+        let mut from: Option<Color> = None;
+        let (mut ms, mut client) = (now, r.client_id());
+        let mut i = 3;
+        while i < args.len() {
+            if args[i].eq_ignore_case("revision") {
+                let revision = history::parse_revision(&r, args, i)?;
+                ms = revision.0;
+                client = revision.1;
+                i += 3;
+                continue
+            }
+            if i + 1 >= args.len() {
+                return Err(ColorError::Syntax(format!("{} needs a value", args[i].to_str()?)))
+            }
+            match args[i].to_str()?.to_uppercase().as_str() {
+                "DURATION" => {
+                    let ms = args[i + 1].to_i64()?;
+                    if ms < 0 {
+                        return Err(ColorError::OutOfRange("DURATION can't be negative".to_string()))
+                    }
+                    // The limit is for clients. What the master or the AOF
+                    // replays was accepted once already, maybe under a
+                    // higher limit, and is kept.
+                    let max = config::get().max_transition_ms;
+                    if max > 0 && ms > max && !r.is_replayed() {
+                        return Err(ColorError::OutOfRange(format!("DURATION can't exceed {} ms", max)))
+                    }
+                    duration = Some(Duration::milliseconds(ms));
+                }
+                "EASING" => easing = args[i + 1].parse("an easing function")?,
+                "SPACE" => space = args[i + 1].parse("srgb or oklch")?,
+                "AT" | "FROM" if !r.is_replayed() => {
+                    return Err(ColorError::Syntax(format!("{} is only accepted from the master or the AOF",
+                        args[i].to_str()?.to_uppercase())))
+                }
+                "AT" => start_ms = args[i + 1].to_i64()?,
+                "FROM" => from = Some(args[i + 1].parse("a hex color")?),
+                other => return Err(ColorError::Syntax(format!("unknown option {}", other))),
+            }
+            i += 2;
+        }
+        let duration = match duration {
+            Some(d) => d,
+            None => return Err(ColorError::Syntax("DURATION is required".to_string())),
+        };
+
+        let key = r.open_key_writable(args[1]);
+        if key.is_empty() && from.is_none() {
+            return Err(ColorError::NotFound(String::from_utf8_lossy(args[1].as_bytes()).into_owned()))
+        }
+        if !key.is_empty() && !key.valid_key_type() {
+            return Err(ColorError::WrongType)
+        }
+        let old = key.read()?.map(|v| v.at(now));
+        let from = from.or(old).unwrap_or(target);
+        let transition = Transition { from, start_ms, duration, easing, space };
+        let value = ColorValue {
+            color: target,
+            transition: if transition.is_finished(now) { None } else { Some(transition) },
+            history: Vec::new(),
+        };
+        let value = key.write_as(value, ms, client)?;
+        replicate_color_value(&r, args[1].as_bytes(), &value)?;
+        color_changed(&r, self.name(), args[1].as_bytes(), old.as_ref(), &target);
+        r.reply_simple_string(raw::SIMPLE_OK)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server, START_MS};
+
+    #[test]
+    fn transitions_replicate_resolved_values() {
+        let mut server = Server::with_colors(&[("pink", "#000000")]);
+        server.run(&["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000"]);
+        server.set_time(START_MS + 500);
+        server.run(&["COLOR.TRANSITION", "pink", "#ff0000", "DURATION", "1000", "EASING", "steps(2)"]);
+        let at = (START_MS + 500).to_string();
+        assert_eq!(server.replicated[2..], [
+            vec!["color.transition", "pink", "#ff0000ff", "DURATION", "1000", "EASING", "steps(2)",
+                "SPACE", "srgb", "AT", &at, "FROM", "#808080ff"],
+        ]);
+
+        // The transition replays on a key that doesn't exist yet.
+        let replicated = server.replicated[2].clone();
+        drop(server);
+        let mut server = Server::new();
+        let args: Vec<&str> = replicated.iter().map(|a| a.as_str()).collect();
+        assert_eq!(server.run_replicated(&args), Reply::simple("OK"));
+        server.set_time(START_MS + 500);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#808080"));
+
+        // Once over, a transition is just the color it led to.
+        server.replicated.clear();
+        server.run(&["COLOR.TRANSITION", "pink", "#00ff00", "DURATION", "0"]);
+        assert_eq!(server.replicated, vec![vec!["color.set", "pink", "#00ff00ff"]]);
+    }
+
+    #[test]
+    fn transition_rejects_bad_easings() {
+        let mut server = Server::with_colors(&[("pink", "#000000")]);
+        for easing in &["cubic-bezier(0,nan,1,1)", "cubic-bezier(0,0,1,inf)", "cubic-bezier(1.5,0,1,1)", "steps(0)", "bounce"] {
+            let reply = server.run(&["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000", "EASING", easing]);
+            assert!(reply.is_error("ERR"), "{}: {:?}", easing, reply);
+        }
+        let overshoot = ["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000", "EASING", "cubic-bezier(0,-1,1,2)"];
+        assert_eq!(server.run(&overshoot), Reply::simple("OK"));
+    }
+
+    #[test]
+    fn max_transition_only_limits_clients() {
+        let mut server = Server::with_colors(&[("pink", "#000000")]);
+        server.run(&["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "60000"]);
+        let aof = server.rewrite_aof("pink");
+        drop(server);
+
+        // Restarted with a lower limit, the server still loads the AOF...
+        let mut server = Server::with_args(&["MAX-TRANSITION-MS", "1000"]).unwrap();
+        server.replay(Server::run_loading, &aof);
+        server.set_time(START_MS + 30000);
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#808080"));
+        // ...but clients are held to it.
+        assert!(server.run(&["COLOR.TRANSITION", "pink", "#000000", "DURATION", "1001"])
+            .is_error("ERR DURATION can't exceed 1000 ms"));
+        assert_eq!(server.run(&["COLOR.TRANSITION", "pink", "#000000", "DURATION", "1000"]), Reply::simple("OK"));
+    }
+
+    #[test]
+    fn transition_start_is_internal() {
+        let mut server = Server::with_colors(&[("pink", "#000000")]);
+        assert!(server.run(&["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000", "AT", "0"])
+            .is_error("ERR syntax error, AT is only accepted from the master or the AOF"));
+        assert!(server.run(&["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000", "FROM", "#ff0000"])
+            .is_error("ERR syntax error, FROM is only accepted"));
+
+        // Starts as far off as i64 goes read without overflowing.
+        let max = i64::MAX.to_string();
+        let transition = ["COLOR.TRANSITION", "pink", "#ffffff", "DURATION", "1000", "AT", &max, "FROM", "#ff0000"];
+        assert_eq!(server.run_replicated(&transition), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#ff0000"));
+        let min = i64::MIN.to_string();
+        let transition = ["COLOR.TRANSITION", "pink", "#00ff00", "DURATION", "1000", "AT", &min, "FROM", "#ff0000"];
+        assert_eq!(server.run_replicated(&transition), Reply::simple("OK"));
+        assert_eq!(server.run(&["COLOR.GET", "pink"]), Reply::simple("#00ff00"));
+    }
+}
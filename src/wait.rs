@@ -0,0 +1,358 @@
+// COLOR.WAIT: block a client until a color key changes.
+//
+// Every write of a color takes the next number of a global sequence, and a
+// key somebody waited on remembers the number of its last write as its
+// version. That lets clients resume waiting with SINCE without missing changes
+// that happened between two waits. Keys nobody waits on are not tracked, and
+// once MAX_TRACKED_KEYS are, those nobody waits on anymore are forgotten. A key
+// that starts being tracked (again) takes the current sequence number as its
+// version: its last write may be any earlier one, so a SINCE older than that
+// replies straight away, even if the key didn't change.
+//
+// Redis wants every blocked client unblocked by the module, whatever happened
+// to it, or its handle leaks. A waiter is unblocked when its key changes, when
+// its timeout fires and, since Redis 4 doesn't tell us when a blocked client
+// disconnects, when a look at CLIENT LIST finds it gone. That look is taken
+// every time a client blocks while others wait, so it costs a CLIENT LIST per
+// COLOR.WAIT, and a handle only outlives its client until somebody else
+// waits or the key changes.
+
+use std::collections::{HashMap, HashSet};
+use std::ptr;
+use std::str;
+use std::sync::Mutex;
+
+use config;
+use error::ColorError;
+use libc::c_int;
+use redis;
+use redis::{Command, CommandFlags};
+use redis::raw;
+use Color;
+
+struct BlockedClient(*mut raw::RedisModuleBlockedClient);
+
+// Blocked client handles are only ever touched from the Redis main thread; the
+// mutex around the registry just satisfies the compiler.
+unsafe impl Send for BlockedClient {}
+
+struct Waiter {
+    client_id: u64,
+    bc: BlockedClient,
+}
+
+// Keys tracked before those nobody waits on are forgotten.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[derive(Default)]
+struct Registry {
+    // The number of the last write of a color, tracked or not.
+    sequence: u64,
+    versions: HashMap<Vec<u8>, u64>,
+    waiters: HashMap<Vec<u8>, Vec<Waiter>>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn with_registry<T, F: FnOnce(&mut Registry) -> T>(f: F) -> T {
+    let mut guard = REGISTRY.lock().unwrap();
+    f(guard.get_or_insert_with(Registry::default))
+}
+
+/// Forgets every waiter and version, when the module is loaded.
+pub fn reset() {
+    with_registry(|reg| *reg = Registry::default())
+}
+
+// Returns the current version of `key`, starting to track it if needed.
+fn track(key: &[u8]) -> u64 {
+    with_registry(|reg| {
+        if !reg.versions.contains_key(key) && reg.versions.len() >= MAX_TRACKED_KEYS {
+            let waiters = &reg.waiters;
+            reg.versions.retain(|k, _| waiters.contains_key(k));
+        }
+        let sequence = reg.sequence;
+        *reg.versions.entry(key.to_vec()).or_insert(sequence)
+    })
+}
+
+/// Records a change to `key` and wakes up every client waiting on it.
+pub fn signal(key: &[u8]) {
+    let waiters = with_registry(|reg| {
+        reg.sequence += 1;
+        let sequence = reg.sequence;
+        if let Some(version) = reg.versions.get_mut(key) {
+            *version = sequence;
+        }
+        reg.waiters.remove(key).unwrap_or_default()
+    });
+    for w in waiters {
+        raw::unblock_client(w.bc.0, ptr::null_mut());
+    }
+}
+
+// Unblocks and forgets the waiters whose client disconnected.
+fn prune_disconnected(r: &redis::Redis) -> Result<(), ColorError> {
+    if with_registry(|reg| reg.waiters.is_empty()) {
+        return Ok(())
+    }
+    let connected = connected_clients(r)?;
+    let gone = with_registry(|reg| {
+        let mut gone: Vec<Waiter> = Vec::new();
+        for waiters in reg.waiters.values_mut() {
+            let (stay, left): (Vec<Waiter>, Vec<Waiter>) =
+                waiters.drain(..).partition(|w| connected.contains(&w.client_id));
+            *waiters = stay;
+            gone.extend(left);
+        }
+        reg.waiters.retain(|_, waiters| !waiters.is_empty());
+        gone
+    });
+    for w in gone {
+        raw::unblock_client(w.bc.0, ptr::null_mut());
+    }
+    Ok(())
+}
+
+// The IDs of the clients connected, from CLIENT LIST.
+fn connected_clients(r: &redis::Redis) -> Result<HashSet<u64>, ColorError> {
+    let list = match r.call("CLIENT", &["LIST"])? {
+        redis::Reply::String(list) => list,
+        other => return Err(error!("Unexpected reply to CLIENT LIST: {:?}", other)),
+    };
+    // Client names and commands are whatever clients sent, so the list is
+    // taken as bytes, of which only the leading `id=` fields are read.
+    Ok(list.split(|&b| b == b'\n')
+        .filter_map(|line| str::from_utf8(line.split(|&b| b == b' ').next()?).ok())
+        .filter_map(|id| id.strip_prefix("id=")?.parse().ok())
+        .collect())
+}
+
+// Replies with the color at `key`, nil if it no longer holds one, and the
+// key's version.
+fn reply_color(r: &redis::Redis, key: &[u8]) -> Result<(), ColorError> {
+    let k = r.open_key(key);
+    let color: Option<Color> = if k.is_empty() || !k.valid_key_type() {
+        None
+    } else {
+        Some(k.read()?.at(redis::milliseconds()))
+    };
+    let mut reply = r.reply_array(2)?;
+    match color {
+        Some(c) => reply.string(c.format(config::get().default_format))?,
+        None => reply.null()?,
+    }
+    reply.integer(track(key) as i64)?;
+    reply.finish()
+}
+
+// Implements COLOR.WAIT key [SINCE version] timeout
+//
+// Blocks until `key` is written and replies with the new color and the key's
+// version. If SINCE is given and the key has already moved past that version,
+// replies straight away (see above for when that happens without a change).
+// The color is nil if the key no longer holds one by then, e.g. because it
+// was deleted right after the write. The timeout is in milliseconds, 0 waits
+// forever, and a timed out wait replies a plain nil.
+pub struct WaitColorCommand {}
+impl Command for WaitColorCommand {
+    fn name(&self) -> &'static str { "color.wait" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::DENY_SCRIPT }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let since = match args.len() {
+            3 => None,
+            5 if args[2].eq_ignore_case("since") => Some(args[3].parse::<u64>("a version")?),
+            5 => return Err(ColorError::Syntax(format!("unknown option {}", args[2].to_str()?))),
+            _ => return Err(ColorError::WrongArity(self.name().to_string())),
+        };
+        let timeout = args[args.len() - 1].to_i64()?;
+        if timeout < 0 {
+            return Err(ColorError::OutOfRange("timeout can't be negative".to_string()))
+        }
+
+        let key = args[1].as_bytes();
+        {
+            let k = r.open_key(key);
+            if !k.is_empty() && !k.valid_key_type() {
+                return Err(ColorError::WrongType)
+            }
+        }
+        let version = track(key);
+        if let Some(since) = since {
+            if version > since {
+                return reply_color(&r, key)
+            }
+        }
+
+        prune_disconnected(&r)?;
+        let bc = r.block_client(WaitColor_Reply, WaitColor_Timeout, timeout)?;
+        let client_id = r.client_id();
+        with_registry(|reg| {
+            reg.waiters
+                .entry(key.to_vec())
+                .or_default()
+                .push(Waiter { client_id, bc: BlockedClient(bc) })
+        });
+        Ok(())
+    }
+}
+
+// Runs when a waiting client is woken up by a change.
+struct WaitReply {}
+impl Command for WaitReply {
+    fn name(&self) -> &'static str { "color.wait" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::DENY_SCRIPT }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        reply_color(&r, args[1].as_bytes())
+    }
+}
+
+// Runs when a waiting client times out before the key changed.
+struct WaitTimeout {}
+impl Command for WaitTimeout {
+    fn name(&self) -> &'static str { "color.wait" }
+    fn arity(&self) -> i32 { -3 }
+    fn flags(&self) -> CommandFlags { CommandFlags::READONLY | CommandFlags::DENY_SCRIPT }
+    fn run(&self, r: redis::Redis, args: &[redis::RedisStr]) -> Result<(), ColorError> {
+        let client_id = r.client_id();
+        let key = args[1].as_bytes();
+        let waiter = with_registry(|reg| {
+            let waiters = reg.waiters.get_mut(key)?;
+            let at = waiters.iter().position(|w| w.client_id == client_id)?;
+            let waiter = waiters.remove(at);
+            if waiters.is_empty() {
+                reg.waiters.remove(key);
+            }
+            Some(waiter)
+        });
+        // Redis keeps the handle of a timed out client until it's unblocked.
+        if let Some(w) = waiter {
+            raw::unblock_client(w.bc.0, ptr::null_mut());
+        }
+        r.reply_null()
+    }
+}
+
+#[allow(non_snake_case)]
+extern "C" fn WaitColor_Reply(
+    ctx: *mut raw::RedisModuleCtx,
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> raw::Status {
+    <dyn Command>::callback_harness(&WaitReply{}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+extern "C" fn WaitColor_Timeout(
+    ctx: *mut raw::RedisModuleCtx,
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> raw::Status {
+    <dyn Command>::callback_harness(&WaitTimeout{}, ctx, argv, argc)
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::mock::{Reply, Server, START_MS};
+
+    #[test]
+    fn wait_wakes_up_on_change() {
+        let mut server = Server::new();
+        server.set_client_id(2);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        server.set_client_id(3);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        server.set_client_id(1);
+        server.run(&["COLOR.SET", "other", "#000000"]);
+        assert_eq!(server.unblocked(), vec![]);
+
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        let woken = Reply::Array(vec![Reply::Bulk(b"#ff55ef".to_vec()), Reply::Integer(2)]);
+        assert_eq!(server.unblocked(), vec![(2, woken.clone()), (3, woken)]);
+        assert_eq!(server.blocked_handles(), 0);
+        server.hset("palette", "pink", "#ff55ef");
+        assert!(server.run(&["COLOR.WAIT", "palette", "0"]).is_error("WRONGTYPE"));
+    }
+
+    #[test]
+    fn wait_since_replies_to_missed_changes() {
+        let mut server = Server::with_colors(&[("pink", "#ff55ef")]);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        server.run(&["COLOR.SET", "pink", "#000000"]);
+        let version = match server.unblocked().pop() {
+            Some((_, Reply::Array(ref reply))) if reply.len() == 2 => match reply[1] {
+                Reply::Integer(version) => version,
+                ref other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        };
+
+        // Caught up: waits for the next change.
+        let since = version.to_string();
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "SINCE", &since, "0"]), None);
+        server.run(&["COLOR.SET", "pink", "#ffffff"]);
+        assert_eq!(server.unblocked().len(), 1);
+
+        // Behind: replies straight away.
+        assert_eq!(server.run(&["COLOR.WAIT", "pink", "SINCE", &since, "0"]),
+            Reply::Array(vec![Reply::Bulk(b"#ffffff".to_vec()), Reply::Integer(version + 1)]));
+        assert!(server.run(&["COLOR.WAIT", "pink", "SINCE", "x", "0"]).is_error("ERR"));
+        assert!(server.run(&["COLOR.WAIT", "pink", "-1"]).is_error("ERR"));
+    }
+
+    #[test]
+    fn wait_times_out_and_frees_the_client() {
+        let mut server = Server::new();
+        server.set_client_id(2);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "100"]), None);
+        server.set_time(START_MS + 99);
+        assert_eq!(server.time_out_blocked(), vec![]);
+        server.set_time(START_MS + 100);
+        assert_eq!(server.time_out_blocked(), vec![(2, Reply::Null)]);
+        assert_eq!(server.blocked_handles(), 0);
+        assert_eq!(server.unblocked(), vec![]);
+
+        // A later change has nobody left to wake up.
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert_eq!(server.unblocked(), vec![]);
+
+        // Blocking and timing out is a single call.
+        assert_eq!(server.run(&["COLOR.INFO", "commands"]).info_field("cmdstat_color.wait"),
+            Some("calls=1,errors=0,parse_failures=0".to_string()));
+    }
+
+    #[test]
+    fn wait_forgets_disconnected_clients() {
+        let mut server = Server::new();
+        server.set_client_id(100);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        server.disconnect(100);
+        assert_eq!(server.blocked_handles(), 1);
+        server.set_client_id(2);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "other", "0"]), None);
+        assert_eq!(server.blocked_handles(), 1);
+        assert_eq!(server.unblocked(), vec![]);
+        server.set_client_id(3);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        assert_eq!(server.blocked_handles(), 2);
+
+        server.set_client_id(1);
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        assert_eq!(server.unblocked().len(), 1);
+        assert_eq!(server.blocked_handles(), 1);
+    }
+
+    #[test]
+    fn wait_tells_deleted_keys_from_timeouts() {
+        let mut server = Server::new();
+        server.set_client_id(2);
+        assert_eq!(server.run_blocking(&["COLOR.WAIT", "pink", "0"]), None);
+        server.set_client_id(1);
+        // Deleted before the woken client gets its reply, as in a MULTI.
+        server.run(&["COLOR.SET", "pink", "#ff55ef"]);
+        server.del("pink");
+        assert_eq!(server.unblocked(), vec![(2, Reply::Array(vec![Reply::Null, Reply::Integer(1)]))]);
+    }
+}